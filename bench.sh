@@ -0,0 +1,183 @@
+#!/bin/bash
+
+# Local Packet Dump - 2つのコミットのパケット処理性能を同じ条件で比較するスクリプト
+# 使い方: ./bench.sh <変更前のコミット> <変更後のコミット> [ワーカー数] [ループ回数]
+#
+# 合成したTCPトラフィックのpcapをvethペアにtcpreplayで最高速度で流し、
+# 計測中は10msごとに/metricsを取得して統計スレッドとの競合を起こす。
+# af-packet（ワーカー数を指定）とpcapのそれぞれで、変更前と変更後を測る。
+# どちらのコミットも同じ外部の入力と同じメトリクスで測るので、--benchがないコミットとも比べられる。
+set -e
+
+# カラー定義
+RED='\033[0;31m'
+GREEN='\033[0;32m'
+YELLOW='\033[1;33m'
+NC='\033[0m'
+
+USAGE="usage: $0 <before-commit> <after-commit> [workers] [loops]"
+BEFORE=${1:?$USAGE}
+AFTER=${2:?$USAGE}
+WORKERS=${3:-4}
+LOOPS=${4:-100}
+
+HOSTS=65536       # 監視対象のホスト数（10.0.0.0/8、既定のmax_hostsと同じ）
+FLOWS_PER_HOST=4  # 既定のmax_flowsをホストで割った数
+ROUNDS=1          # pcapに入れる1フローあたりのデータとACKの往復数
+PAYLOAD_LEN=1200
+METRICS_PORT=59190
+VETH_TX=lpdbench0
+VETH_RX=lpdbench1
+
+REPO_DIR=$(cd "$(dirname "$0")" && pwd)
+WORK_DIR=$(mktemp -d /tmp/lpdbench.XXXXXX)
+PCAP="$WORK_DIR/synthetic.pcap"
+
+if [ "$EUID" -eq 0 ]; then
+    SUDO=""
+else
+    SUDO="sudo"
+fi
+
+cleanup() {
+    $SUDO ip link del "$VETH_TX" 2>/dev/null || true
+    for name in before after; do
+        git -C "$REPO_DIR" worktree remove --force "$WORK_DIR/$name" 2>/dev/null || true
+    done
+    rm -rf "$WORK_DIR"
+}
+trap cleanup EXIT
+
+check_requirements() {
+    for command in git cargo python3 curl tcpreplay ip; do
+        if ! command -v "$command" >/dev/null 2>&1; then
+            echo -e "${RED}❌ $command が見つかりません${NC}"
+            exit 1
+        fi
+    done
+    echo "CPU: $(grep -m1 'model name' /proc/cpuinfo | cut -d: -f2 | sed 's/^ //') ($(nproc) cores)"
+    if [ "$(nproc)" -lt $((WORKERS + 2)) ]; then
+        echo -e "${YELLOW}⚠️  コア数がワーカー数+2より少ないため、スレッド間の競合が結果に出にくくなります${NC}"
+    fi
+}
+
+# 変更前と変更後をそれぞれ別のworktreeでリリースビルドする
+build_commit() {
+    local name=$1 commit=$2
+    echo -e "${YELLOW}🔨 $name ($commit) をビルド中...${NC}"
+    git -C "$REPO_DIR" worktree add --detach "$WORK_DIR/$name" "$commit" >/dev/null
+    cargo build --release --quiet --manifest-path "$WORK_DIR/$name/Cargo.toml"
+}
+
+# --benchと同じ形のトラフィック（監視対象からのデータと、相手からのACK）をpcapに書き出す
+generate_pcap() {
+    python3 - "$PCAP" "$HOSTS" "$FLOWS_PER_HOST" "$ROUNDS" "$PAYLOAD_LEN" <<'EOF'
+import struct
+import sys
+
+path, hosts, flows_per_host, rounds, payload_len = sys.argv[1], *map(int, sys.argv[2:])
+
+def checksum(header):
+    total = sum(struct.unpack("!10H", header))
+    total = (total & 0xFFFF) + (total >> 16)
+    total = (total & 0xFFFF) + (total >> 16)
+    return ~total & 0xFFFF
+
+def frame(src, dst, src_port, dst_port, seq, ack, payload):
+    ip = bytearray(struct.pack("!BBHHHBBH4s4s", 0x45, 0, 40 + payload, 0, 0, 64, 6, 0, bytes(src), bytes(dst)))
+    ip[10:12] = struct.pack("!H", checksum(bytes(ip)))
+    tcp = struct.pack("!HHIIBBHHH", src_port, dst_port, seq, ack, 0x50, 0x18, 65535, 0, 0)
+    ethernet = b"\x02\x00\x00\x00\x00\x02" + b"\x02\x00\x00\x00\x00\x01" + b"\x08\x00"
+    return ethernet + bytes(ip) + tcp + bytes(payload)
+
+with open(path, "wb") as out:
+    out.write(struct.pack("<IHHiIII", 0xA1B2C3D4, 2, 4, 0, 0, 65535, 1))
+    flows = []
+    for flow in range(hosts * flows_per_host):
+        host = flow // flows_per_host
+        local = (10, (host + 1) >> 16, ((host + 1) >> 8) & 0xFF, (host + 1) & 0xFF)
+        remote = (203, 0, 113, host % 250 + 1)
+        flows.append((local, remote, 10000 + flow % flows_per_host))
+    for index in range(rounds):
+        for local, remote, port in flows:
+            seq = 1 + index * payload_len
+            for data in (
+                frame(local, remote, port, 443, seq, 1, payload_len),
+                frame(remote, local, 443, port, 1, seq + payload_len, 0),
+            ):
+                out.write(struct.pack("<IIII", 0, 0, len(data), len(data)))
+                out.write(data)
+EOF
+}
+
+setup_veth() {
+    $SUDO ip link del "$VETH_TX" 2>/dev/null || true
+    $SUDO ip link add "$VETH_TX" type veth peer name "$VETH_RX"
+    $SUDO ip link set "$VETH_TX" up
+    $SUDO ip link set "$VETH_RX" up
+}
+
+sum_metric() {
+    echo "$2" | awk -v name="$1" '$1 == name || index($1, name "{") == 1 { total += $NF } END { printf "%d", total }'
+}
+
+# 1つのコミットのバイナリでキャプチャしながらpcapを流し、カーネルからの受信数と取りこぼしを読む
+# pcapは既定のバックエンドなので、--backendを指定せずに起動する
+measure() {
+    local name=$1 backend=$2
+    local binary="$WORK_DIR/$name/target/release/localpacketDump"
+    local backend_args=()
+    if [ "$backend" = "af-packet" ]; then
+        backend_args=(--backend af-packet --workers "$WORKERS")
+    fi
+    $SUDO "$binary" "${backend_args[@]}" --include 10.0.0.0/8 --no-tui \
+        --metrics-listen "127.0.0.1:$METRICS_PORT" "$VETH_RX" >"$WORK_DIR/$name-$backend.log" 2>&1 &
+    local pid=$!
+    sleep 3
+
+    # 統計スレッドとの競合が結果に出るよう、計測中はメトリクスを取得し続ける
+    (while [ -e "/proc/$pid" ]; do
+        curl -s -o /dev/null "http://127.0.0.1:$METRICS_PORT/metrics" || true
+        sleep 0.01
+    done) &
+    local scraper=$!
+
+    local started finished
+    started=$(date +%s.%N)
+    $SUDO tcpreplay --intf1="$VETH_TX" --topspeed --loop="$LOOPS" "$PCAP" >"$WORK_DIR/$name-$backend.tcpreplay" 2>&1
+    finished=$(date +%s.%N)
+    # リングに残っている分を処理し終えるまで待つ
+    sleep 2
+
+    local metrics received dropped
+    metrics=$(curl -s "http://127.0.0.1:$METRICS_PORT/metrics")
+    received=$(sum_metric network_capture_received_packets_total "$metrics")
+    dropped=$(sum_metric network_capture_dropped_packets_total "$metrics")
+    $SUDO kill -INT "$pid" 2>/dev/null || true
+    wait "$pid" 2>/dev/null || true
+    kill "$scraper" 2>/dev/null || true
+    wait "$scraper" 2>/dev/null || true
+
+    awk -v name="$name/$backend" -v received="$received" -v dropped="$dropped" -v started="$started" -v finished="$finished" 'BEGIN {
+        elapsed = finished - started
+        printf "%-16s received %d, dropped %d (%.2f%%), processed %.0f packets/s over %.2fs\n",
+            name, received, dropped, received ? 100 * dropped / received : 0, (received - dropped) / elapsed, elapsed
+    }'
+}
+
+main() {
+    echo -e "${GREEN}📏 Local Packet Dump ベンチマーク比較${NC}"
+    echo "=================================="
+    check_requirements
+    build_commit before "$BEFORE"
+    build_commit after "$AFTER"
+    generate_pcap
+    setup_veth
+    echo "Traffic: $((HOSTS * FLOWS_PER_HOST)) flows, $((HOSTS * FLOWS_PER_HOST * ROUNDS * 2)) packets x $LOOPS loops, $WORKERS af-packet worker(s)"
+    for backend in af-packet pcap; do
+        measure before "$backend"
+        measure after "$backend"
+    done
+}
+
+main "$@"
@@ -15,6 +15,8 @@ SERVICE_NAME="localpacketdump"
 SERVICE_FILE="/etc/systemd/system/${SERVICE_NAME}.service"
 CURRENT_DIR=$(pwd)
 BINARY_PATH="${CURRENT_DIR}/target/release/localpacketDump"
+CONFIG_EXAMPLE="${CURRENT_DIR}/config.example.toml"
+CONFIG_PATH="/etc/${SERVICE_NAME}.toml"
 
 echo -e "${BLUE}🔧 Local Packet Dump systemdサービス セットアップ${NC}"
 echo "================================================="
@@ -62,6 +64,23 @@ check_existing_service() {
     fi
 }
 
+# 設定ファイル配置（既にあれば上書きしない）
+install_config() {
+    if [ -f "$CONFIG_PATH" ]; then
+        echo -e "${BLUE}ℹ️  既存の設定ファイルを使用します: $CONFIG_PATH${NC}"
+        return 0
+    fi
+
+    if [ ! -f "$CONFIG_EXAMPLE" ]; then
+        echo -e "${RED}❌ 設定ファイルの例が見つかりません: $CONFIG_EXAMPLE${NC}"
+        exit 1
+    fi
+
+    echo -e "${YELLOW}📝 設定ファイルを配置中: $CONFIG_PATH${NC}"
+    sudo install -m 644 "$CONFIG_EXAMPLE" "$CONFIG_PATH"
+    echo -e "${GREEN}✅ 設定ファイル配置完了${NC}"
+}
+
 # サービスファイル作成
 create_service_file() {
     echo -e "${YELLOW}📝 サービスファイルを作成中: $SERVICE_FILE${NC}"
@@ -77,7 +96,8 @@ Wants=network.target
 Type=simple
 User=root
 Group=root
-ExecStart=$BINARY_PATH eth2
+ExecStart=$BINARY_PATH --config $CONFIG_PATH
+ExecReload=/bin/kill -HUP \$MAINPID
 WorkingDirectory=$CURRENT_DIR
 Restart=always
 RestartSec=10
@@ -134,7 +154,8 @@ show_status() {
     echo -e "${BLUE}📊 サービス情報${NC}"
     echo "===================="
     echo -e "${YELLOW}サービス名:${NC} $SERVICE_NAME"
-    echo -e "${YELLOW}設定ファイル:${NC} $SERVICE_FILE"
+    echo -e "${YELLOW}サービスファイル:${NC} $SERVICE_FILE"
+    echo -e "${YELLOW}設定ファイル:${NC} $CONFIG_PATH"
     echo -e "${YELLOW}バイナリパス:${NC} $BINARY_PATH"
     echo -e "${YELLOW}作業ディレクトリ:${NC} $CURRENT_DIR"
     echo ""
@@ -151,6 +172,7 @@ show_status() {
     echo "  sudo systemctl stop $SERVICE_NAME       # サービス停止"
     echo "  sudo systemctl start $SERVICE_NAME      # サービス開始"
     echo "  sudo systemctl restart $SERVICE_NAME    # サービス再起動"
+    echo "  sudo systemctl reload $SERVICE_NAME     # 設定ファイルの再読み込み"
     echo "  sudo journalctl -u $SERVICE_NAME -f     # ログをリアルタイム表示"
     echo "  curl http://localhost:9090/metrics       # メトリクス確認"
     echo ""
@@ -179,6 +201,7 @@ uninstall_service() {
     fi
     
     sudo systemctl daemon-reload
+    echo -e "${BLUE}ℹ️  設定ファイルは残しています: $CONFIG_PATH${NC}"
     echo -e "${GREEN}✅ アンインストール完了${NC}"
 }
 
@@ -189,6 +212,7 @@ main() {
             check_sudo
             check_binary
             check_existing_service
+            install_config
             create_service_file
             setup_systemd
             show_status
@@ -0,0 +1,168 @@
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::fmt;
+
+// 回線上でフレームごとに加わるもの（FCSはキャプチャには含まれない）
+const ETHERNET_PREAMBLE_LEN: u64 = 8; // プリアンブルとSFD
+const ETHERNET_FCS_LEN: u64 = 4;
+const ETHERNET_IFG_LEN: u64 = 12; // フレーム間ギャップ
+const ETHERNET_MIN_FRAME_LEN: u64 = 64; // FCSを含む最小フレーム長
+
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const TCP_MIN_LEN: u64 = 20;
+const UDP_LEN: u64 = 8;
+
+// パケットのどの層からをバイト数として数えるか
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Accounting {
+    Wire, // 回線上の長さ（Ethernetではプリアンブル、FCS、最小フレーム長までのパディング、フレーム間ギャップを含む）
+    #[default]
+    L2, // フレームの長さ（リンク層ヘッダーを含み、FCSは含まない）
+    L3,   // IPパケットの全長
+    L4,   // TCP/UDPのペイロード（IPとTCP/UDPのヘッダーを除く）
+}
+
+// 1パケットの各層の長さ。スナップレンで切り詰められていても、ヘッダーに書かれた元の長さを使う
+pub struct PacketLengths {
+    pub l2: u64,
+    pub l3: u64,
+    pub l4: u64,
+}
+
+impl Accounting {
+    pub fn bytes(self, ethernet: bool, lengths: &PacketLengths) -> u64 {
+        match self {
+            Accounting::Wire if ethernet => {
+                (lengths.l2 + ETHERNET_FCS_LEN).max(ETHERNET_MIN_FRAME_LEN)
+                    + ETHERNET_PREAMBLE_LEN
+                    + ETHERNET_IFG_LEN
+            }
+            Accounting::Wire | Accounting::L2 => lengths.l2,
+            Accounting::L3 => lengths.l3,
+            Accounting::L4 => lengths.l4,
+        }
+    }
+
+    // 外したトンネルのヘッダーが数えるバイト数に含まれるか（L3とL4では内側のパケットだけを数える）
+    pub fn includes_tunnel_headers(self) -> bool {
+        matches!(self, Accounting::Wire | Accounting::L2)
+    }
+}
+
+// IPとL4のヘッダーを除いたペイロードの長さ。TCPとUDP以外はIPのペイロードをすべて数える
+// 分割されたデータグラムの先頭以外のフラグメントにはL4のヘッダーがないので、segmentはNone
+pub fn l4_payload_len(
+    l3_len: u64,
+    ip_header_len: u64,
+    protocol: u8,
+    segment: Option<&[u8]>,
+) -> u64 {
+    let l4_header_len = match (protocol, segment) {
+        (IPPROTO_TCP, Some(segment)) => segment
+            .get(12)
+            .map_or(TCP_MIN_LEN, |offset| (offset >> 4) as u64 * 4),
+        (IPPROTO_UDP, Some(_)) => UDP_LEN,
+        _ => 0,
+    };
+    l3_len.saturating_sub(ip_header_len + l4_header_len)
+}
+
+impl fmt::Display for Accounting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Accounting::Wire => write!(f, "wire (including Ethernet framing)"),
+            Accounting::L2 => write!(f, "l2 (frame length)"),
+            Accounting::L3 => write!(f, "l3 (IP total length)"),
+            Accounting::L4 => write!(f, "l4 (TCP/UDP payload)"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1500バイトのIPパケットを運ぶEthernetフレーム（TCPヘッダーはタイムスタンプ付きの32バイト）
+    const FULL: PacketLengths = PacketLengths {
+        l2: 1514,
+        l3: 1500,
+        l4: 1448,
+    };
+
+    // データオフセット（4バイト単位）だけを入れたTCPヘッダー
+    fn tcp_header(words: u8) -> Vec<u8> {
+        let mut header = vec![0u8; words as usize * 4];
+        header[12] = words << 4;
+        header
+    }
+
+    #[test]
+    fn counts_each_layer() {
+        assert_eq!(Accounting::L2.bytes(true, &FULL), 1514);
+        assert_eq!(Accounting::L3.bytes(true, &FULL), 1500);
+        assert_eq!(Accounting::L4.bytes(true, &FULL), 1448);
+        // FCS、プリアンブル、フレーム間ギャップ
+        assert_eq!(Accounting::Wire.bytes(true, &FULL), 1514 + 4 + 8 + 12);
+    }
+
+    #[test]
+    fn wire_pads_short_ethernet_frames_to_the_minimum() {
+        let ack = PacketLengths {
+            l2: 54,
+            l3: 40,
+            l4: 0,
+        };
+        assert_eq!(Accounting::Wire.bytes(true, &ack), 64 + 8 + 12);
+        let exact = PacketLengths {
+            l2: 60,
+            l3: 46,
+            l4: 6,
+        };
+        assert_eq!(Accounting::Wire.bytes(true, &exact), 64 + 8 + 12);
+        let one_more = PacketLengths {
+            l2: 61,
+            l3: 47,
+            l4: 7,
+        };
+        assert_eq!(Accounting::Wire.bytes(true, &one_more), 65 + 8 + 12);
+    }
+
+    #[test]
+    fn wire_is_the_frame_length_without_ethernet() {
+        assert_eq!(Accounting::Wire.bytes(false, &FULL), 1514);
+    }
+
+    #[test]
+    fn only_wire_and_l2_include_tunnel_headers() {
+        assert!(Accounting::Wire.includes_tunnel_headers());
+        assert!(Accounting::L2.includes_tunnel_headers());
+        assert!(!Accounting::L3.includes_tunnel_headers());
+        assert!(!Accounting::L4.includes_tunnel_headers());
+        assert_eq!(Accounting::default(), Accounting::L2);
+    }
+
+    #[test]
+    fn l4_payload_excludes_the_tcp_header_including_options() {
+        let tcp = tcp_header(8);
+        assert_eq!(l4_payload_len(1500, 20, IPPROTO_TCP, Some(&tcp)), 1448);
+        assert_eq!(
+            l4_payload_len(1500, 20, IPPROTO_TCP, Some(&tcp_header(5))),
+            1460
+        );
+        // データオフセットまで届かない切り詰められたセグメントは最小のヘッダーとみなす
+        assert_eq!(l4_payload_len(1500, 20, IPPROTO_TCP, Some(&tcp[..4])), 1460);
+    }
+
+    #[test]
+    fn l4_payload_of_udp_and_other_protocols() {
+        assert_eq!(l4_payload_len(1500, 40, IPPROTO_UDP, Some(&[0; 8])), 1452);
+        // ICMPなどはIPのペイロード全体
+        assert_eq!(l4_payload_len(84, 20, 1, Some(&[0; 8])), 64);
+        // 先頭以外のフラグメントにはL4のヘッダーがない
+        assert_eq!(l4_payload_len(1500, 20, IPPROTO_UDP, None), 1480);
+        // 長さが壊れていても負にならない
+        assert_eq!(l4_payload_len(20, 20, IPPROTO_TCP, Some(&tcp_header(5))), 0);
+    }
+}
@@ -0,0 +1,357 @@
+use crate::bpf::{self, CaptureFilter};
+use crate::link::LinkType;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{self, AtomicU16, Ordering};
+use std::time::Duration;
+
+// リングの1ブロック（ページサイズの倍数）。パケットはブロック単位でまとめて受け取る
+const BLOCK_SIZE: usize = 1 << 20;
+// TPACKET_V3ではフレームは可変長だが、カーネルはblock_size/frame_sizeの整合を確認する
+const FRAME_SIZE: usize = 2048;
+// バッファサイズが指定されていない場合のワーカーあたりのブロック数
+const DEFAULT_BLOCK_NR: usize = 32;
+// 埋まりきらないブロックもこの時間で返してもらう（libpcapのtimeoutと同じ役割）
+const BLOCK_TIMEOUT_MS: u32 = 100;
+
+static NEXT_FANOUT_GROUP: AtomicU16 = AtomicU16::new(0);
+
+// インターフェースを開くたびに新しいfanoutグループIDを割り当てる
+// IDはホスト全体で共有されるので、プロセスIDを混ぜて他のプロセスと重ならないようにする
+pub fn new_fanout_group() -> u16 {
+    (std::process::id() as u16)
+        .wrapping_mul(64)
+        .wrapping_add(NEXT_FANOUT_GROUP.fetch_add(1, Ordering::Relaxed))
+}
+
+// リングから取り出した1パケット
+pub struct RingPacket<'a> {
+    pub ts: Duration,
+    pub data: &'a [u8],
+    pub len: usize, // スナップレンで切り詰められる前のフレームの長さ
+    // NICやドライバがフレームから外した外側のVLAN ID（libpcapと違いフレームには戻されない）
+    pub vlan: Option<u16>,
+}
+
+// fanoutグループに参加したAF_PACKETソケットとTPACKET_V3のmmapリング（ワーカー1つ分）
+pub struct Ring {
+    fd: OwnedFd,
+    link: LinkType,
+    map: *mut u8,
+    map_len: usize,
+    block_nr: usize,
+    current: usize,
+}
+
+// mmap領域はこのRingだけが所有し、1つのワーカースレッドからしか触らない
+unsafe impl Send for Ring {}
+
+impl Ring {
+    // buffer_sizeはワーカーあたりのリングの大きさ（バイト）
+    pub fn open(
+        interface: &str,
+        fanout_group: u16,
+        filter: &CaptureFilter,
+        buffer_size: Option<i32>,
+    ) -> Result<Self, String> {
+        let failed =
+            |what: &str, e: io::Error| format!("{} on '{}' failed: {}", what, interface, e);
+
+        let ifindex = interface_index(interface)?;
+        let link = interface_link_type(interface)?;
+        // プロトコル0で作ったソケットにはまだパケットが届かない。bindで初めて受信を始めるので、
+        // それまでにフィルタとリングを設定でき、どのインターフェースのパケットもリングに入らない
+        let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW, 0) };
+        if fd < 0 {
+            return Err(failed("socket(AF_PACKET)", io::Error::last_os_error()));
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let raw = fd.as_raw_fd();
+
+        let version = libc::tpacket_versions::TPACKET_V3 as libc::c_int;
+        setsockopt(raw, libc::SOL_PACKET, libc::PACKET_VERSION, &version)
+            .map_err(|e| failed("PACKET_VERSION", e))?;
+
+        // リングに入る前に落とすため、bindより先にフィルタを設定する
+        if let Some(filter) = filter.expression(link) {
+            attach_filter(raw, &filter, link).map_err(|e| format!("{} on '{}'", e, interface))?;
+        }
+
+        let block_nr = match buffer_size {
+            Some(size) => (size as usize / BLOCK_SIZE).max(1),
+            None => DEFAULT_BLOCK_NR,
+        };
+        let req = libc::tpacket_req3 {
+            tp_block_size: BLOCK_SIZE as u32,
+            tp_block_nr: block_nr as u32,
+            tp_frame_size: FRAME_SIZE as u32,
+            tp_frame_nr: (BLOCK_SIZE / FRAME_SIZE * block_nr) as u32,
+            tp_retire_blk_tov: BLOCK_TIMEOUT_MS,
+            tp_sizeof_priv: 0,
+            tp_feature_req_word: 0,
+        };
+        setsockopt(raw, libc::SOL_PACKET, libc::PACKET_RX_RING, &req)
+            .map_err(|e| failed("PACKET_RX_RING", e))?;
+
+        let map_len = BLOCK_SIZE * block_nr;
+        let map = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                map_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                raw,
+                0,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(failed("mmap", io::Error::last_os_error()));
+        }
+        // ここから先で失敗してもDropでmunmapされる
+        let ring = Self {
+            fd,
+            link,
+            map: map as *mut u8,
+            map_len,
+            block_nr,
+            current: 0,
+        };
+
+        let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        addr.sll_family = libc::AF_PACKET as u16;
+        addr.sll_protocol = (libc::ETH_P_ALL as u16).to_be();
+        addr.sll_ifindex = ifindex;
+        let bound = unsafe {
+            libc::bind(
+                raw,
+                &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        };
+        if bound < 0 {
+            return Err(failed("bind", io::Error::last_os_error()));
+        }
+
+        let mut mreq: libc::packet_mreq = unsafe { mem::zeroed() };
+        mreq.mr_ifindex = ifindex;
+        mreq.mr_type = libc::PACKET_MR_PROMISC as u16;
+        setsockopt(raw, libc::SOL_PACKET, libc::PACKET_ADD_MEMBERSHIP, &mreq)
+            .map_err(|e| failed("PACKET_ADD_MEMBERSHIP", e))?;
+
+        // フローの両方向が同じワーカーに届くよう、対称ハッシュで振り分ける
+        // フラグメントはアドレスだけでハッシュされるので、同じデータグラムは同じワーカーに届く
+        // （DEFRAGを付けるとカーネルが再構築してしまい、フラグメントを数えられない）
+        let fanout = (fanout_group as u32) | (libc::PACKET_FANOUT_HASH << 16);
+        setsockopt(
+            raw,
+            libc::SOL_PACKET,
+            libc::PACKET_FANOUT,
+            &(fanout as libc::c_int),
+        )
+        .map_err(|e| failed("PACKET_FANOUT", e))?;
+
+        Ok(ring)
+    }
+
+    pub fn link(&self) -> LinkType {
+        self.link
+    }
+
+    // フィルタを差し替える（式がなければ外す）
+    pub fn set_filter(&self, filter: &CaptureFilter) -> Result<(), String> {
+        let raw = self.fd.as_raw_fd();
+        match filter.expression(self.link) {
+            Some(filter) => attach_filter(raw, &filter, self.link),
+            None => {
+                let zero: libc::c_int = 0;
+                setsockopt(raw, libc::SOL_SOCKET, libc::SO_DETACH_FILTER, &zero).or_else(|e| {
+                    // フィルタが付いていなければENOENT
+                    if e.raw_os_error() == Some(libc::ENOENT) {
+                        Ok(())
+                    } else {
+                        Err(format!("SO_DETACH_FILTER failed: {}", e))
+                    }
+                })
+            }
+        }
+    }
+
+    // 次のブロックがカーネルから渡されるまで最大timeout待ち、中のパケットを順にhandlerに渡す
+    // タイムアウトした場合はOk(false)
+    pub fn next_block(
+        &mut self,
+        timeout: Duration,
+        mut handler: impl FnMut(RingPacket),
+    ) -> Result<bool, String> {
+        let block = unsafe { self.map.add(self.current * BLOCK_SIZE) };
+        let desc = block as *mut libc::tpacket_block_desc;
+
+        if !block_ready(desc) {
+            let mut pfd = libc::pollfd {
+                fd: self.fd.as_raw_fd(),
+                events: libc::POLLIN | libc::POLLERR,
+                revents: 0,
+            };
+            let ready = unsafe { libc::poll(&mut pfd, 1, timeout.as_millis() as libc::c_int) };
+            if ready < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    return Ok(false);
+                }
+                return Err(format!("poll failed: {}", e));
+            }
+            // インターフェースが落ちるとソケットにエラーが立つ
+            if pfd.revents & libc::POLLERR != 0 {
+                return Err(socket_error(self.fd.as_raw_fd()));
+            }
+            if !block_ready(desc) {
+                return Ok(false);
+            }
+        }
+
+        unsafe {
+            let header = &(*desc).hdr.bh1;
+            let mut offset = header.offset_to_first_pkt as usize;
+            for _ in 0..header.num_pkts {
+                let packet = &*(block.add(offset) as *const libc::tpacket3_hdr);
+                let data = slice::from_raw_parts(
+                    block.add(offset + packet.tp_mac as usize),
+                    packet.tp_snaplen as usize,
+                );
+                let vlan = (packet.tp_status & libc::TP_STATUS_VLAN_VALID != 0)
+                    .then_some(packet.hv1.tp_vlan_tci as u16 & 0x0fff);
+                handler(RingPacket {
+                    ts: Duration::new(packet.tp_sec as u64, packet.tp_nsec),
+                    data,
+                    len: packet.tp_len as usize,
+                    vlan,
+                });
+                offset += packet.tp_next_offset as usize;
+            }
+
+            // 読み終えたブロックをカーネルに返す
+            atomic::fence(Ordering::Release);
+            ptr::write_volatile(
+                ptr::addr_of_mut!((*desc).hdr.bh1.block_status),
+                libc::TP_STATUS_KERNEL,
+            );
+        }
+        self.current = (self.current + 1) % self.block_nr;
+        Ok(true)
+    }
+
+    // 前回取得してからの受信数と破棄数（カーネルは読むたびに0に戻す）
+    // 受信数には破棄されたパケットも含まれる
+    pub fn take_stats(&self) -> Result<(u32, u32), String> {
+        let mut stats: libc::tpacket_stats_v3 = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libc::tpacket_stats_v3>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(
+                self.fd.as_raw_fd(),
+                libc::SOL_PACKET,
+                libc::PACKET_STATISTICS,
+                &mut stats as *mut libc::tpacket_stats_v3 as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if result < 0 {
+            return Err(format!(
+                "PACKET_STATISTICS failed: {}",
+                io::Error::last_os_error()
+            ));
+        }
+        Ok((stats.tp_packets, stats.tp_drops))
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.map as *mut libc::c_void, self.map_len);
+        }
+    }
+}
+
+fn block_ready(desc: *mut libc::tpacket_block_desc) -> bool {
+    let status = unsafe { ptr::read_volatile(ptr::addr_of!((*desc).hdr.bh1.block_status)) };
+    atomic::fence(Ordering::Acquire);
+    status & libc::TP_STATUS_USER != 0
+}
+
+fn interface_index(interface: &str) -> Result<libc::c_int, String> {
+    let name =
+        CString::new(interface).map_err(|_| format!("Invalid interface name '{}'", interface))?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(format!("Interface '{}' not found", interface)),
+        index => Ok(index as libc::c_int),
+    }
+}
+
+// インターフェースのハードウェアタイプから、SOCK_RAWで受け取るフレームの形を決める
+pub fn interface_link_type(interface: &str) -> Result<LinkType, String> {
+    let hatype: u16 = fs::read_to_string(format!("/sys/class/net/{}/type", interface))
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .ok_or_else(|| format!("Interface '{}' not found", interface))?;
+    match hatype {
+        libc::ARPHRD_ETHER | libc::ARPHRD_LOOPBACK => Ok(LinkType::Ethernet),
+        // リンク層ヘッダーを持たないインターフェース（WireGuard、tun、PPP、IPトンネル）
+        libc::ARPHRD_NONE | libc::ARPHRD_PPP | libc::ARPHRD_TUNNEL | libc::ARPHRD_TUNNEL6 => {
+            Ok(LinkType::Raw)
+        }
+        other => Err(format!(
+            "Interface '{}' has hardware type {}, which the af-packet backend does not support; use --backend pcap",
+            interface, other
+        )),
+    }
+}
+
+// libpcapでリンク層に合わせてコンパイルしたプログラムをソケットに設定する
+fn attach_filter(fd: RawFd, filter: &str, link: LinkType) -> Result<(), String> {
+    let program = bpf::compile(filter, link)?;
+    let instructions = program.get_instructions();
+    // BpfInstructionはbpf_insnのrepr(transparent)で、sock_filterと同じレイアウト
+    let fprog = libc::sock_fprog {
+        len: instructions.len() as u16,
+        filter: instructions.as_ptr() as *mut libc::sock_filter,
+    };
+    setsockopt(fd, libc::SOL_SOCKET, libc::SO_ATTACH_FILTER, &fprog)
+        .map_err(|e| format!("SO_ATTACH_FILTER failed: {}", e))
+}
+
+fn socket_error(fd: RawFd) -> String {
+    let mut error: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ERROR,
+            &mut error as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        );
+    }
+    io::Error::from_raw_os_error(error).to_string()
+}
+
+fn setsockopt<T>(fd: RawFd, level: libc::c_int, name: libc::c_int, value: &T) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
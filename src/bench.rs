@@ -0,0 +1,203 @@
+use crate::accounting::Accounting;
+use crate::config::{CaptureBackend, StatsLimits};
+use crate::decap::Decapsulation;
+use crate::health::CaptureHealth;
+use crate::link::LinkType;
+use crate::service::ServiceMap;
+use crate::targets::TargetNetworks;
+use crate::{
+    process_packet, Frame, InterfaceCapture, PrometheusMetrics, PublishedStats, StatsShard,
+    WanAssignments,
+};
+use pnet::ipnetwork::IpNetwork;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const PAYLOAD_LEN: usize = 1200;
+// 統計スレッドの負荷を大きめにして、パケット処理との競合が結果に出るようにする
+const REPORT_INTERVAL: Duration = Duration::from_millis(10);
+
+const ETHERNET_LEN: usize = 14;
+const IPV4_LEN: usize = 20;
+const TCP_LEN: usize = 20;
+const SEQ_OFFSET: usize = ETHERNET_LEN + IPV4_LEN + 4;
+const ACK_OFFSET: usize = ETHERNET_LEN + IPV4_LEN + 8;
+
+// 1フロー分の宛先とシーケンス番号
+// フローが多いので、フレームはワーカーごとに1組だけ持ち、送るたびに宛先を書き換える
+struct SyntheticFlow {
+    local: Ipv4Addr,
+    remote: Ipv4Addr,
+    port: u16,
+    seq: u32,
+}
+
+impl SyntheticFlow {
+    fn new(host: u32, port: u16) -> Self {
+        Self {
+            local: Ipv4Addr::from(u32::from(Ipv4Addr::new(10, 0, 0, 0)) + host + 1),
+            remote: Ipv4Addr::new(203, 0, 113, (host % 250) as u8 + 1),
+            port,
+            seq: 1,
+        }
+    }
+}
+
+fn tcp_frame(payload: usize) -> Vec<u8> {
+    let mut frame = vec![0u8; ETHERNET_LEN + IPV4_LEN + TCP_LEN + payload];
+    frame[12..14].copy_from_slice(&0x0800u16.to_be_bytes());
+
+    let ip = &mut frame[ETHERNET_LEN..];
+    ip[0] = 0x45;
+    ip[2..4].copy_from_slice(&((IPV4_LEN + TCP_LEN + payload) as u16).to_be_bytes());
+    ip[8] = 64;
+    ip[9] = 6; // TCP
+
+    let tcp = &mut ip[IPV4_LEN..];
+    tcp[12] = 0x50; // ヘッダー長20バイト
+    tcp[13] = 0x18; // PSH, ACK
+    tcp[14..16].copy_from_slice(&65535u16.to_be_bytes());
+    frame
+}
+
+fn set_endpoints(frame: &mut [u8], src: (Ipv4Addr, u16), dst: (Ipv4Addr, u16)) {
+    let ip = &mut frame[ETHERNET_LEN..];
+    ip[12..16].copy_from_slice(&src.0.octets());
+    ip[16..20].copy_from_slice(&dst.0.octets());
+    let tcp = &mut ip[IPV4_LEN..];
+    tcp[0..2].copy_from_slice(&src.1.to_be_bytes());
+    tcp[2..4].copy_from_slice(&dst.1.to_be_bytes());
+}
+
+// 合成したTCPトラフィックをキャプチャと同じ経路で処理し、1秒あたりの処理パケット数を表示する
+// 統計スレッドは実際より短い間隔でスクレイプ（スナップショットの集計とメトリクスの組み立て）を繰り返す
+// ホスト数とフロー数は既定の上限いっぱいにして、バケットを閉じるときの公開の負荷も測る
+pub fn run(packets: u64, backend: CaptureBackend, workers: usize) {
+    let limits = StatsLimits::default();
+    let hosts = limits.max_hosts as u32;
+    let flows_per_host = (limits.max_flows / limits.max_hosts).max(1) as u32;
+    let targets = TargetNetworks::new(vec!["10.0.0.0/8".parse::<IpNetwork>().unwrap()], Vec::new());
+    let state = InterfaceCapture {
+        name: "bench".to_string(),
+        role: "lan".to_string(),
+        shards: Arc::new(
+            (0..workers)
+                .map(|_| Arc::new(PublishedStats::default()))
+                .collect(),
+        ),
+        up: Arc::new(AtomicBool::new(true)),
+        health: Arc::new(CaptureHealth::default()),
+        backend,
+        buffer_size: None,
+        limits,
+        accounting: Accounting::default(),
+        services: Arc::new(ServiceMap::default()),
+    };
+    let metrics = PrometheusMetrics::new();
+    let scrape_targets = targets.clone();
+    metrics.attach(
+        vec![state.clone()],
+        Box::new(move |_| scrape_targets.clone()),
+        Arc::new(Mutex::new(WanAssignments::new())),
+    );
+    let done = AtomicBool::new(false);
+    let per_worker = packets / workers as u64;
+
+    println!(
+        "Benchmark: {} packets, {} worker(s), {} monitored hosts, {} flows",
+        per_worker * workers as u64,
+        workers,
+        hosts,
+        hosts * flows_per_host
+    );
+
+    let started = Instant::now();
+    let reports = thread::scope(|scope| {
+        let reporter = scope.spawn(|| {
+            let mut reports = 0u64;
+            while !done.load(Ordering::SeqCst) {
+                metrics.registry.gather();
+                reports += 1;
+                thread::sleep(REPORT_INTERVAL);
+            }
+            reports
+        });
+
+        let handles: Vec<_> = (0..workers)
+            .map(|worker| {
+                let (state, targets) = (&state, &targets);
+                scope.spawn(move || {
+                    // フローをワーカーごとに分ける（fanoutでフロー単位に振り分けられるのと同じ）
+                    let mut flows: Vec<SyntheticFlow> = (0..hosts * flows_per_host)
+                        .filter(|flow| *flow as usize % workers == worker)
+                        .map(|flow| {
+                            let port = 10000 + (flow % flows_per_host) as u16;
+                            SyntheticFlow::new(flow / flows_per_host, port)
+                        })
+                        .collect();
+                    let (mut data, mut ack) = (tcp_frame(PAYLOAD_LEN), tcp_frame(0));
+                    let mut shard = StatsShard::new(
+                        state.shards[worker].clone(),
+                        state.limits,
+                        state.accounting,
+                        state.services.clone(),
+                        state.health.clone(),
+                    );
+                    // 合成時刻で1マイクロ秒に1パケットとして、バケットの境界も通るようにする
+                    let base = Duration::from_secs(1_700_000_000);
+                    for i in 0..per_worker {
+                        let ts = base + Duration::from_micros(i);
+                        let flow_index = (i / 2) as usize % flows.len();
+                        let flow = &mut flows[flow_index];
+                        let (local, remote) = ((flow.local, flow.port), (flow.remote, 443));
+                        let frame = if i % 2 == 0 {
+                            set_endpoints(&mut data, local, remote);
+                            data[SEQ_OFFSET..SEQ_OFFSET + 4]
+                                .copy_from_slice(&flow.seq.to_be_bytes());
+                            flow.seq = flow.seq.wrapping_add(PAYLOAD_LEN as u32);
+                            &data
+                        } else {
+                            set_endpoints(&mut ack, remote, local);
+                            ack[ACK_OFFSET..ACK_OFFSET + 4]
+                                .copy_from_slice(&flow.seq.to_be_bytes());
+                            &ack
+                        };
+                        let frame = Frame {
+                            link: LinkType::Ethernet,
+                            data: frame,
+                            len: frame.len(),
+                            vlan: None,
+                            ts,
+                        };
+                        shard.advance(ts);
+                        let _ = process_packet(
+                            &mut shard.table,
+                            targets,
+                            &Decapsulation::default(),
+                            &frame,
+                        );
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        done.store(true, Ordering::SeqCst);
+        reporter.join().unwrap()
+    });
+    let elapsed = started.elapsed().as_secs_f64();
+
+    let processed = per_worker * workers as u64;
+    println!(
+        "Processed {} packets in {:.3}s: {:.0} packets/s ({:.0} per worker), {} stats refreshes",
+        processed,
+        elapsed,
+        processed as f64 / elapsed,
+        processed as f64 / elapsed / workers as f64,
+        reports
+    );
+}
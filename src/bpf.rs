@@ -0,0 +1,138 @@
+use crate::config::Config;
+use crate::decap::Decapsulation;
+use crate::link::LinkType;
+use crate::targets::TargetNetworks;
+use pcap::{BpfProgram, Capture};
+
+// キャプチャハンドルに設定するBPFフィルタ（監視対象から生成した式と利用者の追加式）
+// 実際の式はハンドルのデータリンクタイプによって変わるので、設定するときに組み立てる
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaptureFilter {
+    auto: Option<String>, // 監視対象のプレフィックスから生成した式
+    decap: Decapsulation, // 生成した式に加えて丸ごと通すトンネル
+    extra: Option<String>,
+}
+
+impl CaptureFilter {
+    // 生成した式と追加式をANDで結び、どちらもなければNone
+    // トンネルの式はそのリンク層に現れるカプセル化の分だけ加える
+    // Ethernetではタグ付きのフレームも通すよう、タグ1つ（802.1Q）と2つ（QinQ）の場合を続けて書く
+    // libpcapのvlanは以降の式のオフセットをずらすので、追加式を前に置いてタグなしの位置で評価させる
+    pub fn expression(&self, link: LinkType) -> Option<String> {
+        let auto = self.auto.as_ref().map(|nets| {
+            // トンネルのパケットは外側のアドレスでは絞り込めないので、外すカプセル化ごとに丸ごと通す
+            let nets = match self.decap.bpf_expression(link) {
+                Some(tunnels) => format!("{} or {}", nets, tunnels),
+                None => nets.clone(),
+            };
+            if link.supports_vlan() {
+                format!("{} or (vlan and ({})) or (vlan and ({}))", nets, nets, nets)
+            } else {
+                nets
+            }
+        });
+        match (auto, &self.extra) {
+            (Some(auto), Some(extra)) => Some(format!("({}) and ({})", extra, auto)),
+            (Some(auto), None) => Some(auto),
+            (None, Some(extra)) => Some(extra.clone()),
+            (None, None) => None,
+        }
+    }
+
+    // 実際のデータリンクタイプでコンパイルできるか確かめる
+    // 追加式がリンク層の条件のせいでこのリンクでは何にも一致しない場合もエラーにする
+    pub fn validate(&self, link: LinkType) -> Result<(), String> {
+        if let Some(extra) = &self.extra {
+            if matches_nothing(&compile(extra, link)?) {
+                return Err(format!(
+                    "BPF filter '{}' matches no packets on {} links",
+                    extra, link
+                ));
+            }
+        }
+        if let Some(expression) = self.expression(link) {
+            compile(&expression, link)?;
+        }
+        Ok(())
+    }
+
+    // ログに示す、そのリンクで実際に設定する式
+    pub fn describe(&self, link: LinkType) -> String {
+        self.expression(link).unwrap_or_else(|| "none".to_string())
+    }
+}
+
+// 設定と監視対象からフィルタを組み立て、キャプチャするリンク向けにコンパイルできるか確認する
+// まだ開いていないインターフェースはEthernetとして確かめ、開いたときに実際のリンクで確かめ直す
+pub fn capture_filter(
+    config: &Config,
+    targets: &TargetNetworks,
+    link: LinkType,
+) -> Result<CaptureFilter, String> {
+    let filter = CaptureFilter {
+        auto: if config.bpf_auto {
+            targets.bpf_expression()
+        } else {
+            None
+        },
+        decap: config.decap,
+        extra: config.bpf_filter.clone(),
+    };
+    filter.validate(link)?;
+    Ok(filter)
+}
+
+// 指定したデータリンクタイプ向けにコンパイルしたプログラム（AF_PACKETのソケットに直接設定する場合に使う）
+pub fn compile(filter: &str, link: LinkType) -> Result<BpfProgram, String> {
+    let dead = Capture::dead(link.to_pcap()).map_err(|e| e.to_string())?;
+    dead.compile(filter, true)
+        .map_err(|e| format!("invalid BPF filter '{}': {}", filter, e))
+}
+
+// 常に破棄する（ret #0だけの）プログラムか
+// libpcapはそのリンクにない条件（Raw IPでのether hostなど）をエラーにせず偽にする
+fn matches_nothing(program: &BpfProgram) -> bool {
+    // BpfInstructionのフィールドは公開されていないので、"code jt jf k"の表示で比べる
+    matches!(program.get_instructions(), [only] if only.to_string() == "6 0 0 0")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decap::Encapsulation;
+
+    fn filter(extra: Option<&str>) -> CaptureFilter {
+        CaptureFilter {
+            auto: Some("net 10.40.0.0/20".to_string()),
+            decap: Decapsulation::new(&[Encapsulation::Pppoe, Encapsulation::Gre], false),
+            extra: extra.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn adds_tunnels_and_vlan_variants_on_ethernet() {
+        let nets = "net 10.40.0.0/20 or ether proto 0x8864 or ip proto 47 or ip6 proto 47";
+        assert_eq!(
+            filter(Some("not port 873")).expression(LinkType::Ethernet),
+            Some(format!(
+                "(not port 873) and ({} or (vlan and ({})) or (vlan and ({})))",
+                nets, nets, nets
+            ))
+        );
+    }
+
+    #[test]
+    fn leaves_ethertype_tunnels_out_on_raw_ip_links() {
+        assert_eq!(
+            filter(None).expression(LinkType::Raw).as_deref(),
+            Some("net 10.40.0.0/20 or ip proto 47 or ip6 proto 47")
+        );
+        let none = CaptureFilter {
+            auto: None,
+            decap: Decapsulation::default(),
+            extra: None,
+        };
+        assert_eq!(none.expression(LinkType::Raw), None);
+        assert_eq!(none.describe(LinkType::Raw), "none");
+    }
+}
@@ -0,0 +1,958 @@
+use crate::accounting::Accounting;
+use crate::decap::{Decapsulation, Encapsulation};
+use crate::service::ServiceMap;
+use clap::{Parser, ValueEnum};
+use pnet::ipnetwork::IpNetwork;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const DEFAULT_WAN_API_URL: &str = "http://localhost:32599/status";
+const DEFAULT_WAN_POLL_INTERVAL_SECS: f64 = 30.0;
+const DEFAULT_METRICS_LISTEN: &str = "127.0.0.1:59122";
+const DEFAULT_REFRESH_INTERVAL_SECS: f64 = 1.0;
+const DEFAULT_ROLE: &str = "lan";
+const MAX_WORKERS: usize = 64;
+const MAX_VLAN_ID: u16 = 4094;
+const DEFAULT_HOST_IDLE_TIMEOUT_SECS: f64 = 300.0;
+const DEFAULT_FLOW_IDLE_TIMEOUT_SECS: f64 = 120.0;
+const DEFAULT_MAX_HOSTS: usize = 65536;
+const DEFAULT_MAX_FLOWS: usize = 262144;
+const DEFAULT_MAX_PORTS: usize = 64;
+const DEFAULT_TOP_PORTS: usize = 10;
+const DEFAULT_TOP_SERVICES: usize = 10;
+
+// コマンドライン引数（指定されたものは設定ファイルの値より優先）
+#[derive(Debug, Clone, Parser)]
+#[command(
+    name = "localpacketDump",
+    version = crate::version::VERSION,
+    about = "Per-IP traffic and TCP quality monitor with Prometheus export"
+)]
+pub struct Cli {
+    #[arg(
+        value_name = "INTERFACE[=ROLE]",
+        help = "Interfaces to capture on, e.g. br0=lan eth1=wan0 (overrides `interfaces` in the config file)"
+    )]
+    interfaces: Vec<String>,
+
+    #[arg(short, long, value_name = "FILE", help = "TOML configuration file")]
+    config: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with = "interfaces",
+        help = "Replay a pcap/pcapng file instead of capturing live"
+    )]
+    read: Option<String>,
+
+    #[arg(
+        long = "include",
+        value_name = "CIDR",
+        help = "Prefix to monitor (repeatable, replaces `include` in the config file)"
+    )]
+    include: Vec<IpNetwork>,
+
+    #[arg(
+        long = "exclude",
+        value_name = "CIDR",
+        help = "Prefix to ignore (repeatable, replaces `exclude` in the config file)"
+    )]
+    exclude: Vec<IpNetwork>,
+
+    #[arg(
+        long = "vlan",
+        value_name = "ID",
+        help = "Only count frames tagged with this VLAN ID, 0 for untagged (repeatable, replaces `vlans` in the config file)"
+    )]
+    vlans: Vec<u16>,
+
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        value_name = "ENCAP,...",
+        help = "Tunnels to decapsulate and account by their inner addresses (replaces `decap` in the config file)"
+    )]
+    decap: Vec<Encapsulation>,
+
+    #[arg(
+        long,
+        help = "Also count the bytes of decapsulated tunnel headers separately per IP"
+    )]
+    tunnel_overhead: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        help = "Which bytes of each packet to count (default: l2)"
+    )]
+    accounting: Option<Accounting>,
+
+    #[arg(
+        long = "service",
+        value_name = "NAME=PROTO/PORT[-PORT]",
+        help = "Classify traffic on a port as a service, e.g. minecraft=tcp/25565 (repeatable, overrides the built-in map)"
+    )]
+    services: Vec<String>,
+
+    #[arg(long, value_name = "URL", help = "WAN assignment API endpoint")]
+    wan_api_url: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "Interval between WAN assignment API polls"
+    )]
+    wan_poll_interval: Option<f64>,
+
+    #[arg(
+        long,
+        value_name = "ADDR:PORT",
+        help = "Listen address of the Prometheus endpoint"
+    )]
+    metrics_listen: Option<SocketAddr>,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "Interval between metrics/console refreshes"
+    )]
+    refresh_interval: Option<f64>,
+
+    #[arg(long, overrides_with = "no_tui", help = "Show the console table")]
+    tui: bool,
+
+    #[arg(
+        long,
+        overrides_with = "tui",
+        help = "Do not show the console table (metrics only)"
+    )]
+    no_tui: bool,
+
+    #[arg(
+        long,
+        help = "Show each IP's traffic split by protocol (TCP/UDP/ICMP/...) in the console table"
+    )]
+    tui_protocols: bool,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Write a JSON summary of the final statistics here on shutdown"
+    )]
+    summary_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "EXPR",
+        help = "Extra BPF expression ANDed with the filter generated from the targets, e.g. \"not port 873\""
+    )]
+    bpf_filter: Option<String>,
+
+    #[arg(
+        long,
+        help = "Do not generate a BPF filter from the targets (only --bpf-filter is applied)"
+    )]
+    no_bpf_auto: bool,
+
+    #[arg(
+        long,
+        value_name = "MIB",
+        help = "Kernel capture buffer size in MiB (default: libpcap's default)"
+    )]
+    buffer_size: Option<u32>,
+
+    #[arg(
+        long,
+        value_enum,
+        help = "Capture backend (af-packet uses a TPACKET_V3 ring with fanout, Linux only)"
+    )]
+    backend: Option<CaptureBackend>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Number of af-packet fanout worker threads per interface (default: number of CPUs)"
+    )]
+    workers: Option<usize>,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "Forget a host's statistics after this long without traffic (default: 300)"
+    )]
+    host_idle_timeout: Option<f64>,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "Forget a TCP flow's state after this long without packets (default: 120)"
+    )]
+    flow_idle_timeout: Option<f64>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Maximum number of hosts tracked per capture worker (default: 65536)"
+    )]
+    max_hosts: Option<usize>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Maximum number of TCP flows tracked per capture worker (default: 262144)"
+    )]
+    max_flows: Option<usize>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Maximum number of remote ports tracked per host (default: 64)"
+    )]
+    max_ports: Option<usize>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Export only each IP's N busiest remote ports (default: 10)"
+    )]
+    top_ports: Option<usize>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Export only each IP's N busiest services (default: 10)"
+    )]
+    top_services: Option<usize>,
+
+    #[cfg(feature = "bench")]
+    #[arg(
+        long,
+        value_name = "PACKETS",
+        conflicts_with_all = ["interfaces", "read"],
+        help = "Process this many synthetic packets through the capture path, print packets per second and exit"
+    )]
+    bench: Option<u64>,
+}
+
+// 設定ファイルの内容（すべて省略可能）
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    interface: Option<String>,
+    interfaces: Option<Vec<InterfaceConfig>>,
+    include: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+    vlans: Option<Vec<u16>>,
+    decap: Option<Vec<Encapsulation>>,
+    tunnel_overhead: Option<bool>,
+    accounting: Option<Accounting>,
+    #[serde(default)]
+    services: BTreeMap<String, Vec<String>>,
+    refresh_interval: Option<f64>,
+    tui: Option<bool>,
+    tui_protocols: Option<bool>,
+    summary_file: Option<PathBuf>,
+    bpf_filter: Option<String>,
+    bpf_auto: Option<bool>,
+    buffer_size: Option<u32>,
+    backend: Option<CaptureBackend>,
+    workers: Option<usize>,
+    #[serde(default)]
+    wan_api: WanApiSection,
+    #[serde(default)]
+    metrics: MetricsSection,
+    #[serde(default)]
+    stats: StatsSection,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WanApiSection {
+    url: Option<String>,
+    poll_interval: Option<f64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MetricsSection {
+    listen: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StatsSection {
+    host_idle_timeout: Option<f64>,
+    flow_idle_timeout: Option<f64>,
+    max_hosts: Option<usize>,
+    max_flows: Option<usize>,
+    max_ports: Option<usize>,
+    top_ports: Option<usize>,
+    top_services: Option<usize>,
+}
+
+// キャプチャするインターフェースと役割（lan, wan0, wan1, ...）
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InterfaceConfig {
+    pub name: String,
+    #[serde(default = "default_role")]
+    pub role: String,
+}
+
+fn default_role() -> String {
+    DEFAULT_ROLE.to_string()
+}
+
+// キャプチャ元: ライブのインターフェース（複数可）、または保存済みのpcap/pcapngファイル
+#[derive(Debug, Clone, PartialEq)]
+pub enum CaptureSource {
+    Live(Vec<InterfaceConfig>),
+    File(String),
+}
+
+// 検証済みの実行時設定
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub source: CaptureSource,
+    pub include: Vec<IpNetwork>,
+    pub exclude: Vec<IpNetwork>,
+    pub vlans: Vec<u16>, // 空ならVLANで絞り込まない
+    pub decap: Decapsulation,
+    pub accounting: Accounting,
+    pub services: ServiceMap,
+    pub wan_api_url: String,
+    pub wan_poll_interval: Duration,
+    pub metrics_listen: SocketAddr,
+    pub refresh_interval: Duration,
+    pub tui: bool,
+    pub tui_protocols: bool,
+    pub summary_file: Option<PathBuf>,
+    pub bpf_filter: Option<String>, // 利用者が追加するBPF式
+    pub bpf_auto: bool,             // 監視対象からBPFフィルタを自動生成するか
+    pub buffer_size: Option<i32>,   // キャプチャバッファのサイズ（バイト）。Noneならlibpcapの既定値
+    pub backend: CaptureBackend,
+    pub workers: usize, // af-packetでインターフェースごとに動かすワーカー数（pcapでは常に1）
+    pub limits: StatsLimits,
+    #[cfg(feature = "bench")]
+    pub bench: Option<u64>, // ベンチマークで処理する合成パケット数
+}
+
+// ホストとTCPフローの状態を捨てるまでの時間と、追跡する数の上限（キャプチャのワーカーごと）
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StatsLimits {
+    pub host_idle_timeout: Duration,
+    pub flow_idle_timeout: Duration,
+    pub max_hosts: usize,
+    pub max_flows: usize,
+    pub max_ports: usize,    // ホストごとに追跡するリモートポートの数
+    pub top_ports: usize,    // IPごとに出力するリモートポートの数
+    pub top_services: usize, // IPごとに出力するサービスの数
+}
+
+impl Default for StatsLimits {
+    fn default() -> Self {
+        Self {
+            host_idle_timeout: Duration::from_secs_f64(DEFAULT_HOST_IDLE_TIMEOUT_SECS),
+            flow_idle_timeout: Duration::from_secs_f64(DEFAULT_FLOW_IDLE_TIMEOUT_SECS),
+            max_hosts: DEFAULT_MAX_HOSTS,
+            max_flows: DEFAULT_MAX_FLOWS,
+            max_ports: DEFAULT_MAX_PORTS,
+            top_ports: DEFAULT_TOP_PORTS,
+            top_services: DEFAULT_TOP_SERVICES,
+        }
+    }
+}
+
+// ライブキャプチャの受信方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CaptureBackend {
+    Pcap,     // libpcap（1インターフェース1スレッド）
+    AfPacket, // AF_PACKETのTPACKET_V3リングをfanoutで複数スレッドに分散
+}
+
+impl fmt::Display for CaptureBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureBackend::Pcap => write!(f, "pcap"),
+            CaptureBackend::AfPacket => write!(f, "af-packet"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => {
+                write!(f, "cannot read config file '{}': {}", path.display(), e)
+            }
+            ConfigError::Parse(path, e) => {
+                write!(f, "invalid config file '{}': {}", path.display(), e)
+            }
+            ConfigError::Invalid(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    // 設定ファイルを読み直し、コマンドライン引数と合成して検証する
+    // リロード時も同じ引数で呼ぶため、コマンドラインの指定は常に優先される
+    pub fn from_cli(cli: &Cli) -> Result<Self, ConfigError> {
+        let file = match &cli.config {
+            Some(path) => read_file(path)?,
+            None => FileConfig::default(),
+        };
+        Self::merge(cli.clone(), file)
+    }
+
+    fn merge(cli: Cli, file: FileConfig) -> Result<Self, ConfigError> {
+        let source = match cli.read {
+            Some(path) => CaptureSource::File(path),
+            // ベンチマークではインターフェースを開かない
+            #[cfg(feature = "bench")]
+            None if cli.bench.is_some() => CaptureSource::Live(Vec::new()),
+            None => CaptureSource::Live(merge_interfaces(
+                cli.interfaces,
+                file.interface,
+                file.interfaces,
+            )?),
+        };
+
+        let include = if cli.include.is_empty() {
+            parse_networks("include", file.include.unwrap_or_default())?
+        } else {
+            cli.include
+        };
+        let exclude = if cli.exclude.is_empty() {
+            parse_networks("exclude", file.exclude.unwrap_or_default())?
+        } else {
+            cli.exclude
+        };
+        let vlans = if cli.vlans.is_empty() {
+            file.vlans.unwrap_or_default()
+        } else {
+            cli.vlans
+        };
+        if let Some(id) = vlans.iter().find(|id| **id > MAX_VLAN_ID) {
+            return Err(ConfigError::Invalid(format!(
+                "vlans entry {} is out of range (0-{})",
+                id, MAX_VLAN_ID
+            )));
+        }
+        let decap = if cli.decap.is_empty() {
+            file.decap.unwrap_or_default()
+        } else {
+            cli.decap
+        };
+        let decap = Decapsulation::new(
+            &decap,
+            cli.tunnel_overhead || file.tunnel_overhead.unwrap_or(false),
+        );
+
+        let wan_api_url = cli
+            .wan_api_url
+            .or(file.wan_api.url)
+            .unwrap_or_else(|| DEFAULT_WAN_API_URL.to_string());
+        match reqwest::Url::parse(&wan_api_url) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {}
+            Ok(_) => {
+                return Err(ConfigError::Invalid(format!(
+                    "wan_api.url '{}' must be an http:// or https:// URL",
+                    wan_api_url
+                )))
+            }
+            Err(e) => {
+                return Err(ConfigError::Invalid(format!(
+                    "wan_api.url '{}' is not a valid URL: {}",
+                    wan_api_url, e
+                )))
+            }
+        }
+
+        let wan_poll_interval = parse_interval(
+            "wan_api.poll_interval",
+            cli.wan_poll_interval
+                .or(file.wan_api.poll_interval)
+                .unwrap_or(DEFAULT_WAN_POLL_INTERVAL_SECS),
+        )?;
+        let refresh_interval = parse_interval(
+            "refresh_interval",
+            cli.refresh_interval
+                .or(file.refresh_interval)
+                .unwrap_or(DEFAULT_REFRESH_INTERVAL_SECS),
+        )?;
+
+        let limits = StatsLimits {
+            host_idle_timeout: parse_interval(
+                "stats.host_idle_timeout",
+                cli.host_idle_timeout
+                    .or(file.stats.host_idle_timeout)
+                    .unwrap_or(DEFAULT_HOST_IDLE_TIMEOUT_SECS),
+            )?,
+            flow_idle_timeout: parse_interval(
+                "stats.flow_idle_timeout",
+                cli.flow_idle_timeout
+                    .or(file.stats.flow_idle_timeout)
+                    .unwrap_or(DEFAULT_FLOW_IDLE_TIMEOUT_SECS),
+            )?,
+            max_hosts: parse_limit(
+                "stats.max_hosts",
+                cli.max_hosts
+                    .or(file.stats.max_hosts)
+                    .unwrap_or(DEFAULT_MAX_HOSTS),
+            )?,
+            max_flows: parse_limit(
+                "stats.max_flows",
+                cli.max_flows
+                    .or(file.stats.max_flows)
+                    .unwrap_or(DEFAULT_MAX_FLOWS),
+            )?,
+            max_ports: parse_limit(
+                "stats.max_ports",
+                cli.max_ports
+                    .or(file.stats.max_ports)
+                    .unwrap_or(DEFAULT_MAX_PORTS),
+            )?,
+            top_ports: parse_limit(
+                "stats.top_ports",
+                cli.top_ports
+                    .or(file.stats.top_ports)
+                    .unwrap_or(DEFAULT_TOP_PORTS),
+            )?,
+            top_services: parse_limit(
+                "stats.top_services",
+                cli.top_services
+                    .or(file.stats.top_services)
+                    .unwrap_or(DEFAULT_TOP_SERVICES),
+            )?,
+        };
+
+        // 設定ファイルの割り当てを先に、コマンドラインの指定を後から上書きする
+        let mut service_overrides = Vec::new();
+        for (name, ports) in &file.services {
+            for spec in ports {
+                service_overrides.push((name.as_str(), spec.as_str()));
+            }
+        }
+        for arg in &cli.services {
+            let (name, spec) = arg.split_once('=').ok_or_else(|| {
+                ConfigError::Invalid(format!(
+                    "--service '{}' must be NAME=PROTO/PORT[-PORT]",
+                    arg
+                ))
+            })?;
+            service_overrides.push((name, spec));
+        }
+        let services = ServiceMap::new(service_overrides).map_err(ConfigError::Invalid)?;
+
+        let metrics_listen = match cli.metrics_listen {
+            Some(addr) => addr,
+            None => {
+                let listen = file
+                    .metrics
+                    .listen
+                    .unwrap_or_else(|| DEFAULT_METRICS_LISTEN.to_string());
+                listen.parse().map_err(|_| {
+                    ConfigError::Invalid(format!(
+                        "metrics.listen '{}' is not a valid ADDR:PORT",
+                        listen
+                    ))
+                })?
+            }
+        };
+
+        let tui = if cli.tui {
+            true
+        } else if cli.no_tui {
+            false
+        } else {
+            file.tui.unwrap_or(true)
+        };
+
+        let summary_file = cli.summary_file.or(file.summary_file);
+        if let Some(path) = &summary_file {
+            if path.as_os_str().is_empty() || path.is_dir() {
+                return Err(ConfigError::Invalid(format!(
+                    "summary_file '{}' must be a file path",
+                    path.display()
+                )));
+            }
+        }
+
+        // libpcapにはintのバイト数で渡すので2GiB未満に制限する
+        let buffer_size = match cli.buffer_size.or(file.buffer_size) {
+            Some(mib @ 1..=2047) => Some(mib as i32 * 1024 * 1024),
+            Some(mib) => {
+                return Err(ConfigError::Invalid(format!(
+                    "buffer_size must be between 1 and 2047 MiB, got {}",
+                    mib
+                )))
+            }
+            None => None,
+        };
+
+        let backend = cli.backend.or(file.backend).unwrap_or(CaptureBackend::Pcap);
+        if backend == CaptureBackend::AfPacket && !cfg!(target_os = "linux") {
+            return Err(ConfigError::Invalid(
+                "backend 'af-packet' is only available on Linux".to_string(),
+            ));
+        }
+        // anyはlibpcapの疑似デバイスなので、AF_PACKETのソケットをbindできない
+        if let CaptureSource::Live(interfaces) = &source {
+            if backend == CaptureBackend::AfPacket && interfaces.iter().any(|i| i.name == "any") {
+                return Err(ConfigError::Invalid(
+                    "interface 'any' can only be captured with backend 'pcap'".to_string(),
+                ));
+            }
+        }
+        let workers = match (backend, cli.workers.or(file.workers)) {
+            (CaptureBackend::Pcap, Some(_)) => {
+                return Err(ConfigError::Invalid(
+                    "workers can only be set with backend 'af-packet'".to_string(),
+                ))
+            }
+            (CaptureBackend::Pcap, None) => 1,
+            (CaptureBackend::AfPacket, Some(n @ 1..=MAX_WORKERS)) => n,
+            (CaptureBackend::AfPacket, Some(n)) => {
+                return Err(ConfigError::Invalid(format!(
+                    "workers must be between 1 and {}, got {}",
+                    MAX_WORKERS, n
+                )))
+            }
+            (CaptureBackend::AfPacket, None) => std::thread::available_parallelism()
+                .map(|n| n.get().min(MAX_WORKERS))
+                .unwrap_or(1),
+        };
+
+        Ok(Self {
+            source,
+            include,
+            exclude,
+            vlans,
+            decap,
+            accounting: cli.accounting.or(file.accounting).unwrap_or_default(),
+            services,
+            wan_api_url,
+            wan_poll_interval,
+            metrics_listen,
+            refresh_interval,
+            tui,
+            tui_protocols: cli.tui_protocols || file.tui_protocols.unwrap_or(false),
+            summary_file,
+            bpf_filter: cli
+                .bpf_filter
+                .or(file.bpf_filter)
+                .filter(|expr| !expr.trim().is_empty()),
+            bpf_auto: !cli.no_bpf_auto && file.bpf_auto.unwrap_or(true),
+            buffer_size,
+            backend,
+            workers,
+            limits,
+            #[cfg(feature = "bench")]
+            bench: cli.bench,
+        })
+    }
+}
+
+// コマンドラインの指定があればそれを使い、なければ設定ファイルの`interfaces`（または単独の`interface`）を使う
+fn merge_interfaces(
+    cli: Vec<String>,
+    interface: Option<String>,
+    interfaces: Option<Vec<InterfaceConfig>>,
+) -> Result<Vec<InterfaceConfig>, ConfigError> {
+    let merged = if !cli.is_empty() {
+        cli.iter()
+            .map(|arg| match arg.split_once('=') {
+                Some((name, role)) => InterfaceConfig {
+                    name: name.to_string(),
+                    role: role.to_string(),
+                },
+                None => InterfaceConfig {
+                    name: arg.clone(),
+                    role: default_role(),
+                },
+            })
+            .collect()
+    } else {
+        match (interface, interfaces) {
+            (Some(_), Some(_)) => {
+                return Err(ConfigError::Invalid(
+                    "set either `interface` or `interfaces` in the config file, not both"
+                        .to_string(),
+                ))
+            }
+            (Some(name), None) => vec![InterfaceConfig {
+                name,
+                role: default_role(),
+            }],
+            (None, Some(interfaces)) => interfaces,
+            (None, None) => Vec::new(),
+        }
+    };
+
+    if merged.is_empty() {
+        return Err(ConfigError::Invalid(
+            "no interface given: pass <INTERFACE>, --read <FILE> or set `interfaces` in the config file"
+                .to_string(),
+        ));
+    }
+    for (i, interface) in merged.iter().enumerate() {
+        if interface.name.is_empty() {
+            return Err(ConfigError::Invalid(
+                "interface name must not be empty".to_string(),
+            ));
+        }
+        let valid_role = !interface.role.is_empty()
+            && interface
+                .role
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+        if !valid_role {
+            return Err(ConfigError::Invalid(format!(
+                "role '{}' of interface '{}' must be lowercase letters, digits or '_' (e.g. lan, wan0)",
+                interface.role, interface.name
+            )));
+        }
+        if merged[..i].iter().any(|other| other.name == interface.name) {
+            return Err(ConfigError::Invalid(format!(
+                "interface '{}' is listed more than once",
+                interface.name
+            )));
+        }
+    }
+    Ok(merged)
+}
+
+fn read_file(path: &PathBuf) -> Result<FileConfig, ConfigError> {
+    let text = fs::read_to_string(path).map_err(|e| ConfigError::Read(path.clone(), e))?;
+    toml::from_str(&text).map_err(|e| ConfigError::Parse(path.clone(), e))
+}
+
+fn parse_networks(field: &str, values: Vec<String>) -> Result<Vec<IpNetwork>, ConfigError> {
+    values
+        .iter()
+        .map(|value| {
+            value.parse::<IpNetwork>().map_err(|e| {
+                ConfigError::Invalid(format!(
+                    "{} entry '{}' is not a valid CIDR: {}",
+                    field, value, e
+                ))
+            })
+        })
+        .collect()
+}
+
+fn parse_interval(field: &str, seconds: f64) -> Result<Duration, ConfigError> {
+    if !seconds.is_finite() || seconds <= 0.0 {
+        return Err(ConfigError::Invalid(format!(
+            "{} must be a positive number of seconds (got {})",
+            field, seconds
+        )));
+    }
+    // Durationに収まらない大きな値はfrom_secs_f64だとpanicする
+    Duration::try_from_secs_f64(seconds).map_err(|_| {
+        ConfigError::Invalid(format!("{} is too large (got {} seconds)", field, seconds))
+    })
+}
+
+fn parse_limit(field: &str, value: usize) -> Result<usize, ConfigError> {
+    if value == 0 {
+        return Err(ConfigError::Invalid(format!(
+            "{} must be at least 1",
+            field
+        )));
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(args: &[&str], file: &str) -> Result<Config, ConfigError> {
+        let cli =
+            Cli::try_parse_from(std::iter::once("localpacketDump").chain(args.iter().copied()))
+                .unwrap();
+        Config::merge(cli, toml::from_str(file).unwrap())
+    }
+
+    fn interfaces(config: &Config) -> Vec<(&str, &str)> {
+        match &config.source {
+            CaptureSource::Live(interfaces) => interfaces
+                .iter()
+                .map(|interface| (interface.name.as_str(), interface.role.as_str()))
+                .collect(),
+            CaptureSource::File(path) => panic!("expected interfaces, got file {}", path),
+        }
+    }
+
+    fn invalid(args: &[&str], file: &str) -> String {
+        match load(args, file) {
+            Err(ConfigError::Invalid(message)) => message,
+            other => panic!("expected an invalid config, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn uses_defaults_when_nothing_is_set() {
+        let config = load(&["eth0"], "").unwrap();
+        assert_eq!(interfaces(&config), [("eth0", "lan")]);
+        assert!(config.include.is_empty());
+        assert_eq!(config.wan_api_url, DEFAULT_WAN_API_URL);
+        assert_eq!(config.wan_poll_interval, Duration::from_secs(30));
+        assert_eq!(
+            config.metrics_listen,
+            DEFAULT_METRICS_LISTEN.parse().unwrap()
+        );
+        assert_eq!(config.refresh_interval, Duration::from_secs(1));
+        assert!(config.tui);
+    }
+
+    #[test]
+    fn command_line_overrides_the_file() {
+        let file = r#"
+            interface = "eth1"
+            include = ["10.40.0.0/20"]
+            refresh_interval = 5.0
+            tui = false
+
+            [wan_api]
+            poll_interval = 10.0
+
+            [metrics]
+            listen = "0.0.0.0:9100"
+        "#;
+        let config = load(&[], file).unwrap();
+        assert_eq!(interfaces(&config), [("eth1", "lan")]);
+        assert_eq!(config.include, vec!["10.40.0.0/20".parse().unwrap()]);
+        assert_eq!(config.refresh_interval, Duration::from_secs(5));
+        assert_eq!(config.wan_poll_interval, Duration::from_secs(10));
+        assert_eq!(config.metrics_listen, "0.0.0.0:9100".parse().unwrap());
+        assert!(!config.tui);
+
+        let args = [
+            "eth2",
+            "--include",
+            "192.168.0.0/24",
+            "--refresh-interval",
+            "0.5",
+            "--metrics-listen",
+            "127.0.0.1:9200",
+            "--tui",
+        ];
+        let config = load(&args, file).unwrap();
+        assert_eq!(interfaces(&config), [("eth2", "lan")]);
+        assert_eq!(config.include, vec!["192.168.0.0/24".parse().unwrap()]);
+        assert_eq!(config.refresh_interval, Duration::from_millis(500));
+        assert_eq!(config.wan_poll_interval, Duration::from_secs(10));
+        assert_eq!(config.metrics_listen, "127.0.0.1:9200".parse().unwrap());
+        assert!(config.tui);
+    }
+
+    #[test]
+    fn replays_a_file_instead_of_an_interface() {
+        let config = load(&["--read", "capture.pcapng"], "interface = \"eth1\"").unwrap();
+        assert!(matches!(&config.source, CaptureSource::File(path) if path == "capture.pcapng"));
+    }
+
+    #[test]
+    fn requires_an_interface_or_a_file() {
+        assert!(invalid(&[], "").starts_with("no interface given"));
+    }
+
+    #[test]
+    fn takes_interfaces_with_roles() {
+        let config = load(&["br0=lan", "eth1=wan0", "eth2"], "").unwrap();
+        assert_eq!(
+            interfaces(&config),
+            [("br0", "lan"), ("eth1", "wan0"), ("eth2", "lan")]
+        );
+
+        let file = r#"
+            [[interfaces]]
+            name = "br0"
+
+            [[interfaces]]
+            name = "eth1"
+            role = "wan1"
+        "#;
+        let config = load(&[], file).unwrap();
+        assert_eq!(interfaces(&config), [("br0", "lan"), ("eth1", "wan1")]);
+        // コマンドラインの指定が設定ファイルより優先される
+        let config = load(&["eth3=wan0"], file).unwrap();
+        assert_eq!(interfaces(&config), [("eth3", "wan0")]);
+    }
+
+    #[test]
+    fn rejects_invalid_interface_lists() {
+        let message = invalid(&["eth0=WAN"], "");
+        assert!(message.starts_with("role 'WAN'"), "{}", message);
+        let message = invalid(&["eth0=lan", "eth0=wan0"], "");
+        assert!(message.contains("more than once"), "{}", message);
+        let message = invalid(&["=lan"], "");
+        assert!(message.starts_with("interface name"), "{}", message);
+        let both = "interface = \"eth0\"\n[[interfaces]]\nname = \"eth1\"";
+        let message = invalid(&[], both);
+        assert!(message.starts_with("set either"), "{}", message);
+    }
+
+    #[test]
+    fn rejects_intervals_that_are_not_positive() {
+        for seconds in ["0", "-1", "NaN", "inf"] {
+            let arg = format!("--refresh-interval={}", seconds);
+            let message = invalid(&["eth0", &arg], "");
+            assert!(message.starts_with("refresh_interval"), "{}", message);
+        }
+        let message = invalid(&["eth0", "--refresh-interval=1e30"], "");
+        assert!(
+            message.starts_with("refresh_interval is too large"),
+            "{}",
+            message
+        );
+        let message = invalid(&["eth0"], "[wan_api]\npoll_interval = 0.0");
+        assert!(message.starts_with("wan_api.poll_interval"), "{}", message);
+    }
+
+    #[test]
+    fn rejects_wan_api_urls_that_are_not_http() {
+        for url in ["ftp://localhost/status", "localhost:32599"] {
+            let message = invalid(&["eth0", "--wan-api-url", url], "");
+            assert!(message.starts_with("wan_api.url"), "{}", message);
+        }
+        assert!(load(&["eth0", "--wan-api-url", "https://router/status"], "").is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_values_in_the_file() {
+        let message = invalid(&["eth0"], "include = [\"10.40.0.0/33\"]");
+        assert!(
+            message.starts_with("include entry '10.40.0.0/33'"),
+            "{}",
+            message
+        );
+        let message = invalid(&["eth0"], "[metrics]\nlisten = \"localhost\"");
+        assert!(message.starts_with("metrics.listen"), "{}", message);
+    }
+
+    #[test]
+    fn rejects_unknown_keys_in_the_file() {
+        assert!(toml::from_str::<FileConfig>("interfce = \"eth0\"").is_err());
+        assert!(toml::from_str::<FileConfig>("[metrics]\nport = 9090").is_err());
+    }
+}
@@ -0,0 +1,684 @@
+use crate::health::Malformed;
+use crate::ipv6;
+use crate::link::LinkType;
+use crate::vlan::{self, VlanTags};
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::fmt;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_PPPOE_SESSION: u16 = 0x8864;
+const ETHERTYPE_MPLS_UNICAST: u16 = 0x8847;
+const ETHERTYPE_MPLS_MULTICAST: u16 = 0x8848;
+// GREで運ぶEthernetフレーム
+const ETHERTYPE_TEB: u16 = 0x6558;
+// IP以外のパケット（集計の対象外）
+const ETHERTYPE_OTHER: u16 = 0;
+
+const PPP_IPV4: u16 = 0x0021;
+const PPP_IPV6: u16 = 0x0057;
+
+const IPPROTO_IPIP: u8 = 4;
+const IPPROTO_UDP: u8 = 17;
+const IPPROTO_IPV6: u8 = 41;
+const IPPROTO_GRE: u8 = 47;
+const VXLAN_PORT: u16 = 4789;
+
+const ETHERNET_LEN: usize = 14;
+const PPPOE_LEN: usize = 6; // PPPoEヘッダー（この後に2バイトのPPPプロトコル番号）
+const MPLS_LABEL_LEN: usize = 4;
+const IPV4_MIN_LEN: usize = 20;
+const IPV6_LEN: usize = 40;
+const GRE_LEN: usize = 4; // オプションを除いた長さ
+const UDP_LEN: usize = 8;
+const VXLAN_LEN: usize = 8;
+
+// トンネルの入れ子の上限（GREの中にGREを入れ続けるようなパケットで止まらないように）
+const MAX_DEPTH: usize = 8;
+
+// 外すことができるカプセル化
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Encapsulation {
+    Pppoe, // PPPoEセッション
+    Mpls,  // MPLSラベルスタック
+    Gre,   // GRE（IPと、Ethernetを運ぶTEB）
+    Vxlan, // VXLAN（UDP 4789）
+    Ipip,  // IPv4/IPv6 over IPv4/IPv6
+}
+
+impl Encapsulation {
+    const ALL: [Encapsulation; 5] = [
+        Encapsulation::Pppoe,
+        Encapsulation::Mpls,
+        Encapsulation::Gre,
+        Encapsulation::Vxlan,
+        Encapsulation::Ipip,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Encapsulation::Pppoe => "pppoe",
+            Encapsulation::Mpls => "mpls",
+            Encapsulation::Gre => "gre",
+            Encapsulation::Vxlan => "vxlan",
+            Encapsulation::Ipip => "ipip",
+        }
+    }
+
+    // このカプセル化のパケットを通すBPF式（vlanやpppoesのようにオフセットをずらすキーワードは使わない）
+    fn bpf_expression(self) -> &'static str {
+        match self {
+            Encapsulation::Pppoe => "ether proto 0x8864",
+            Encapsulation::Mpls => "ether proto 0x8847 or ether proto 0x8848",
+            Encapsulation::Gre => "ip proto 47 or ip6 proto 47",
+            Encapsulation::Vxlan => "udp port 4789",
+            Encapsulation::Ipip => "ip proto 4 or ip proto 41 or ip6 proto 4 or ip6 proto 41",
+        }
+    }
+
+    // PPPoEとMPLSはEtherTypeで見分けるので、EtherTypeのないリンクには現れない
+    fn applies_to(self, link: LinkType) -> bool {
+        match self {
+            Encapsulation::Pppoe | Encapsulation::Mpls => link.carries_ethertype(),
+            Encapsulation::Gre | Encapsulation::Vxlan | Encapsulation::Ipip => true,
+        }
+    }
+}
+
+// 有効にしたカプセル化と、トンネルのオーバーヘッドを別に数えるか
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Decapsulation {
+    enabled: u8, // Encapsulationごとのビット
+    pub count_overhead: bool,
+}
+
+// カプセル化を外した一番内側のネットワーク層
+pub struct Inner<'a> {
+    pub ethertype: u16,
+    pub payload: &'a [u8],
+    pub overhead: u64, // 外したトンネルのヘッダーのバイト数
+}
+
+impl Decapsulation {
+    pub fn new(encapsulations: &[Encapsulation], count_overhead: bool) -> Self {
+        Self {
+            enabled: encapsulations
+                .iter()
+                .fold(0, |bits, encapsulation| bits | 1 << *encapsulation as u8),
+            count_overhead,
+        }
+    }
+
+    fn enabled(&self, encapsulation: Encapsulation) -> bool {
+        self.enabled & (1 << encapsulation as u8) != 0
+    }
+
+    // IPパケットの中を見る必要があるか
+    fn ip_tunnels_enabled(&self) -> bool {
+        self.enabled(Encapsulation::Gre)
+            || self.enabled(Encapsulation::Vxlan)
+            || self.enabled(Encapsulation::Ipip)
+    }
+
+    fn encapsulations(&self) -> impl Iterator<Item = Encapsulation> + '_ {
+        Encapsulation::ALL
+            .into_iter()
+            .filter(|encapsulation| self.enabled(*encapsulation))
+    }
+
+    // 監視対象のフィルタに加える、トンネルのパケットを通す式
+    // 外側のアドレスは監視対象とは限らないので、中身は確認せずに通す
+    pub fn bpf_expression(&self, link: LinkType) -> Option<String> {
+        let terms: Vec<&str> = self
+            .encapsulations()
+            .filter(|encapsulation| encapsulation.applies_to(link))
+            .map(Encapsulation::bpf_expression)
+            .collect();
+        if terms.is_empty() {
+            None
+        } else {
+            Some(terms.join(" or "))
+        }
+    }
+
+    // 有効なカプセル化を外側から順に外す。VLANタグを外した後のEtherTypeとペイロードを渡す
+    pub fn unwrap<'a>(&self, ethertype: u16, payload: &'a [u8]) -> Result<Inner<'a>, Malformed> {
+        let mut inner = Inner {
+            ethertype,
+            payload,
+            overhead: 0,
+        };
+        for _ in 0..MAX_DEPTH {
+            let next = match inner.ethertype {
+                ETHERTYPE_PPPOE_SESSION if self.enabled(Encapsulation::Pppoe) => {
+                    pppoe_session(inner.payload)?
+                }
+                ETHERTYPE_MPLS_UNICAST | ETHERTYPE_MPLS_MULTICAST
+                    if self.enabled(Encapsulation::Mpls) =>
+                {
+                    mpls(inner.payload)?
+                }
+                ETHERTYPE_IPV4 | ETHERTYPE_IPV6 if self.ip_tunnels_enabled() => {
+                    match self.ip_tunnel(inner.ethertype, inner.payload)? {
+                        Some(next) => next,
+                        None => break,
+                    }
+                }
+                _ => break,
+            };
+            inner = Inner {
+                ethertype: next.ethertype,
+                payload: next.payload,
+                overhead: inner.overhead + next.overhead,
+            };
+        }
+        Ok(inner)
+    }
+
+    // IPパケットがトンネルなら中身を返す。トンネルでなければNone
+    fn ip_tunnel<'a>(
+        &self,
+        ethertype: u16,
+        packet: &'a [u8],
+    ) -> Result<Option<Inner<'a>>, Malformed> {
+        let (protocol, header_len, body) = if ethertype == ETHERTYPE_IPV4 {
+            if packet.len() < IPV4_MIN_LEN {
+                return Err(Malformed::Ipv4);
+            }
+            let header_len = (packet[0] & 0x0f) as usize * 4;
+            if header_len < IPV4_MIN_LEN || packet.len() < header_len {
+                return Err(Malformed::Ipv4);
+            }
+            // 分割されたトンネルは再構築しないので、外側のアドレスで数える
+            if u16::from_be_bytes([packet[6], packet[7]]) & 0x3fff != 0 {
+                return Ok(None);
+            }
+            let total_len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+            let end = total_len.clamp(header_len, packet.len());
+            (packet[9], header_len, &packet[header_len..end])
+        } else {
+            if packet.len() < IPV6_LEN {
+                return Err(Malformed::Ipv6);
+            }
+            let payload_len = u16::from_be_bytes([packet[4], packet[5]]) as usize;
+            let end = (IPV6_LEN + payload_len).min(packet.len());
+            let upper = ipv6::upper_layer(packet[6], &packet[IPV6_LEN..end])?;
+            if upper.fragment.is_some() {
+                return Ok(None);
+            }
+            (upper.protocol, end - upper.payload.len(), upper.payload)
+        };
+
+        let tunnel = match protocol {
+            IPPROTO_IPIP if self.enabled(Encapsulation::Ipip) => Inner {
+                ethertype: ETHERTYPE_IPV4,
+                payload: body,
+                overhead: 0,
+            },
+            IPPROTO_IPV6 if self.enabled(Encapsulation::Ipip) => Inner {
+                ethertype: ETHERTYPE_IPV6,
+                payload: body,
+                overhead: 0,
+            },
+            IPPROTO_GRE if self.enabled(Encapsulation::Gre) => {
+                match gre(body, self.enabled(Encapsulation::Mpls))? {
+                    Some(inner) => inner,
+                    None => return Ok(None),
+                }
+            }
+            IPPROTO_UDP if self.enabled(Encapsulation::Vxlan) => match vxlan(body)? {
+                Some(inner) => inner,
+                None => return Ok(None),
+            },
+            _ => return Ok(None),
+        };
+        Ok(Some(Inner {
+            overhead: tunnel.overhead + header_len as u64,
+            ..tunnel
+        }))
+    }
+}
+
+// PPPoEセッションヘッダーとPPPのプロトコル番号を外す
+fn pppoe_session(payload: &[u8]) -> Result<Inner<'_>, Malformed> {
+    let header_len = PPPOE_LEN + 2;
+    if payload.len() < header_len {
+        return Err(Malformed::Tunnel);
+    }
+    // PPPoEの長さはPPPのプロトコル番号を含む。Ethernetのパディングを除くために使う
+    let length = u16::from_be_bytes([payload[4], payload[5]]) as usize;
+    let end = (PPPOE_LEN + length).clamp(header_len, payload.len());
+    let ethertype = match u16::from_be_bytes([payload[6], payload[7]]) {
+        PPP_IPV4 => ETHERTYPE_IPV4,
+        PPP_IPV6 => ETHERTYPE_IPV6,
+        _ => ETHERTYPE_OTHER, // LCPなどの制御パケット
+    };
+    Ok(Inner {
+        ethertype,
+        payload: &payload[header_len..end],
+        overhead: header_len as u64,
+    })
+}
+
+// スタックの底までMPLSラベルを外す。中身はIPのバージョンで判別する
+fn mpls(payload: &[u8]) -> Result<Inner<'_>, Malformed> {
+    let mut offset = 0;
+    loop {
+        let label = payload
+            .get(offset..offset + MPLS_LABEL_LEN)
+            .ok_or(Malformed::Tunnel)?;
+        offset += MPLS_LABEL_LEN;
+        if label[2] & 0x01 != 0 {
+            break;
+        }
+    }
+    let inner = &payload[offset..];
+    // 疑似回線（Ethernet over MPLS）などIP以外は対象外
+    let ethertype = match inner.first().map(|b| b >> 4) {
+        Some(4) => ETHERTYPE_IPV4,
+        Some(6) => ETHERTYPE_IPV6,
+        _ => ETHERTYPE_OTHER,
+    };
+    Ok(Inner {
+        ethertype,
+        payload: inner,
+        overhead: offset as u64,
+    })
+}
+
+// GREヘッダー（チェックサム、キー、シーケンス番号のオプションを含む）を外す
+// PPTPの拡張GRE（バージョン1）とソースルーティング付きのもの、IP・TEB・（有効なら）MPLS以外を運ぶもの
+// （ERSPANなど）はトンネルとして扱わず、外側のアドレスで数える
+fn gre(body: &[u8], mpls: bool) -> Result<Option<Inner<'_>>, Malformed> {
+    if body.len() < GRE_LEN {
+        return Err(Malformed::Tunnel);
+    }
+    let flags = u16::from_be_bytes([body[0], body[1]]);
+    if flags & 0x0007 != 0 || flags & 0x4000 != 0 {
+        return Ok(None);
+    }
+    let protocol = u16::from_be_bytes([body[2], body[3]]);
+    match protocol {
+        ETHERTYPE_IPV4 | ETHERTYPE_IPV6 | ETHERTYPE_TEB => {}
+        ETHERTYPE_MPLS_UNICAST | ETHERTYPE_MPLS_MULTICAST if mpls => {}
+        _ => return Ok(None),
+    }
+    let options = [0x8000, 0x2000, 0x1000]
+        .iter()
+        .filter(|bit| flags & *bit != 0)
+        .count();
+    let header_len = GRE_LEN + options * 4;
+    if body.len() < header_len {
+        return Err(Malformed::Tunnel);
+    }
+    let inner = &body[header_len..];
+    if protocol == ETHERTYPE_TEB {
+        let (ethertype, payload) = inner_ethernet(inner)?;
+        return Ok(Some(Inner {
+            ethertype,
+            payload,
+            overhead: header_len as u64,
+        }));
+    }
+    Ok(Some(Inner {
+        ethertype: protocol,
+        payload: inner,
+        overhead: header_len as u64,
+    }))
+}
+
+// 宛先ポートが4789のUDPをVXLANとして、中のEthernetフレームを取り出す
+// VNIが有効（Iフラグ）なVXLANヘッダーがなければ、ただのUDPとして外側のアドレスで数える
+fn vxlan(body: &[u8]) -> Result<Option<Inner<'_>>, Malformed> {
+    if body.len() < UDP_LEN + VXLAN_LEN
+        || u16::from_be_bytes([body[2], body[3]]) != VXLAN_PORT
+        || body[UDP_LEN] & 0x08 == 0
+    {
+        return Ok(None);
+    }
+    let frame = &body[UDP_LEN + VXLAN_LEN..];
+    let (ethertype, payload) = inner_ethernet(frame)?;
+    Ok(Some(Inner {
+        ethertype,
+        payload,
+        overhead: (UDP_LEN + VXLAN_LEN) as u64,
+    }))
+}
+
+// トンネルで運ばれるEthernetフレーム。ヘッダーは元のフレームのものなのでオーバーヘッドには含めない
+// 内側のVLANタグは外すだけで、vlanラベルには使わない
+fn inner_ethernet(frame: &[u8]) -> Result<(u16, &[u8]), Malformed> {
+    if frame.len() < ETHERNET_LEN {
+        return Err(Malformed::Tunnel);
+    }
+    let ethertype = u16::from_be_bytes([frame[12], frame[13]]);
+    vlan::strip_tags(ethertype, &frame[ETHERNET_LEN..], &mut VlanTags::default())
+        .ok_or(Malformed::Vlan)
+}
+
+impl fmt::Display for Decapsulation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = self.encapsulations().map(Encapsulation::name).collect();
+        if names.is_empty() {
+            return write!(f, "none");
+        }
+        write!(f, "{}", names.join(", "))?;
+        if self.count_overhead {
+            write!(f, " (counting tunnel overhead)")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INNER: [u8; 4] = [0xde, 0xad, 0xbe, 0xef];
+
+    fn ipv4(protocol: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, protocol, 0, 0];
+        packet[2..4].copy_from_slice(&((IPV4_MIN_LEN + payload.len()) as u16).to_be_bytes());
+        packet.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1]);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn ipv6(next_header: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x60, 0, 0, 0];
+        packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&[next_header, 64]);
+        packet.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8]);
+        packet.resize(IPV6_LEN, 1);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn ethernet(ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0u8; 12];
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn pppoe(protocol: u16, payload: &[u8]) -> Vec<u8> {
+        let mut session = vec![0x11, 0x00, 0x00, 0x01];
+        session.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        session.extend_from_slice(&protocol.to_be_bytes());
+        session.extend_from_slice(payload);
+        session
+    }
+
+    fn decap(encapsulations: &[Encapsulation]) -> Decapsulation {
+        Decapsulation::new(encapsulations, false)
+    }
+
+    // 外せなかったことを確かめる（EtherTypeとペイロードがそのまま返る）
+    fn assert_untouched(decap: Decapsulation, ethertype: u16, payload: &[u8]) {
+        let inner = decap.unwrap(ethertype, payload).unwrap();
+        assert_eq!(inner.ethertype, ethertype);
+        assert_eq!(inner.payload, payload);
+        assert_eq!(inner.overhead, 0);
+    }
+
+    #[test]
+    fn leaves_packets_alone_when_nothing_is_enabled() {
+        let session = pppoe(PPP_IPV4, &ipv4(6, &INNER));
+        assert_untouched(decap(&[]), ETHERTYPE_PPPOE_SESSION, &session);
+        let packet = ipv4(IPPROTO_GRE, &[0, 0, 0x08, 0x00]);
+        assert_untouched(decap(&[]), ETHERTYPE_IPV4, &packet);
+        assert_untouched(decap(&[Encapsulation::Pppoe]), ETHERTYPE_IPV4, &packet);
+    }
+
+    #[test]
+    fn strips_pppoe_and_the_ethernet_padding() {
+        let ip = ipv4(6, &INNER);
+        let mut session = pppoe(PPP_IPV4, &ip);
+        session.extend_from_slice(&[0; 10]);
+        let inner = decap(&[Encapsulation::Pppoe])
+            .unwrap(ETHERTYPE_PPPOE_SESSION, &session)
+            .unwrap();
+        assert_eq!(inner.ethertype, ETHERTYPE_IPV4);
+        assert_eq!(inner.payload, &ip[..]);
+        assert_eq!(inner.overhead, 8);
+
+        // LCPなどの制御パケットはIPではない
+        let lcp = pppoe(0xc021, &[1, 1, 0, 4]);
+        let inner = decap(&[Encapsulation::Pppoe])
+            .unwrap(ETHERTYPE_PPPOE_SESSION, &lcp)
+            .unwrap();
+        assert_eq!(inner.ethertype, ETHERTYPE_OTHER);
+
+        assert!(matches!(
+            decap(&[Encapsulation::Pppoe]).unwrap(ETHERTYPE_PPPOE_SESSION, &session[..7]),
+            Err(Malformed::Tunnel)
+        ));
+    }
+
+    #[test]
+    fn strips_the_whole_mpls_label_stack() {
+        let ip = ipv6(17, &INNER);
+        // 2つ目のラベルにスタックの底（S）ビット
+        let mut labels = vec![0x00, 0x01, 0x00, 64, 0x00, 0x02, 0x01, 64];
+        labels.extend_from_slice(&ip);
+        let inner = decap(&[Encapsulation::Mpls])
+            .unwrap(ETHERTYPE_MPLS_UNICAST, &labels)
+            .unwrap();
+        assert_eq!(inner.ethertype, ETHERTYPE_IPV6);
+        assert_eq!(inner.payload, &ip[..]);
+        assert_eq!(inner.overhead, 8);
+
+        // 疑似回線のコントロールワードはIPではない
+        let pseudowire = [0x00, 0x03, 0x01, 64, 0x00, 0x00, 0x00, 0x00];
+        let inner = decap(&[Encapsulation::Mpls])
+            .unwrap(ETHERTYPE_MPLS_MULTICAST, &pseudowire)
+            .unwrap();
+        assert_eq!(inner.ethertype, ETHERTYPE_OTHER);
+
+        assert!(matches!(
+            decap(&[Encapsulation::Mpls]).unwrap(ETHERTYPE_MPLS_UNICAST, &labels[..4]),
+            Err(Malformed::Tunnel)
+        ));
+    }
+
+    #[test]
+    fn strips_gre_with_key_and_sequence_number() {
+        let ip = ipv4(6, &INNER);
+        let mut gre = vec![0x30, 0x00, 0x08, 0x00];
+        gre.extend_from_slice(&[0, 0, 0, 42, 0, 0, 0, 7]);
+        gre.extend_from_slice(&ip);
+        let outer = ipv4(IPPROTO_GRE, &gre);
+        let inner = decap(&[Encapsulation::Gre])
+            .unwrap(ETHERTYPE_IPV4, &outer)
+            .unwrap();
+        assert_eq!(inner.ethertype, ETHERTYPE_IPV4);
+        assert_eq!(inner.payload, &ip[..]);
+        assert_eq!(inner.overhead, 20 + 12);
+
+        // キーがあるはずの位置で切れている
+        let truncated = ipv4(IPPROTO_GRE, &gre[..6]);
+        assert!(matches!(
+            decap(&[Encapsulation::Gre]).unwrap(ETHERTYPE_IPV4, &truncated),
+            Err(Malformed::Tunnel)
+        ));
+    }
+
+    #[test]
+    fn leaves_pptp_gre_alone() {
+        let mut gre = vec![0x30, 0x01, 0x88, 0x0b, 0, 0, 0, 0, 0, 0, 0, 1];
+        gre.extend_from_slice(&INNER);
+        let outer = ipv4(IPPROTO_GRE, &gre);
+        assert_untouched(decap(&[Encapsulation::Gre]), ETHERTYPE_IPV4, &outer);
+    }
+
+    #[test]
+    fn leaves_gre_that_carries_neither_ip_nor_ethernet_alone() {
+        // ERSPANとPPP
+        for protocol in [0x88be_u16, 0x880b] {
+            let mut gre = vec![0x10, 0x00];
+            gre.extend_from_slice(&protocol.to_be_bytes());
+            gre.extend_from_slice(&[0, 0, 0, 1]);
+            gre.extend_from_slice(&INNER);
+            let outer = ipv4(IPPROTO_GRE, &gre);
+            assert_untouched(decap(&[Encapsulation::Gre]), ETHERTYPE_IPV4, &outer);
+        }
+
+        // MPLSはMPLSも外すときだけ中を見る
+        let ip = ipv4(6, &INNER);
+        let mut gre = vec![0x00, 0x00, 0x88, 0x47, 0x00, 0x01, 0x01, 64];
+        gre.extend_from_slice(&ip);
+        let outer = ipv4(IPPROTO_GRE, &gre);
+        assert_untouched(decap(&[Encapsulation::Gre]), ETHERTYPE_IPV4, &outer);
+        let inner = decap(&[Encapsulation::Gre, Encapsulation::Mpls])
+            .unwrap(ETHERTYPE_IPV4, &outer)
+            .unwrap();
+        assert_eq!(inner.ethertype, ETHERTYPE_IPV4);
+        assert_eq!(inner.payload, &ip[..]);
+        assert_eq!(inner.overhead, 20 + 4 + 4);
+    }
+
+    #[test]
+    fn gre_teb_carries_a_tagged_ethernet_frame() {
+        let ip = ipv4(17, &INNER);
+        let mut tagged = vec![0x00, 0x05, 0x08, 0x00];
+        tagged.extend_from_slice(&ip);
+        let mut gre = vec![0x00, 0x00, 0x65, 0x58];
+        gre.extend(ethernet(0x8100, &tagged));
+        let outer = ipv4(IPPROTO_GRE, &gre);
+        let inner = decap(&[Encapsulation::Gre])
+            .unwrap(ETHERTYPE_IPV4, &outer)
+            .unwrap();
+        assert_eq!(inner.ethertype, ETHERTYPE_IPV4);
+        assert_eq!(inner.payload, &ip[..]);
+        // 運ばれるEthernetヘッダーとVLANタグは元のフレームの一部
+        assert_eq!(inner.overhead, 20 + 4);
+    }
+
+    #[test]
+    fn strips_vxlan_only_on_its_port() {
+        let ip = ipv6(6, &INNER);
+        let mut udp = vec![0xc0, 0x00];
+        udp.extend_from_slice(&VXLAN_PORT.to_be_bytes());
+        udp.extend_from_slice(&[0, 0, 0, 0]);
+        udp.extend_from_slice(&[0x08, 0, 0, 0, 0, 0x10, 0x00, 0]);
+        udp.extend(ethernet(ETHERTYPE_IPV6, &ip));
+        let outer = ipv6(IPPROTO_UDP, &udp);
+        let inner = decap(&[Encapsulation::Vxlan])
+            .unwrap(ETHERTYPE_IPV6, &outer)
+            .unwrap();
+        assert_eq!(inner.ethertype, ETHERTYPE_IPV6);
+        assert_eq!(inner.payload, &ip[..]);
+        assert_eq!(inner.overhead, 40 + 8 + 8);
+
+        // Iフラグのないヘッダーと、VXLANヘッダーに届かないものはただのUDP
+        let mut invalid = udp.clone();
+        invalid[8] = 0;
+        let other = ipv6(IPPROTO_UDP, &invalid);
+        assert_untouched(decap(&[Encapsulation::Vxlan]), ETHERTYPE_IPV6, &other);
+        let short = ipv6(IPPROTO_UDP, &udp[..12]);
+        assert_untouched(decap(&[Encapsulation::Vxlan]), ETHERTYPE_IPV6, &short);
+
+        udp[2..4].copy_from_slice(&4790u16.to_be_bytes());
+        let other = ipv6(IPPROTO_UDP, &udp);
+        assert_untouched(decap(&[Encapsulation::Vxlan]), ETHERTYPE_IPV6, &other);
+    }
+
+    #[test]
+    fn strips_ip_in_ip_in_both_directions() {
+        let ip6 = ipv6(6, &INNER);
+        let sit = ipv4(IPPROTO_IPV6, &ip6);
+        let inner = decap(&[Encapsulation::Ipip])
+            .unwrap(ETHERTYPE_IPV4, &sit)
+            .unwrap();
+        assert_eq!(inner.ethertype, ETHERTYPE_IPV6);
+        assert_eq!(inner.payload, &ip6[..]);
+        assert_eq!(inner.overhead, 20);
+
+        let ip4 = ipv4(6, &INNER);
+        let outer = ipv6(IPPROTO_IPIP, &ip4);
+        let inner = decap(&[Encapsulation::Ipip])
+            .unwrap(ETHERTYPE_IPV6, &outer)
+            .unwrap();
+        assert_eq!(inner.ethertype, ETHERTYPE_IPV4);
+        assert_eq!(inner.payload, &ip4[..]);
+        assert_eq!(inner.overhead, 40);
+
+        assert_untouched(decap(&[Encapsulation::Gre]), ETHERTYPE_IPV4, &sit);
+    }
+
+    #[test]
+    fn strips_nested_tunnels_and_adds_up_the_overhead() {
+        let ip = ipv4(6, &INNER);
+        let mut gre = vec![0x00, 0x00, 0x08, 0x00];
+        gre.extend_from_slice(&ip);
+        let session = pppoe(PPP_IPV4, &ipv4(IPPROTO_GRE, &gre));
+        let inner = decap(&[Encapsulation::Pppoe, Encapsulation::Gre])
+            .unwrap(ETHERTYPE_PPPOE_SESSION, &session)
+            .unwrap();
+        assert_eq!(inner.ethertype, ETHERTYPE_IPV4);
+        assert_eq!(inner.payload, &ip[..]);
+        assert_eq!(inner.overhead, 8 + 20 + 4);
+    }
+
+    #[test]
+    fn later_fragments_of_a_tunnel_are_not_unwrapped() {
+        let mut outer = ipv4(IPPROTO_IPV6, &ipv6(6, &INNER));
+        outer[6..8].copy_from_slice(&0x0001u16.to_be_bytes());
+        assert_untouched(decap(&[Encapsulation::Ipip]), ETHERTYPE_IPV4, &outer);
+    }
+
+    #[test]
+    fn rejects_a_malformed_outer_header() {
+        let mut outer = ipv4(IPPROTO_GRE, &[0, 0, 0x08, 0x00]);
+        outer[0] = 0x44;
+        assert!(matches!(
+            decap(&[Encapsulation::Gre]).unwrap(ETHERTYPE_IPV4, &outer),
+            Err(Malformed::Ipv4)
+        ));
+        // 固定長のIPv6ヘッダー（40バイト）に届かない
+        assert!(matches!(
+            decap(&[Encapsulation::Gre]).unwrap(ETHERTYPE_IPV6, &outer),
+            Err(Malformed::Ipv6)
+        ));
+    }
+
+    #[test]
+    fn leaves_malformed_ip_headers_to_the_ip_parser_without_ip_tunnels() {
+        let mut outer = ipv4(IPPROTO_GRE, &[0, 0, 0x08, 0x00]);
+        outer[0] = 0x44;
+        assert_untouched(decap(&[]), ETHERTYPE_IPV4, &outer);
+        assert_untouched(decap(&[Encapsulation::Pppoe]), ETHERTYPE_IPV4, &outer);
+        assert_untouched(decap(&[Encapsulation::Mpls]), ETHERTYPE_IPV6, &outer);
+    }
+
+    #[test]
+    fn builds_the_bpf_expression_and_label_from_the_enabled_encapsulations() {
+        assert_eq!(decap(&[]).bpf_expression(LinkType::Ethernet), None);
+        assert_eq!(decap(&[]).to_string(), "none");
+        let both = Decapsulation::new(&[Encapsulation::Vxlan, Encapsulation::Pppoe], true);
+        assert_eq!(
+            both.bpf_expression(LinkType::Ethernet).as_deref(),
+            Some("ether proto 0x8864 or udp port 4789")
+        );
+        assert_eq!(both.to_string(), "pppoe, vxlan (counting tunnel overhead)");
+    }
+
+    #[test]
+    fn leaves_ethertype_terms_out_on_links_without_ethertypes() {
+        let both = decap(&[Encapsulation::Mpls, Encapsulation::Gre]);
+        assert_eq!(
+            both.bpf_expression(LinkType::LinuxSll).as_deref(),
+            Some("ether proto 0x8847 or ether proto 0x8848 or ip proto 47 or ip6 proto 47")
+        );
+        assert_eq!(
+            both.bpf_expression(LinkType::Raw).as_deref(),
+            Some("ip proto 47 or ip6 proto 47")
+        );
+        assert_eq!(
+            decap(&[Encapsulation::Pppoe]).bpf_expression(LinkType::Null),
+            None
+        );
+    }
+}
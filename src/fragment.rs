@@ -0,0 +1,300 @@
+use crate::HostKey;
+use std::collections::HashMap;
+use std::time::Duration;
+
+// 再構築を待つ時間（Linuxのipfrag_timeの既定値と同じ）
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+// 同時に再構築するデータグラムの上限（1ワーカーあたり）
+const MAX_PENDING: usize = 256;
+// 諦めたデータグラムを覚えておく上限（残りのフラグメントで失敗を数え直さないため）
+const MAX_REJECTED: usize = MAX_PENDING * 4;
+const MAX_DATAGRAM_LEN: usize = 65535;
+const MAX_STORED_LEN: usize = MAX_DATAGRAM_LEN * 2;
+
+// IPv4ヘッダー、またはIPv6のフラグメントヘッダーのフラグメント情報
+#[derive(Debug, Clone, Copy)]
+pub struct Fragment {
+    pub id: u32,
+    pub offset: usize, // バイト単位
+    pub more: bool,    // 後続のフラグメントがある（MFフラグ）
+}
+
+impl Fragment {
+    pub fn is_fragment(&self) -> bool {
+        self.offset != 0 || self.more
+    }
+}
+
+// 同じデータグラムのフラグメントを見分けるキー
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DatagramKey {
+    pub src: HostKey,
+    pub dst: HostKey,
+    pub protocol: u8,
+    pub id: u32,
+}
+
+// 再構築中のデータグラム
+struct PendingDatagram {
+    first_seen: Duration,
+    pieces: Vec<(usize, Vec<u8>)>, // (オフセット, データ)
+    total_len: Option<usize>,      // 最後のフラグメントが届くと分かる
+    hosts: Vec<HostKey>,           // 失敗したときに数える監視対象のホスト
+    stored: usize,                 // 重複を含めて受け取ったバイト数
+}
+
+impl PendingDatagram {
+    // すべての範囲が揃っていれば、つなげたペイロードを返す
+    fn assemble(&mut self) -> Option<Vec<u8>> {
+        let total_len = self.total_len?;
+        self.pieces.sort_by_key(|(offset, _)| *offset);
+        let mut covered = 0;
+        for (offset, data) in &self.pieces {
+            if *offset > covered {
+                return None;
+            }
+            covered = covered.max(offset + data.len());
+        }
+        if covered < total_len {
+            return None;
+        }
+        let mut datagram = vec![0u8; total_len];
+        for (offset, data) in &self.pieces {
+            let end = (offset + data.len()).min(total_len);
+            if *offset < end {
+                datagram[*offset..end].copy_from_slice(&data[..end - offset]);
+            }
+        }
+        Some(datagram)
+    }
+}
+
+// 分割されたIPデータグラムをL4の解析用に再構築する
+// 同じデータグラムのフラグメントはどれもアドレスだけでfanoutされるので、ワーカーごとに持てばよい
+#[derive(Default)]
+pub struct Reassembler {
+    pending: HashMap<DatagramKey, PendingDatagram>,
+    rejected: HashMap<DatagramKey, Duration>, // 諦めたデータグラムと諦めた時刻
+}
+
+// フラグメントを受け取った結果
+pub enum Reassembly {
+    Pending,
+    Complete(Vec<u8>), // 上位層のペイロード（IPv6では分割された部分の先頭から）
+    Failed,            // 上限を超えたので諦めた
+    Discarded,         // 既に諦めたデータグラムの残りのフラグメント
+}
+
+impl Reassembler {
+    // hostsは再構築に失敗したときに数える監視対象のホスト
+    pub fn insert(
+        &mut self,
+        key: DatagramKey,
+        fragment: Fragment,
+        payload: &[u8],
+        hosts: &[HostKey],
+        now: Duration,
+    ) -> Reassembly {
+        if self.rejected.contains_key(&key) {
+            return Reassembly::Discarded;
+        }
+        let end = fragment.offset + payload.len();
+        if end > MAX_DATAGRAM_LEN {
+            return self.reject(key, now);
+        }
+        if !self.pending.contains_key(&key) && self.pending.len() >= MAX_PENDING {
+            return self.reject(key, now);
+        }
+
+        let datagram = self.pending.entry(key).or_insert_with(|| PendingDatagram {
+            first_seen: now,
+            pieces: Vec::new(),
+            total_len: None,
+            hosts: hosts.to_vec(),
+            stored: 0,
+        });
+        // 重複したフラグメントを送り続けられてもメモリを使い切らないようにする
+        datagram.stored += payload.len();
+        if datagram.stored > MAX_STORED_LEN {
+            return self.reject(key, now);
+        }
+        datagram.pieces.push((fragment.offset, payload.to_vec()));
+        if !fragment.more {
+            datagram.total_len = Some(end);
+        }
+        match datagram.assemble() {
+            Some(payload) => {
+                self.pending.remove(&key);
+                Reassembly::Complete(payload)
+            }
+            None => Reassembly::Pending,
+        }
+    }
+
+    // 失敗はデータグラムごとに1回だけ数える
+    fn reject(&mut self, key: DatagramKey, now: Duration) -> Reassembly {
+        self.pending.remove(&key);
+        if self.rejected.len() < MAX_REJECTED {
+            self.rejected.insert(key, now);
+        }
+        Reassembly::Failed
+    }
+
+    // 時間内に揃わなかったデータグラムを捨て、失敗として数えるホストを返す
+    pub fn expire(&mut self, now: Duration) -> Vec<HostKey> {
+        self.rejected
+            .retain(|_, rejected_at| now.saturating_sub(*rejected_at) < REASSEMBLY_TIMEOUT);
+        let mut failed = Vec::new();
+        self.pending.retain(|_, datagram| {
+            if now.saturating_sub(datagram.first_seen) < REASSEMBLY_TIMEOUT {
+                return true;
+            }
+            failed.extend_from_slice(&datagram.hosts);
+            false
+        });
+        failed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vlan::VlanTags;
+
+    fn host(ip: &str) -> HostKey {
+        HostKey {
+            ip: ip.parse().unwrap(),
+            vlan: VlanTags::default(),
+        }
+    }
+
+    fn key(id: u32) -> DatagramKey {
+        DatagramKey {
+            src: host("10.40.0.5"),
+            dst: host("203.0.113.1"),
+            protocol: 6,
+            id,
+        }
+    }
+
+    fn fragment(offset: usize, more: bool) -> Fragment {
+        Fragment {
+            id: 1,
+            offset,
+            more,
+        }
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn only_a_nonzero_offset_or_more_fragments_is_a_fragment() {
+        assert!(!fragment(0, false).is_fragment());
+        assert!(fragment(0, true).is_fragment());
+        assert!(fragment(1480, false).is_fragment());
+    }
+
+    #[test]
+    fn reassembles_fragments_in_any_order() {
+        let datagram: Vec<u8> = (0..40).collect();
+        let hosts = [host("10.40.0.5")];
+        let mut reassembler = Reassembler::default();
+        assert!(matches!(
+            reassembler.insert(
+                key(1),
+                fragment(24, false),
+                &datagram[24..],
+                &hosts,
+                secs(0)
+            ),
+            Reassembly::Pending
+        ));
+        assert!(matches!(
+            reassembler.insert(key(1), fragment(0, true), &datagram[..16], &hosts, secs(0)),
+            Reassembly::Pending
+        ));
+        // 重なったフラグメントで残りが埋まる
+        match reassembler.insert(key(1), fragment(8, true), &datagram[8..32], &hosts, secs(1)) {
+            Reassembly::Complete(payload) => assert_eq!(payload, datagram),
+            _ => panic!("datagram should be complete"),
+        }
+        // 揃ったデータグラムは残さない
+        assert!(reassembler.expire(secs(60)).is_empty());
+    }
+
+    #[test]
+    fn waits_for_the_last_fragment_and_for_holes() {
+        let data = [0u8; 16];
+        let mut reassembler = Reassembler::default();
+        reassembler.insert(key(1), fragment(0, true), &data, &[], secs(0));
+        assert!(matches!(
+            reassembler.insert(key(1), fragment(16, true), &data, &[], secs(0)),
+            Reassembly::Pending
+        ));
+        // 32から48が欠けている
+        assert!(matches!(
+            reassembler.insert(key(1), fragment(48, false), &data, &[], secs(0)),
+            Reassembly::Pending
+        ));
+        // 別のデータグラムとは混ざらない
+        assert!(matches!(
+            reassembler.insert(key(2), fragment(32, true), &data, &[], secs(0)),
+            Reassembly::Pending
+        ));
+        assert!(matches!(
+            reassembler.insert(key(1), fragment(32, true), &data, &[], secs(0)),
+            Reassembly::Complete(payload) if payload.len() == 64
+        ));
+    }
+
+    #[test]
+    fn expires_incomplete_datagrams_and_reports_their_hosts() {
+        let hosts = [host("10.40.0.5"), host("10.40.0.6")];
+        let mut reassembler = Reassembler::default();
+        reassembler.insert(key(1), fragment(0, true), &[0; 8], &hosts, secs(100));
+        assert!(reassembler.expire(secs(129)).is_empty());
+        assert_eq!(reassembler.expire(secs(130)), hosts.to_vec());
+        assert!(reassembler.expire(secs(200)).is_empty());
+    }
+
+    #[test]
+    fn rejects_datagrams_beyond_the_limits() {
+        let mut reassembler = Reassembler::default();
+        assert!(matches!(
+            reassembler.insert(key(1), fragment(65528, false), &[0; 8], &[], secs(0)),
+            Reassembly::Failed
+        ));
+        for id in 0..MAX_PENDING as u32 {
+            reassembler.insert(key(100 + id), fragment(0, true), &[0; 8], &[], secs(0));
+        }
+        assert!(matches!(
+            reassembler.insert(key(2), fragment(0, true), &[0; 8], &[], secs(0)),
+            Reassembly::Failed
+        ));
+    }
+
+    #[test]
+    fn counts_a_rejected_datagram_once() {
+        let mut reassembler = Reassembler::default();
+        let data = [0u8; 1480];
+        for _ in 0..MAX_STORED_LEN / data.len() {
+            reassembler.insert(key(1), fragment(0, true), &data, &[], secs(0));
+        }
+        assert!(matches!(
+            reassembler.insert(key(1), fragment(0, true), &data, &[], secs(0)),
+            Reassembly::Failed
+        ));
+        assert!(matches!(
+            reassembler.insert(key(1), fragment(1480, false), &data, &[], secs(1)),
+            Reassembly::Discarded
+        ));
+        // 諦めたことは再構築の待ち時間だけ覚えておく
+        reassembler.expire(secs(30));
+        assert!(matches!(
+            reassembler.insert(key(1), fragment(1480, false), &data, &[], secs(31)),
+            Reassembly::Pending
+        ));
+    }
+}
@@ -0,0 +1,119 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// 解析できなかったパケットの層（malformedカウンタのlayerラベル）
+#[derive(Debug, Clone, Copy)]
+pub enum Malformed {
+    Ethernet,
+    Link, // Ethernet以外のリンク層ヘッダー
+    Vlan,
+    Ipv4,
+    Ipv6,
+    Tunnel, // PPPoE、MPLS、GRE、VXLANのヘッダー
+    Tcp,
+}
+
+impl Malformed {
+    pub const ALL: [Malformed; 7] = [
+        Malformed::Ethernet,
+        Malformed::Link,
+        Malformed::Vlan,
+        Malformed::Ipv4,
+        Malformed::Ipv6,
+        Malformed::Tunnel,
+        Malformed::Tcp,
+    ];
+
+    pub fn layer(self) -> &'static str {
+        match self {
+            Malformed::Ethernet => "ethernet",
+            Malformed::Link => "link",
+            Malformed::Vlan => "vlan",
+            Malformed::Ipv4 => "ipv4",
+            Malformed::Ipv6 => "ipv6",
+            Malformed::Tunnel => "tunnel",
+            Malformed::Tcp => "tcp",
+        }
+    }
+}
+
+// 上限に達していて追跡できなかったもの（untrackedカウンタのkindラベル）
+#[derive(Debug, Clone, Copy)]
+pub enum Untracked {
+    Host, // 新しいホスト（バイト数も数えない）
+    Flow, // 新しいTCPフロー（バイト数は数えるが、再送などの解析はしない）
+}
+
+impl Untracked {
+    pub const ALL: [Untracked; 2] = [Untracked::Host, Untracked::Flow];
+
+    pub fn kind(self) -> &'static str {
+        match self {
+            Untracked::Host => "host",
+            Untracked::Flow => "flow",
+        }
+    }
+}
+
+// キャプチャの健全性に関するカウンタ
+// キャプチャスレッドが更新し、統計スレッドがメトリクスに反映する。再オープンをまたいで累計する
+#[derive(Debug, Default)]
+pub struct CaptureHealth {
+    received: AtomicU64,   // libpcapが受け取ったパケット数
+    dropped: AtomicU64,    // バッファが足りずにカーネル/libpcapが落としたパケット数
+    if_dropped: AtomicU64, // インターフェース（ドライバ）が落としたパケット数
+    malformed: [AtomicU64; Malformed::ALL.len()],
+    untracked: [AtomicU64; Untracked::ALL.len()], // 上限のために追跡しなかったパケット数
+}
+
+// ある時点の値（表示やJSON出力用）
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct HealthSnapshot {
+    pub received: u64,
+    pub dropped: u64,
+    pub if_dropped: u64,
+    pub malformed: u64,
+}
+
+impl CaptureHealth {
+    pub fn count_malformed(&self, malformed: Malformed) {
+        self.malformed[malformed as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn malformed(&self, malformed: Malformed) -> u64 {
+        self.malformed[malformed as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn count_untracked(&self, untracked: Untracked) {
+        self.untracked[untracked as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn untracked(&self, untracked: Untracked) -> u64 {
+        self.untracked[untracked as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn add_counts(&self, received: u64, dropped: u64, if_dropped: u64) {
+        self.received.fetch_add(received, Ordering::Relaxed);
+        self.dropped.fetch_add(dropped, Ordering::Relaxed);
+        self.if_dropped.fetch_add(if_dropped, Ordering::Relaxed);
+    }
+
+    // pcap_statsの値はハンドルを開いてからの累計なので、前回の値との差分を足す
+    // 32ビットのカウンタは折り返すことがあるためwrapping_subで差を取る
+    pub fn add_pcap_stats(&self, previous: &pcap::Stat, current: &pcap::Stat) {
+        self.add_counts(
+            current.received.wrapping_sub(previous.received) as u64,
+            current.dropped.wrapping_sub(previous.dropped) as u64,
+            current.if_dropped.wrapping_sub(previous.if_dropped) as u64,
+        );
+    }
+
+    pub fn snapshot(&self) -> HealthSnapshot {
+        HealthSnapshot {
+            received: self.received.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            if_dropped: self.if_dropped.load(Ordering::Relaxed),
+            malformed: Malformed::ALL.iter().map(|m| self.malformed(*m)).sum(),
+        }
+    }
+}
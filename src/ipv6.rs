@@ -0,0 +1,154 @@
+use crate::fragment::Fragment;
+use crate::health::Malformed;
+
+const HOP_BY_HOP: u8 = 0;
+const ROUTING: u8 = 43;
+const FRAGMENT: u8 = 44;
+const AUTHENTICATION: u8 = 51;
+const DESTINATION_OPTIONS: u8 = 60;
+const MOBILITY: u8 = 135;
+const FRAGMENT_HEADER_LEN: usize = 8;
+
+// 拡張ヘッダーを読み飛ばした後の上位層
+pub struct UpperLayer<'a> {
+    pub protocol: u8,
+    pub payload: &'a [u8],
+    // フラグメントヘッダーがあれば、その後ろ（分割された部分）がpayloadになる
+    pub fragment: Option<Fragment>,
+}
+
+// IPv6の拡張ヘッダーをたどり、上位層のプロトコル番号とペイロードを返す
+// フラグメントヘッダーで止まる（その後ろの拡張ヘッダーは再構築してから読む）
+// ESPや未知のヘッダーは上位層として扱う
+pub fn upper_layer(mut next_header: u8, mut payload: &[u8]) -> Result<UpperLayer<'_>, Malformed> {
+    loop {
+        let header_len = match next_header {
+            HOP_BY_HOP | ROUTING | DESTINATION_OPTIONS | MOBILITY => {
+                (*payload.get(1).ok_or(Malformed::Ipv6)? as usize + 1) * 8
+            }
+            AUTHENTICATION => (*payload.get(1).ok_or(Malformed::Ipv6)? as usize + 2) * 4,
+            FRAGMENT => {
+                let header = payload.get(..FRAGMENT_HEADER_LEN).ok_or(Malformed::Ipv6)?;
+                let offset_flags = u16::from_be_bytes([header[2], header[3]]);
+                let fragment = Fragment {
+                    id: u32::from_be_bytes([header[4], header[5], header[6], header[7]]),
+                    offset: (offset_flags & 0xfff8) as usize,
+                    more: offset_flags & 0x0001 != 0,
+                };
+                return Ok(UpperLayer {
+                    protocol: header[0],
+                    payload: &payload[FRAGMENT_HEADER_LEN..],
+                    // オフセット0で後続もないフラグメントヘッダー（アトミックフラグメント）は分割されていない
+                    fragment: fragment.is_fragment().then_some(fragment),
+                });
+            }
+            _ => {
+                return Ok(UpperLayer {
+                    protocol: next_header,
+                    payload,
+                    fragment: None,
+                })
+            }
+        };
+        if payload.len() < header_len {
+            return Err(Malformed::Ipv6);
+        }
+        next_header = payload[0];
+        payload = &payload[header_len..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TCP: u8 = 6;
+    const ESP: u8 = 50;
+
+    // 8バイト単位の長さを持つ拡張ヘッダー（Hop-by-Hop、ルーティング、宛先オプション）
+    fn extension(next_header: u8, units: u8) -> Vec<u8> {
+        let mut header = vec![next_header, units];
+        header.resize((units as usize + 1) * 8, 0);
+        header
+    }
+
+    fn fragment_header(next_header: u8, offset: u16, more: bool, id: u32) -> Vec<u8> {
+        let mut header = vec![next_header, 0];
+        header.extend_from_slice(&(offset | more as u16).to_be_bytes());
+        header.extend_from_slice(&id.to_be_bytes());
+        header
+    }
+
+    #[test]
+    fn returns_the_payload_when_there_are_no_extension_headers() {
+        let upper = upper_layer(TCP, &[1, 2, 3]).unwrap();
+        assert_eq!(upper.protocol, TCP);
+        assert_eq!(upper.payload, &[1, 2, 3]);
+        assert!(upper.fragment.is_none());
+    }
+
+    #[test]
+    fn walks_a_chain_of_extension_headers() {
+        let mut packet = extension(ROUTING, 0);
+        packet.extend(extension(DESTINATION_OPTIONS, 2));
+        packet.extend(extension(AUTHENTICATION, 0));
+        // AHの長さは4バイト単位で、2を引いた値
+        let mut ah = vec![TCP, 4];
+        ah.resize(24, 0);
+        packet.extend(ah);
+        packet.extend_from_slice(&[0xaa, 0xbb]);
+
+        let upper = upper_layer(HOP_BY_HOP, &packet).unwrap();
+        assert_eq!(upper.protocol, TCP);
+        assert_eq!(upper.payload, &[0xaa, 0xbb]);
+        assert!(upper.fragment.is_none());
+    }
+
+    #[test]
+    fn stops_at_the_fragment_header() {
+        let mut packet = extension(FRAGMENT, 0);
+        packet.extend(fragment_header(TCP, 1232, true, 0x1234_5678));
+        packet.extend_from_slice(&[0xaa; 16]);
+        let upper = upper_layer(HOP_BY_HOP, &packet).unwrap();
+        assert_eq!(upper.protocol, TCP);
+        assert_eq!(upper.payload, &[0xaa; 16]);
+        let fragment = upper.fragment.unwrap();
+        assert_eq!(fragment.id, 0x1234_5678);
+        assert_eq!(fragment.offset, 1232);
+        assert!(fragment.more);
+    }
+
+    #[test]
+    fn an_atomic_fragment_is_not_fragmented() {
+        let mut packet = fragment_header(TCP, 0, false, 1);
+        packet.extend_from_slice(&[0xaa; 4]);
+        let upper = upper_layer(FRAGMENT, &packet).unwrap();
+        assert_eq!(upper.protocol, TCP);
+        assert_eq!(upper.payload, &[0xaa; 4]);
+        assert!(upper.fragment.is_none());
+    }
+
+    #[test]
+    fn esp_and_unknown_headers_are_upper_layers() {
+        assert_eq!(upper_layer(ESP, &[0; 8]).unwrap().protocol, ESP);
+        assert_eq!(upper_layer(253, &[0; 8]).unwrap().protocol, 253);
+    }
+
+    #[test]
+    fn truncated_extension_headers_are_malformed() {
+        assert!(matches!(upper_layer(HOP_BY_HOP, &[]), Err(Malformed::Ipv6)));
+        // 長さは16バイトだが8バイトしかない
+        assert!(matches!(
+            upper_layer(ROUTING, &extension(TCP, 1)[..8]),
+            Err(Malformed::Ipv6)
+        ));
+        assert!(matches!(
+            upper_layer(FRAGMENT, &fragment_header(TCP, 8, false, 1)[..7]),
+            Err(Malformed::Ipv6)
+        ));
+        assert!(matches!(
+            upper_layer(AUTHENTICATION, &[TCP, 4, 0, 0]),
+            Err(Malformed::Ipv6)
+        ));
+    }
+}
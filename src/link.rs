@@ -0,0 +1,274 @@
+use pcap::Linktype;
+use std::fmt;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+// IP以外のパケット（集計の対象外）
+const ETHERTYPE_OTHER: u16 = 0;
+
+// DLT_RAWの値はOSによって違う（Linuxなどは12、OpenBSDは14）。ファイルではLINKTYPE_RAW(101)
+const DLT_RAW: [i32; 3] = [12, 14, 101];
+const ETHERNET_LEN: usize = 14;
+const LINUX_SLL_LEN: usize = 16;
+const LINUX_SLL2_LEN: usize = 20;
+const NULL_LEN: usize = 4;
+// DLT_NULL/DLT_LOOPのアドレスファミリ。IPv6の値はOSによって違う
+// （Linuxは10、NetBSDとOpenBSDは24、FreeBSDは28、macOSは30）
+const AF_INET: u32 = 2;
+const AF_INET6: [u32; 4] = [10, 24, 28, 30];
+
+// パケットの先頭にあるリンク層ヘッダーの種類（pcapのデータリンクタイプ）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkType {
+    Ethernet,
+    LinuxSll,  // anyデバイスなどのcookedキャプチャ
+    LinuxSll2, // 新しいlibpcapのanyデバイス
+    Raw,       // WireGuardやtunなど、IPパケットがそのまま来る
+    Ipv4,
+    Ipv6,
+    Null, // BSDのループバック（ホストのバイト順のアドレスファミリ）
+    Loop, // OpenBSDのループバック（ネットワークバイト順のアドレスファミリ）
+}
+
+impl LinkType {
+    pub fn from_pcap(linktype: Linktype) -> Result<Self, String> {
+        match linktype.0 {
+            1 => Ok(Self::Ethernet),
+            113 => Ok(Self::LinuxSll),
+            276 => Ok(Self::LinuxSll2),
+            dlt if DLT_RAW.contains(&dlt) => Ok(Self::Raw),
+            228 => Ok(Self::Ipv4),
+            229 => Ok(Self::Ipv6),
+            0 => Ok(Self::Null),
+            108 => Ok(Self::Loop),
+            dlt => Err(format!(
+                "unsupported link type {} ({})",
+                linktype
+                    .get_name()
+                    .unwrap_or_else(|_| "unknown".to_string()),
+                dlt
+            )),
+        }
+    }
+
+    // BPFをコンパイルするときのデータリンクタイプ
+    pub fn to_pcap(self) -> Linktype {
+        match self {
+            Self::Ethernet => Linktype::ETHERNET,
+            Self::LinuxSll => Linktype::LINUX_SLL,
+            Self::LinuxSll2 => Linktype::LINUX_SLL2,
+            Self::Raw => Linktype(DLT_RAW[0]),
+            Self::Ipv4 => Linktype::IPV4,
+            Self::Ipv6 => Linktype::IPV6,
+            Self::Null => Linktype::NULL,
+            Self::Loop => Linktype::LOOP,
+        }
+    }
+
+    // EtherTypeを持つリンク層か（libpcapのether protoで絞り込める）
+    pub fn carries_ethertype(self) -> bool {
+        matches!(self, Self::Ethernet | Self::LinuxSll | Self::LinuxSll2)
+    }
+
+    // libpcapのvlanキーワードが使えるのはEthernetだけ
+    pub fn supports_vlan(self) -> bool {
+        self == Self::Ethernet
+    }
+
+    // リンク層ヘッダーを外し、中身のEtherTypeとペイロードを返す（VLANタグは残る）
+    // ヘッダーが短すぎればNone
+    pub fn strip_header(self, data: &[u8]) -> Option<(u16, &[u8])> {
+        match self {
+            Self::Ethernet => {
+                let payload = data.get(ETHERNET_LEN..)?;
+                Some((u16::from_be_bytes([data[12], data[13]]), payload))
+            }
+            Self::LinuxSll => {
+                let payload = data.get(LINUX_SLL_LEN..)?;
+                Some((u16::from_be_bytes([data[14], data[15]]), payload))
+            }
+            Self::LinuxSll2 => {
+                let payload = data.get(LINUX_SLL2_LEN..)?;
+                Some((u16::from_be_bytes([data[0], data[1]]), payload))
+            }
+            Self::Raw => match data.first()? >> 4 {
+                4 => Some((ETHERTYPE_IPV4, data)),
+                6 => Some((ETHERTYPE_IPV6, data)),
+                _ => Some((ETHERTYPE_OTHER, data)),
+            },
+            Self::Ipv4 => Some((ETHERTYPE_IPV4, data)),
+            Self::Ipv6 => Some((ETHERTYPE_IPV6, data)),
+            Self::Null | Self::Loop => {
+                let payload = data.get(NULL_LEN..)?;
+                let header = [data[0], data[1], data[2], data[3]];
+                // DLT_NULLは書き込んだホストのバイト順なので、どちらの順でも解釈する
+                let family = match self {
+                    Self::Loop => u32::from_be_bytes(header),
+                    _ => u32::from_le_bytes(header).min(u32::from_be_bytes(header)),
+                };
+                let ethertype = if family == AF_INET {
+                    ETHERTYPE_IPV4
+                } else if AF_INET6.contains(&family) {
+                    ETHERTYPE_IPV6
+                } else {
+                    ETHERTYPE_OTHER
+                };
+                Some((ethertype, payload))
+            }
+        }
+    }
+}
+
+impl fmt::Display for LinkType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Ethernet => "EN10MB",
+            Self::LinuxSll => "LINUX_SLL",
+            Self::LinuxSll2 => "LINUX_SLL2",
+            Self::Raw => "RAW",
+            Self::Ipv4 => "IPV4",
+            Self::Ipv6 => "IPV6",
+            Self::Null => "NULL",
+            Self::Loop => "LOOP",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IPV4: [u8; 4] = [0x45, 0, 0, 20];
+    const IPV6: [u8; 4] = [0x60, 0, 0, 0];
+
+    fn with_header(header: &[u8], payload: &[u8]) -> Vec<u8> {
+        let mut data = header.to_vec();
+        data.extend_from_slice(payload);
+        data
+    }
+
+    #[test]
+    fn maps_pcap_link_types() {
+        assert_eq!(LinkType::from_pcap(Linktype(1)), Ok(LinkType::Ethernet));
+        assert_eq!(LinkType::from_pcap(Linktype(113)), Ok(LinkType::LinuxSll));
+        assert_eq!(LinkType::from_pcap(Linktype(276)), Ok(LinkType::LinuxSll2));
+        for dlt in DLT_RAW {
+            assert_eq!(LinkType::from_pcap(Linktype(dlt)), Ok(LinkType::Raw));
+        }
+        assert_eq!(LinkType::from_pcap(Linktype(0)), Ok(LinkType::Null));
+        assert_eq!(LinkType::from_pcap(Linktype(108)), Ok(LinkType::Loop));
+        // 802.11のradiotap
+        assert!(LinkType::from_pcap(Linktype(127)).is_err());
+    }
+
+    #[test]
+    fn strips_ethernet_and_cooked_headers() {
+        let mut ethernet = vec![0u8; 12];
+        ethernet.extend_from_slice(&0x86ddu16.to_be_bytes());
+        let frame = with_header(&ethernet, &IPV6);
+        assert_eq!(
+            LinkType::Ethernet.strip_header(&frame),
+            Some((ETHERTYPE_IPV6, &IPV6[..]))
+        );
+
+        let mut sll = vec![0u8; 14];
+        sll.extend_from_slice(&0x0800u16.to_be_bytes());
+        let frame = with_header(&sll, &IPV4);
+        assert_eq!(
+            LinkType::LinuxSll.strip_header(&frame),
+            Some((ETHERTYPE_IPV4, &IPV4[..]))
+        );
+
+        // SLL2はプロトコルが先頭にある
+        let mut sll2 = 0x0800u16.to_be_bytes().to_vec();
+        sll2.resize(LINUX_SLL2_LEN, 0xff);
+        let frame = with_header(&sll2, &IPV4);
+        assert_eq!(
+            LinkType::LinuxSll2.strip_header(&frame),
+            Some((ETHERTYPE_IPV4, &IPV4[..]))
+        );
+    }
+
+    #[test]
+    fn short_headers_are_rejected() {
+        assert_eq!(
+            LinkType::Ethernet.strip_header(&[0; ETHERNET_LEN - 1]),
+            None
+        );
+        assert_eq!(
+            LinkType::LinuxSll.strip_header(&[0; LINUX_SLL_LEN - 1]),
+            None
+        );
+        assert_eq!(
+            LinkType::LinuxSll2.strip_header(&[0; LINUX_SLL2_LEN - 1]),
+            None
+        );
+        assert_eq!(LinkType::Null.strip_header(&[2, 0, 0]), None);
+        assert_eq!(LinkType::Raw.strip_header(&[]), None);
+        // ヘッダーだけで中身のないフレーム
+        assert_eq!(
+            LinkType::Ethernet.strip_header(&[0; ETHERNET_LEN]),
+            Some((0, &[][..]))
+        );
+    }
+
+    #[test]
+    fn raw_ip_is_dispatched_on_the_version() {
+        assert_eq!(
+            LinkType::Raw.strip_header(&IPV4),
+            Some((ETHERTYPE_IPV4, &IPV4[..]))
+        );
+        assert_eq!(
+            LinkType::Raw.strip_header(&IPV6),
+            Some((ETHERTYPE_IPV6, &IPV6[..]))
+        );
+        assert_eq!(
+            LinkType::Raw.strip_header(&[0x10]),
+            Some((ETHERTYPE_OTHER, &[0x10][..]))
+        );
+        assert_eq!(
+            LinkType::Ipv6.strip_header(&IPV6),
+            Some((ETHERTYPE_IPV6, &IPV6[..]))
+        );
+    }
+
+    #[test]
+    fn null_accepts_either_byte_order() {
+        for header in [[2, 0, 0, 0], [0, 0, 0, 2]] {
+            let frame = with_header(&header, &IPV4);
+            assert_eq!(
+                LinkType::Null.strip_header(&frame),
+                Some((ETHERTYPE_IPV4, &IPV4[..]))
+            );
+        }
+        for family in AF_INET6 {
+            for header in [family.to_le_bytes(), family.to_be_bytes()] {
+                let frame = with_header(&header, &IPV6);
+                assert_eq!(
+                    LinkType::Null.strip_header(&frame),
+                    Some((ETHERTYPE_IPV6, &IPV6[..]))
+                );
+            }
+        }
+        let frame = with_header(&17u32.to_le_bytes(), &IPV4);
+        assert_eq!(
+            LinkType::Null.strip_header(&frame),
+            Some((ETHERTYPE_OTHER, &IPV4[..]))
+        );
+    }
+
+    #[test]
+    fn loop_is_always_network_byte_order() {
+        let frame = with_header(&24u32.to_be_bytes(), &IPV6);
+        assert_eq!(
+            LinkType::Loop.strip_header(&frame),
+            Some((ETHERTYPE_IPV6, &IPV6[..]))
+        );
+        let frame = with_header(&2u32.to_le_bytes(), &IPV4);
+        assert_eq!(
+            LinkType::Loop.strip_header(&frame),
+            Some((ETHERTYPE_OTHER, &IPV4[..]))
+        );
+    }
+}
@@ -1,35 +1,92 @@
+use accounting::{l4_payload_len, Accounting, PacketLengths};
+use bpf::CaptureFilter;
+use clap::Parser;
+use config::{CaptureBackend, CaptureSource, Cli, Config, InterfaceConfig, StatsLimits};
+use decap::Decapsulation;
+use fragment::{DatagramKey, Fragment, Reassembler, Reassembly};
+use health::{CaptureHealth, Malformed, Untracked};
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Response, Server};
+use hyper::{Body, Method, Response, Server, StatusCode};
+use link::LinkType;
+use metrics::PrometheusMetrics;
 use pcap::{Capture, Device};
 use pnet::datalink;
-use pnet::ipnetwork::IpNetwork;
-use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
-use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::ethernet::{EtherType, EtherTypes};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::{Ipv4Flags, Ipv4Packet};
 use pnet::packet::ipv6::Ipv6Packet;
-use pnet::packet::tcp::{TcpFlags, TcpPacket};
+use pnet::packet::tcp::TcpPacket;
 use pnet::packet::Packet;
-use prometheus::{Counter, Encoder, Gauge, Registry, TextEncoder};
+use prometheus::{Encoder, TextEncoder};
+use protocol::Protocol;
+use reload::{Reloader, RuntimeSettings, SharedSettings};
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
-use std::env;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use service::{Ports, RemotePort, ServiceId, ServiceMap};
+use snapshot::{Delta, DeltaSlot};
+use std::cmp::Reverse;
+use std::collections::hash_map::Entry;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
 use std::process;
 use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use targets::TargetNetworks;
+use tcp_flow::{AckKind, FlowKey, FlowState, SegmentKind, TcpObservation};
 use tokio::runtime::Runtime;
+use traffic::TrafficStats;
+use vlan::VlanTags;
 
+// 受け取った停止シグナル（SIGINT/SIGTERM）の回数。1回目で停止処理を始め、2回目で即座に終了する
 static SIGINT_COUNT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
 
+mod accounting;
+#[cfg(target_os = "linux")]
+mod af_packet;
+#[cfg(feature = "bench")]
+mod bench;
+mod bpf;
+mod config;
+mod decap;
+mod fragment;
+mod health;
+mod ipv6;
+mod link;
+mod metrics;
+mod protocol;
+mod reload;
+mod service;
+mod snapshot;
+mod summary;
+mod targets;
+mod tcp_flow;
+mod traffic;
+mod vlan;
+
 mod version {
     pub const VERSION: &str = "2.0.0";
 }
 
-// 固定値設定: Noneの場合は自動検出、Some((ip, prefix))の場合は固定値を使用
-// 例: Some((Ipv4Addr::new(192, 168, 1, 1), 24))
-const FIXED_INTERFACE_CONFIG: Option<(Ipv4Addr, u8)> = Some((Ipv4Addr::new(10, 40, 0, 1), 20));
+// libpcapのバッファからパケットが届くまでの遅延を見込んだ猶予
+// パケットが来ない間はこの分だけ遅らせた壁時計でバケットを閉じる
+const CAPTURE_DELIVERY_GRACE: Duration = Duration::from_millis(500);
+
+// RTTの集計対象にする直近の期間と、IPごとに保持するサンプル数の上限
+const RTT_WINDOW: Duration = Duration::from_secs(10);
+const MAX_RTT_SAMPLES: usize = 512;
+
+// キャプチャが止まったときの再オープンの待ち時間（失敗するたびに倍にする）
+const REOPEN_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const REOPEN_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+// すべてのインターフェースをまとめてキャプチャするlibpcapの疑似デバイス
+const ANY_DEVICE: &str = "any";
+
+// IPv6の固定ヘッダーの長さ（Payload Lengthに含まれない）
+const IPV6_HEADER_LEN: u64 = 40;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct StatusConfig {
@@ -59,10 +116,10 @@ impl WanAssignments {
         }
     }
 
-    async fn fetch_from_api() -> Result<Self, Box<dyn std::error::Error>> {
+    async fn fetch_from_api(url: &str) -> Result<Self, Box<dyn std::error::Error>> {
         let client = reqwest::Client::new();
         let response = client
-            .get("http://localhost:32599/status")
+            .get(url)
             .timeout(Duration::from_secs(5))
             .send()
             .await?;
@@ -95,758 +152,1889 @@ impl WanAssignments {
     }
 }
 
-struct PrometheusMetrics {
-    registry: Registry,
-    tx_bytes_total: Counter,
-    rx_bytes_total: Counter,
-    tx_bytes_per_sec: Gauge,
-    rx_bytes_per_sec: Gauge,
-    tx_bps: Gauge,
-    rx_bps: Gauge,
-    retransmissions_per_sec: Gauge,
-    duplicate_acks_per_sec: Gauge,
-    window_size_changes_per_sec: Gauge,
-    // 各IPごとのメトリクス
-    ip_tx_bytes_total: prometheus::CounterVec,
-    ip_rx_bytes_total: prometheus::CounterVec,
-    ip_tx_bytes_per_sec: prometheus::GaugeVec,
-    ip_rx_bytes_per_sec: prometheus::GaugeVec,
-    ip_tx_bps: prometheus::GaugeVec,
-    ip_rx_bps: prometheus::GaugeVec,
-    ip_retransmissions_per_sec: prometheus::GaugeVec,
-    ip_duplicate_acks_per_sec: prometheus::GaugeVec,
-    ip_window_size_changes_per_sec: prometheus::GaugeVec,
-    // NIC別の合計メトリクス
-    nic_tx_bps_total: prometheus::GaugeVec,
-    nic_rx_bps_total: prometheus::GaugeVec,
-    nic_tx_bytes_per_sec_total: prometheus::GaugeVec,
-    nic_rx_bytes_per_sec_total: prometheus::GaugeVec,
-}
-
-impl PrometheusMetrics {
+// 統計のキー。VLANが違えば同じアドレスでも別のホストとして数える
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct HostKey {
+    ip: IpAddr,
+    vlan: VlanTags,
+}
+
+impl HostKey {
+    fn is_target(&self, targets: &TargetNetworks) -> bool {
+        targets.contains(&self.ip) && targets.matches_vlan(&self.vlan)
+    }
+}
+
+struct IpStats {
+    tx_packet_count: u64,          // 送信パケット数
+    rx_packet_count: u64,          // 受信パケット数
+    tx_byte_count: u64,            // 送信バイト数
+    rx_byte_count: u64,            // 受信バイト数
+    tx_tunnel_overhead_bytes: u64, // 送信バイト数のうちトンネルのヘッダー分
+    rx_tunnel_overhead_bytes: u64, // 受信バイト数のうちトンネルのヘッダー分
+    fragments: u64,                // 送受信したIPフラグメントの数
+    reassembly_failures: u64,      // 揃わなかった（または上限を超えた）分割データグラムの数
+    tx_last_bytes: u64,
+    rx_last_bytes: u64,
+    last_time: Duration,   // 前回レート計算時刻（UNIXエポックからの経過時間）
+    last_seen: Duration,   // 最後にパケットを見た時刻（しばらく見なければ捨てる）
+    changed: bool,         // 前回の公開から値が変わったか（HostTable::changedに入っているか）
+    idle_check: Duration,  // 次に無通信を確かめる時刻（HostTable::idle_checksの予定）
+    tx_current_bps: f64,   // 送信ビット/秒
+    rx_current_bps: f64,   // 受信ビット/秒
+    tx_bytes_per_sec: u64, // 1秒間の送信バイト数
+    rx_bytes_per_sec: u64, // 1秒間の受信バイト数
+    tx_last_packets: u64,
+    rx_last_packets: u64,
+    tx_packets_per_sec: u64, // 1秒間の送信パケット数
+    rx_packets_per_sec: u64, // 1秒間の受信パケット数
+
+    // L4プロトコル別の内訳（Protocol::ALLの順）
+    protocols: [TrafficStats; Protocol::ALL.len()],
+    // TCP/UDPの相手側のポート別とサービス別の内訳
+    // スナップショットとは共有し、書き込むときにコピーする（Arc::make_mut）
+    ports: Arc<HashMap<RemotePort, TrafficStats>>,
+    services: Arc<HashMap<ServiceId, TrafficStats>>,
+
+    // TCPフロー状態（4タプル別）
+    flows: HashMap<FlowKey, FlowState>,
+
+    // パケットロス関連
+    retransmissions: u64,                  // 再送パケット数
+    spurious_retransmissions: u64,         // ACK済みデータの再送数
+    out_of_order: u64,                     // 順序入れ替わりセグメント数
+    duplicate_acks: u64,                   // 重複ACK数
+    triple_duplicate_acks: u64,            // 3回連続の重複ACK（高速再送のきっかけ）数
+    last_retransmissions: u64,             // 前回の再送パケット数
+    last_spurious_retransmissions: u64,    // 前回のACK済みデータの再送数
+    last_out_of_order: u64,                // 前回の順序入れ替わりセグメント数
+    last_duplicate_acks: u64,              // 前回の重複ACK数
+    last_triple_duplicate_acks: u64,       // 前回の3回連続の重複ACK数
+    retransmissions_per_sec: u64,          // 1秒間の再送パケット数
+    spurious_retransmissions_per_sec: u64, // 1秒間のACK済みデータの再送数
+    out_of_order_per_sec: u64,             // 1秒間の順序入れ替わりセグメント数
+    duplicate_acks_per_sec: u64,           // 1秒間の重複ACK数
+    triple_duplicate_acks_per_sec: u64,    // 1秒間の3回連続の重複ACK数
+
+    // TCPウィンドウサイズ関連
+    window_size_changes: u64,         // ウィンドウサイズ変更回数
+    last_window_size_changes: u64,    // 前回のウィンドウサイズ変更回数
+    window_size_changes_per_sec: u64, // 1秒間のウィンドウサイズ変更回数
+
+    // RTT関連（秒）
+    rtt_samples: VecDeque<(Duration, f64)>, // 直近のRTTサンプル（計測時刻, RTT）
+    rtt: Option<RttSummary>,                // 直近のRTTサンプルの集計
+}
+
+// RTTサンプルの集計値（秒）
+#[derive(Debug, Clone, Copy)]
+struct RttSummary {
+    min: f64,
+    mean: f64,
+    p95: f64,
+}
+
+impl RttSummary {
+    fn from_samples(samples: &[f64]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+        let mut sorted = samples.to_vec();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        let p95_index = ((sorted.len() as f64 * 0.95).ceil() as usize).saturating_sub(1);
+        Some(Self {
+            min: sorted[0],
+            mean: sorted.iter().sum::<f64>() / sorted.len() as f64,
+            p95: sorted[p95_index],
+        })
+    }
+}
+
+impl IpStats {
+    fn new(now: Duration) -> Self {
+        Self {
+            tx_packet_count: 0,
+            rx_packet_count: 0,
+            tx_byte_count: 0,
+            rx_byte_count: 0,
+            tx_tunnel_overhead_bytes: 0,
+            rx_tunnel_overhead_bytes: 0,
+            fragments: 0,
+            reassembly_failures: 0,
+            tx_last_bytes: 0,
+            rx_last_bytes: 0,
+            // 秒の境界に揃えて、最初のバケットも1秒単位で計算されるようにする
+            last_time: Duration::from_secs(now.as_secs()),
+            last_seen: now,
+            changed: false,
+            idle_check: now,
+            tx_current_bps: 0.0,
+            rx_current_bps: 0.0,
+            tx_bytes_per_sec: 0,
+            rx_bytes_per_sec: 0,
+            tx_last_packets: 0,
+            rx_last_packets: 0,
+            tx_packets_per_sec: 0,
+            rx_packets_per_sec: 0,
+            protocols: [TrafficStats::default(); Protocol::ALL.len()],
+            ports: Arc::default(),
+            services: Arc::default(),
+            flows: HashMap::new(),
+            retransmissions: 0,
+            spurious_retransmissions: 0,
+            out_of_order: 0,
+            duplicate_acks: 0,
+            triple_duplicate_acks: 0,
+            last_retransmissions: 0,
+            last_spurious_retransmissions: 0,
+            last_out_of_order: 0,
+            last_duplicate_acks: 0,
+            last_triple_duplicate_acks: 0,
+            retransmissions_per_sec: 0,
+            spurious_retransmissions_per_sec: 0,
+            out_of_order_per_sec: 0,
+            duplicate_acks_per_sec: 0,
+            triple_duplicate_acks_per_sec: 0,
+            window_size_changes: 0,
+            last_window_size_changes: 0,
+            window_size_changes_per_sec: 0,
+            rtt_samples: VecDeque::new(),
+            rtt: None,
+        }
+    }
+
+    // ワーカーごとの統計を足し合わせる（フロー状態は引き継がない）
+    // 最後にcalculate_rttでRTTを集計し直すこと
+    fn merge(&mut self, other: &IpStats) {
+        self.merge_counters(other);
+        let ports = Arc::make_mut(&mut self.ports);
+        for (port, other) in other.ports.iter() {
+            ports.entry(*port).or_default().merge(other);
+        }
+        let services = Arc::make_mut(&mut self.services);
+        for (service, other) in other.services.iter() {
+            services.entry(*service).or_default().merge(other);
+        }
+    }
+
+    // ポート別とサービス別の内訳以外を足し合わせる
+    fn merge_counters(&mut self, other: &IpStats) {
+        self.tx_packet_count += other.tx_packet_count;
+        self.rx_packet_count += other.rx_packet_count;
+        self.tx_byte_count += other.tx_byte_count;
+        self.rx_byte_count += other.rx_byte_count;
+        self.tx_tunnel_overhead_bytes += other.tx_tunnel_overhead_bytes;
+        self.rx_tunnel_overhead_bytes += other.rx_tunnel_overhead_bytes;
+        self.fragments += other.fragments;
+        self.reassembly_failures += other.reassembly_failures;
+        self.tx_last_bytes += other.tx_last_bytes;
+        self.rx_last_bytes += other.rx_last_bytes;
+        self.last_time = self.last_time.max(other.last_time);
+        self.last_seen = self.last_seen.max(other.last_seen);
+        self.tx_current_bps += other.tx_current_bps;
+        self.rx_current_bps += other.rx_current_bps;
+        self.tx_bytes_per_sec += other.tx_bytes_per_sec;
+        self.rx_bytes_per_sec += other.rx_bytes_per_sec;
+        self.tx_last_packets += other.tx_last_packets;
+        self.rx_last_packets += other.rx_last_packets;
+        self.tx_packets_per_sec += other.tx_packets_per_sec;
+        self.rx_packets_per_sec += other.rx_packets_per_sec;
+        for (protocol, other) in self.protocols.iter_mut().zip(&other.protocols) {
+            protocol.merge(other);
+        }
+        self.retransmissions += other.retransmissions;
+        self.spurious_retransmissions += other.spurious_retransmissions;
+        self.out_of_order += other.out_of_order;
+        self.duplicate_acks += other.duplicate_acks;
+        self.triple_duplicate_acks += other.triple_duplicate_acks;
+        self.retransmissions_per_sec += other.retransmissions_per_sec;
+        self.spurious_retransmissions_per_sec += other.spurious_retransmissions_per_sec;
+        self.out_of_order_per_sec += other.out_of_order_per_sec;
+        self.duplicate_acks_per_sec += other.duplicate_acks_per_sec;
+        self.triple_duplicate_acks_per_sec += other.triple_duplicate_acks_per_sec;
+        self.window_size_changes += other.window_size_changes;
+        self.window_size_changes_per_sec += other.window_size_changes_per_sec;
+        self.rtt_samples.extend(other.rtt_samples.iter().copied());
+    }
+
+    // フロー状態を除いたコピー（統計スレッドに渡すスナップショット用）
+    // ポート別とサービス別の内訳はコピーせずに共有する
+    fn snapshot(&self) -> Self {
+        let mut copy = Self::new(self.last_time);
+        copy.merge_counters(self);
+        copy.ports = Arc::clone(&self.ports);
+        copy.services = Arc::clone(&self.services);
+        copy.rtt = self.rtt;
+        copy
+    }
+
+    // 前回のレート計算でトラフィックがあったか
+    fn has_rates(&self) -> bool {
+        self.tx_current_bps > 0.0
+            || self.rx_current_bps > 0.0
+            || self.tx_packets_per_sec > 0
+            || self.rx_packets_per_sec > 0
+    }
+
+    fn calculate_rtt(&mut self) {
+        let samples: Vec<f64> = self.rtt_samples.iter().map(|&(_, rtt)| rtt).collect();
+        self.rtt = RttSummary::from_samples(&samples);
+    }
+
+    // 相手側のポートの内訳。上限に達していれば、新しいポートはプロトコルごとに1つにまとめて数える
+    fn port_traffic(&mut self, remote: RemotePort, max_ports: usize) -> &mut TrafficStats {
+        let remote = if self.ports.len() >= max_ports && !self.ports.contains_key(&remote) {
+            RemotePort {
+                port: None,
+                ..remote
+            }
+        } else {
+            remote
+        };
+        Arc::make_mut(&mut self.ports).entry(remote).or_default()
+    }
+
+    fn record_tcp_observation(&mut self, observation: &TcpObservation, now: Duration) {
+        match observation.segment {
+            Some(SegmentKind::Retransmission) => self.retransmissions += 1,
+            Some(SegmentKind::SpuriousRetransmission) => self.spurious_retransmissions += 1,
+            Some(SegmentKind::OutOfOrder) => self.out_of_order += 1,
+            Some(SegmentKind::New) | None => {}
+        }
+        match observation.ack {
+            Some(AckKind::Duplicate) => self.duplicate_acks += 1,
+            Some(AckKind::TripleDuplicate) => {
+                self.duplicate_acks += 1;
+                self.triple_duplicate_acks += 1;
+            }
+            None => {}
+        }
+        if observation.window_changed {
+            self.window_size_changes += 1;
+        }
+        if let Some(rtt) = observation.rtt_sample {
+            if self.rtt_samples.len() >= MAX_RTT_SAMPLES {
+                self.rtt_samples.pop_front();
+            }
+            self.rtt_samples.push_back((now, rtt.as_secs_f64()));
+        }
+    }
+}
+
+// パケットのタイムスタンプを基準に1秒単位のバケットを管理する
+struct PacketClock {
+    bucket_end: Option<Duration>,
+}
+
+impl PacketClock {
+    // 長時間パケットが途切れた場合に、空のバケットを大量に処理しないための上限
+    const MAX_EMPTY_BUCKETS: u64 = 60;
+
     fn new() -> Self {
-        let registry = Registry::new();
-
-        // 全体のメトリクス - パケットロスは累積値として扱う
-        let tx_bytes_total =
-            Counter::new("network_tx_bytes_total", "Total transmitted bytes").unwrap();
-        let rx_bytes_total =
-            Counter::new("network_rx_bytes_total", "Total received bytes").unwrap();
-        let tx_bytes_per_sec =
-            Gauge::new("network_tx_bytes_per_sec", "Transmitted bytes per second").unwrap();
-        let rx_bytes_per_sec =
-            Gauge::new("network_rx_bytes_per_sec", "Received bytes per second").unwrap();
-        let tx_bps = Gauge::new("network_tx_bps", "Transmitted bits per second").unwrap();
-        let rx_bps = Gauge::new("network_rx_bps", "Received bits per second").unwrap();
-        let retransmissions_per_sec = Gauge::new(
-            "network_retransmissions_per_sec",
-            "Retransmissions per second",
-        )
-        .unwrap();
-        let duplicate_acks_per_sec = Gauge::new(
-            "network_duplicate_acks_per_sec",
-            "Duplicate ACKs per second",
-        )
-        .unwrap();
-        let window_size_changes_per_sec = Gauge::new(
-            "network_window_size_changes_per_sec",
-            "Window size changes per second",
-        )
-        .unwrap();
+        Self { bucket_end: None }
+    }
 
-        // IPごとのメトリクス
-        let ip_tx_bytes_total = prometheus::CounterVec::new(
-            prometheus::Opts::new(
-                "network_ip_tx_bytes_total",
-                "Total transmitted bytes per IP",
-            ),
-            &["ip_address"],
-        )
-        .unwrap();
-        let ip_rx_bytes_total = prometheus::CounterVec::new(
-            prometheus::Opts::new("network_ip_rx_bytes_total", "Total received bytes per IP"),
-            &["ip_address"],
-        )
-        .unwrap();
-        let ip_tx_bytes_per_sec = prometheus::GaugeVec::new(
-            prometheus::Opts::new(
-                "network_ip_tx_bytes_per_sec",
-                "Transmitted bytes per second per IP",
-            ),
-            &["ip_address"],
-        )
-        .unwrap();
-        let ip_rx_bytes_per_sec = prometheus::GaugeVec::new(
-            prometheus::Opts::new(
-                "network_ip_rx_bytes_per_sec",
-                "Received bytes per second per IP",
-            ),
-            &["ip_address"],
-        )
-        .unwrap();
-        let ip_tx_bps = prometheus::GaugeVec::new(
-            prometheus::Opts::new("network_ip_tx_bps", "Transmitted bits per second per IP"),
-            &["ip_address"],
-        )
-        .unwrap();
-        let ip_rx_bps = prometheus::GaugeVec::new(
-            prometheus::Opts::new("network_ip_rx_bps", "Received bits per second per IP"),
-            &["ip_address"],
-        )
-        .unwrap();
-        // パケットロス関連は1秒間の値をGaugeで表示
-        let ip_retransmissions_per_sec = prometheus::GaugeVec::new(
-            prometheus::Opts::new(
-                "network_ip_retransmissions_per_sec",
-                "Retransmissions per second per IP",
-            ),
-            &["ip_address"],
-        )
-        .unwrap();
-        let ip_duplicate_acks_per_sec = prometheus::GaugeVec::new(
-            prometheus::Opts::new(
-                "network_ip_duplicate_acks_per_sec",
-                "Duplicate ACKs per second per IP",
-            ),
-            &["ip_address"],
-        )
-        .unwrap();
-        let ip_window_size_changes_per_sec = prometheus::GaugeVec::new(
-            prometheus::Opts::new(
-                "network_ip_window_size_changes_per_sec",
-                "Window size changes per second per IP",
-            ),
-            &["ip_address"],
-        )
-        .unwrap();
+    // tsまでに閉じたバケットの境界時刻を古い順に返す
+    fn advance(&mut self, ts: Duration) -> Vec<Duration> {
+        let bucket_end = match self.bucket_end {
+            Some(end) => end,
+            None => {
+                self.bucket_end = Some(Duration::from_secs(ts.as_secs() + 1));
+                return Vec::new();
+            }
+        };
+        if ts < bucket_end {
+            return Vec::new();
+        }
 
-        // 累積値のパケットロスメトリクスも追加
-        let ip_retransmissions_total = prometheus::CounterVec::new(
-            prometheus::Opts::new(
-                "network_ip_retransmissions_total",
-                "Total retransmissions per IP",
-            ),
-            &["ip_address"],
-        )
-        .unwrap();
-        let ip_duplicate_acks_total = prometheus::CounterVec::new(
-            prometheus::Opts::new(
-                "network_ip_duplicate_acks_total",
-                "Total duplicate ACKs per IP",
-            ),
-            &["ip_address"],
-        )
-        .unwrap();
-        let ip_window_size_changes_total = prometheus::CounterVec::new(
-            prometheus::Opts::new(
-                "network_ip_window_size_changes_total",
-                "Total window size changes per IP",
-            ),
-            &["ip_address"],
-        )
-        .unwrap();
+        let next_end = Duration::from_secs(ts.as_secs() + 1);
+        let closed = next_end.as_secs() - bucket_end.as_secs();
+        let mut boundaries = Vec::new();
+        if closed > Self::MAX_EMPTY_BUCKETS {
+            // 最初のバケットと直前のバケットだけを閉じる
+            boundaries.push(bucket_end);
+            boundaries.push(next_end - Duration::from_secs(1));
+        } else {
+            for i in 0..closed {
+                boundaries.push(bucket_end + Duration::from_secs(i));
+            }
+        }
+        self.bucket_end = Some(next_end);
+        boundaries
+    }
 
-        // NIC別の合計メトリクス（要件に合わせて名前を修正）
-        let nic_tx_bps_total = prometheus::GaugeVec::new(
-            prometheus::Opts::new(
-                "network_ip_tx_bps_total",
-                "Total transmitted bits per second by NIC",
-            ),
-            &["nic"],
-        )
-        .unwrap();
-        let nic_rx_bps_total = prometheus::GaugeVec::new(
-            prometheus::Opts::new(
-                "network_ip_rx_bps_total",
-                "Total received bits per second by NIC",
-            ),
-            &["nic"],
-        )
-        .unwrap();
-        let nic_tx_bytes_per_sec_total = prometheus::GaugeVec::new(
-            prometheus::Opts::new(
-                "network_ip_tx_bytes_per_sec_total",
-                "Total transmitted bytes per second by NIC",
-            ),
-            &["nic"],
-        )
-        .unwrap();
-        let nic_rx_bytes_per_sec_total = prometheus::GaugeVec::new(
-            prometheus::Opts::new(
-                "network_ip_rx_bytes_per_sec_total",
-                "Total received bytes per second by NIC",
-            ),
-            &["nic"],
-        )
-        .unwrap();
+    // 現在のバケットを強制的に閉じる（リプレイ終了時など）
+    fn finish(&mut self) -> Option<Duration> {
+        self.bucket_end.take()
+    }
+}
 
-        // メトリクス登録
-        registry.register(Box::new(tx_bytes_total.clone())).unwrap();
-        registry.register(Box::new(rx_bytes_total.clone())).unwrap();
-        registry
-            .register(Box::new(tx_bytes_per_sec.clone()))
-            .unwrap();
-        registry
-            .register(Box::new(rx_bytes_per_sec.clone()))
-            .unwrap();
-        registry.register(Box::new(tx_bps.clone())).unwrap();
-        registry.register(Box::new(rx_bps.clone())).unwrap();
-        registry
-            .register(Box::new(retransmissions_per_sec.clone()))
-            .unwrap();
-        registry
-            .register(Box::new(duplicate_acks_per_sec.clone()))
-            .unwrap();
-        registry
-            .register(Box::new(window_size_changes_per_sec.clone()))
-            .unwrap();
-
-        registry
-            .register(Box::new(ip_tx_bytes_total.clone()))
-            .unwrap();
-        registry
-            .register(Box::new(ip_rx_bytes_total.clone()))
-            .unwrap();
-        registry
-            .register(Box::new(ip_tx_bytes_per_sec.clone()))
-            .unwrap();
-        registry
-            .register(Box::new(ip_rx_bytes_per_sec.clone()))
-            .unwrap();
-        registry.register(Box::new(ip_tx_bps.clone())).unwrap();
-        registry.register(Box::new(ip_rx_bps.clone())).unwrap();
-        registry
-            .register(Box::new(ip_retransmissions_per_sec.clone()))
-            .unwrap();
-        registry
-            .register(Box::new(ip_duplicate_acks_per_sec.clone()))
-            .unwrap();
-        registry
-            .register(Box::new(ip_window_size_changes_per_sec.clone()))
-            .unwrap();
-        registry
-            .register(Box::new(ip_retransmissions_total.clone()))
-            .unwrap();
-        registry
-            .register(Box::new(ip_duplicate_acks_total.clone()))
-            .unwrap();
-        registry
-            .register(Box::new(ip_window_size_changes_total.clone()))
-            .unwrap();
-        registry
-            .register(Box::new(nic_tx_bps_total.clone()))
-            .unwrap();
-        registry
-            .register(Box::new(nic_rx_bps_total.clone()))
-            .unwrap();
-        registry
-            .register(Box::new(nic_tx_bytes_per_sec_total.clone()))
-            .unwrap();
-        registry
-            .register(Box::new(nic_rx_bytes_per_sec_total.clone()))
-            .unwrap();
+fn packet_timestamp(header: &pcap::PacketHeader) -> Duration {
+    Duration::from_secs(header.ts.tv_sec as u64) + Duration::from_micros(header.ts.tv_usec as u64)
+}
+
+// パケット時刻で1秒のバケットを越えたら、閉じたバケットごとにレートを計算する
+// 再構築が時間切れになったデータグラムもここで失敗として数える
+// バケットを閉じた場合はtrueを返す
+fn advance_clock(clock: &mut PacketClock, table: &mut HostTable, ts: Duration) -> bool {
+    let boundaries = clock.advance(ts);
+    if boundaries.is_empty() {
+        return false;
+    }
+    let failed = table.fragments.expire(ts);
+    table.count_reassembly_failures(&failed);
+    table.evict_idle(ts);
+    table.calculate_rates(&boundaries);
+    true
+}
 
+// 1回のバケットの境界で無通信を確かめるホストの数の上限（残りは次のバケットに回す）
+const IDLE_CHECKS_PER_BUCKET: usize = 4096;
+
+// 1ワーカー分のホストごとの統計とTCPフロー状態
+// 上限に達している間は新しいホストやフローを追跡せず、しばらくパケットのないものは捨てる
+// バケットを閉じるときの処理は変わったホストと予定の来たホストだけに限り、ホストの数に比例させない
+struct HostTable {
+    hosts: HashMap<HostKey, IpStats>,
+    changed: Vec<HostKey>, // 前回の公開から値が変わったホスト（IpStats::changedが立っているもの）
+    settling: Vec<HostKey>, // 前回の計算でレートかRTTが残っていたホスト（0に戻るまで計算し続ける）
+    evicted: Vec<HostKey>, // 前回の公開から捨てたホスト
+    idle_checks: BinaryHeap<Reverse<(Duration, HostKey)>>, // 無通信を確かめる予定（早い順）
+    fragments: Reassembler,
+    flow_count: usize, // 全ホストのフロー数の合計
+    retired: RetiredTraffic,
+    limits: StatsLimits,
+    accounting: Accounting,
+    services: Arc<ServiceMap>,
+    health: Arc<CaptureHealth>,
+}
+
+impl HostTable {
+    fn new(
+        limits: StatsLimits,
+        accounting: Accounting,
+        services: Arc<ServiceMap>,
+        health: Arc<CaptureHealth>,
+    ) -> Self {
         Self {
-            registry,
-            tx_bytes_total,
-            rx_bytes_total,
-            tx_bytes_per_sec,
-            rx_bytes_per_sec,
-            tx_bps,
-            rx_bps,
-            retransmissions_per_sec,
-            duplicate_acks_per_sec,
-            window_size_changes_per_sec,
-            ip_tx_bytes_total,
-            ip_rx_bytes_total,
-            ip_tx_bytes_per_sec,
-            ip_rx_bytes_per_sec,
-            ip_tx_bps,
-            ip_rx_bps,
-            ip_retransmissions_per_sec,
-            ip_duplicate_acks_per_sec,
-            ip_window_size_changes_per_sec,
-            nic_tx_bps_total,
-            nic_rx_bps_total,
-            nic_tx_bytes_per_sec_total,
-            nic_rx_bytes_per_sec_total,
-        }
-    }
-
-    fn update_metrics(
-        &self,
-        stats: &HashMap<IpAddr, IpStats>,
-        target_ips: &HashSet<IpAddr>,
-        wan_assignments: &WanAssignments,
+            hosts: HashMap::new(),
+            changed: Vec::new(),
+            settling: Vec::new(),
+            evicted: Vec::new(),
+            idle_checks: BinaryHeap::new(),
+            fragments: Reassembler::default(),
+            flow_count: 0,
+            retired: RetiredTraffic::default(),
+            limits,
+            accounting,
+            services,
+            health,
+        }
+    }
+
+    // パケットを数えるホストの統計。新しいホストは上限に達していれば追跡しない
+    fn host_mut(&mut self, key: HostKey, now: Duration) -> Option<&mut IpStats> {
+        let full = self.hosts.len() >= self.limits.max_hosts;
+        let stat = match self.hosts.entry(key) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(_) if full => {
+                self.health.count_untracked(Untracked::Host);
+                return None;
+            }
+            Entry::Vacant(entry) => {
+                let stat = entry.insert(IpStats::new(now));
+                let timeout = self.limits.host_idle_timeout;
+                stat.idle_check = now.saturating_add(timeout.min(self.limits.flow_idle_timeout));
+                self.idle_checks.push(Reverse((stat.idle_check, key)));
+                stat
+            }
+        };
+        stat.last_seen = now;
+        if !stat.changed {
+            // レートのないホストは計算から外れていたので、このバケットの始まりから数え直す
+            if !stat.has_rates() {
+                stat.last_time = stat.last_time.max(Duration::from_secs(now.as_secs()));
+            }
+            stat.changed = true;
+            self.changed.push(key);
+        }
+        Some(stat)
+    }
+
+    fn count_reassembly_failures(&mut self, hosts: &[HostKey]) {
+        for host in hosts {
+            if let Some(stat) = self.hosts.get_mut(host) {
+                stat.reassembly_failures += 1;
+                if !stat.changed {
+                    stat.changed = true;
+                    self.changed.push(*host);
+                }
+            }
+        }
+    }
+
+    // 追跡中のホストのTCPフロー状態を更新し、結果を記録する
+    // 新しいフローは上限に達していれば追跡しない
+    fn observe_flow(
+        &mut self,
+        key: HostKey,
+        flow: FlowKey,
+        now: Duration,
+        observe: impl FnOnce(&mut FlowState) -> TcpObservation,
     ) {
-        let mut total_tx_bytes = 0u64;
-        let mut total_rx_bytes = 0u64;
-        let mut total_tx_bytes_per_sec = 0u64;
-        let mut total_rx_bytes_per_sec = 0u64;
-        let mut total_tx_bps = 0f64;
-        let mut total_rx_bps = 0f64;
-        let mut total_retransmissions_per_sec = 0u64;
-        let mut total_duplicate_acks_per_sec = 0u64;
-        let mut total_window_size_changes_per_sec = 0u64;
-
-        // NIC別の合計値を計算するためのマップ
-        let mut nic_stats: HashMap<String, (f64, f64, u64, u64)> = HashMap::new(); // (tx_bps, rx_bps, tx_bytes_per_sec, rx_bytes_per_sec)
-
-        // target_ipsに含まれるIPアドレスのみを処理
-        for (ip, stat) in stats {
-            // target_ipsに含まれないIPは無視
-            if !target_ips.contains(ip) {
+        let Some(stat) = self.hosts.get_mut(&key) else {
+            return;
+        };
+        let full = self.flow_count >= self.limits.max_flows;
+        let state = match stat.flows.entry(flow) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(_) if full => {
+                self.health.count_untracked(Untracked::Flow);
+                return;
+            }
+            Entry::Vacant(entry) => {
+                self.flow_count += 1;
+                entry.insert(FlowState::default())
+            }
+        };
+        let observation = observe(state);
+        stat.record_tcp_observation(&observation, now);
+    }
+
+    // 予定の来たホストを調べ、しばらくパケットのないホストとフローを捨てる
+    // 残ったホストは、次にホストかフローが時間切れになりうる時刻に調べ直す
+    fn evict_idle(&mut self, now: Duration) {
+        let limits = self.limits;
+        for _ in 0..IDLE_CHECKS_PER_BUCKET {
+            let Some(&Reverse((due, key))) = self.idle_checks.peek() else {
+                break;
+            };
+            if due > now {
+                break;
+            }
+            self.idle_checks.pop();
+            let Entry::Occupied(mut entry) = self.hosts.entry(key) else {
+                continue;
+            };
+            // 捨てたあとにまた現れたホストには、別の予定が入っている
+            if entry.get().idle_check != due {
                 continue;
             }
+            if now.saturating_sub(entry.get().last_seen) >= limits.host_idle_timeout {
+                let stat = entry.remove();
+                self.retired.tx_bytes += stat.tx_byte_count;
+                self.retired.rx_bytes += stat.rx_byte_count;
+                self.retired.tx_packets += stat.tx_packet_count;
+                self.retired.rx_packets += stat.rx_packet_count;
+                self.flow_count -= stat.flows.len();
+                self.evicted.push(key);
+                continue;
+            }
+            let stat = entry.get_mut();
+            let flows = stat.flows.len();
+            let mut oldest_flow = now;
+            stat.flows.retain(|_, flow| {
+                let keep = now.saturating_sub(flow.last_seen()) < limits.flow_idle_timeout;
+                if keep {
+                    oldest_flow = oldest_flow.min(flow.last_seen());
+                }
+                keep
+            });
+            self.flow_count -= flows - stat.flows.len();
+            stat.idle_check = stat
+                .last_seen
+                .saturating_add(limits.host_idle_timeout)
+                .min(oldest_flow.saturating_add(limits.flow_idle_timeout));
+            self.idle_checks.push(Reverse((stat.idle_check, key)));
+        }
+    }
+
+    // 変わったホストと、前回レートかRTTが残っていたホストだけ計算し直す
+    // ほかのホストはレートが0のまま変わらないので触れない
+    fn calculate_rates(&mut self, boundaries: &[Duration]) {
+        for key in std::mem::take(&mut self.settling) {
+            if let Some(stat) = self.hosts.get_mut(&key) {
+                if !stat.changed {
+                    stat.changed = true;
+                    self.changed.push(key);
+                }
+            }
+        }
+        for key in &self.changed {
+            let Some(stat) = self.hosts.get_mut(key) else {
+                continue;
+            };
+            for boundary in boundaries {
+                calculate_bps(stat, *boundary);
+            }
+            if stat.has_rates() || !stat.rtt_samples.is_empty() {
+                self.settling.push(*key);
+            }
+        }
+    }
+
+    // 前回の公開から変わったホストと捨てたホストだけを渡す
+    fn take_delta(&mut self) -> StatsDelta {
+        let mut hosts: HashMap<HostKey, Option<Arc<IpStats>>> =
+            self.evicted.drain(..).map(|key| (key, None)).collect();
+        for key in self.changed.drain(..) {
+            if let Some(stat) = self.hosts.get_mut(&key) {
+                stat.changed = false;
+                hosts.insert(key, Some(Arc::new(stat.snapshot())));
+            }
+        }
+        StatsDelta {
+            hosts,
+            retired: self.retired,
+        }
+    }
+}
+
+// 統計スレッドとスクレイプに渡す統計（フロー状態は含まない）
+#[derive(Clone, Default)]
+struct StatsSnapshot {
+    hosts: HashMap<HostKey, Arc<IpStats>>,
+    retired: RetiredTraffic,
+}
+
+impl StatsSnapshot {
+    fn apply(&mut self, delta: StatsDelta) {
+        for (key, stat) in delta.hosts {
+            match stat {
+                Some(stat) => self.hosts.insert(key, stat),
+                None => self.hosts.remove(&key),
+            };
+        }
+        self.retired = delta.retired;
+    }
+}
+
+// ワーカーが前回の公開から変わった分だけを渡す差分
+struct StatsDelta {
+    hosts: HashMap<HostKey, Option<Arc<IpStats>>>, // Noneは捨てたホスト
+    retired: RetiredTraffic,
+}
+
+impl Delta for StatsDelta {
+    fn absorb(&mut self, newer: Self) {
+        self.hosts.extend(newer.hosts);
+        self.retired = newer.retired;
+    }
+}
+
+// 1ワーカー分の公開済みの統計
+// ワーカーは差分をスロットに置くだけで、統計スレッドが読むときに自分の側のマップに畳み込む
+#[derive(Default)]
+struct PublishedStats {
+    deltas: DeltaSlot<StatsDelta>,
+    folded: Mutex<Arc<StatsSnapshot>>,
+}
+
+impl PublishedStats {
+    fn load(&self) -> Arc<StatsSnapshot> {
+        let mut folded = self.folded.lock().unwrap();
+        if let Some(delta) = self.deltas.take() {
+            Arc::make_mut(&mut folded).apply(delta);
+        }
+        folded.clone()
+    }
+}
 
-            let ip_str = ip.to_string();
+// 捨てたホストの累積バイト数とパケット数。インターフェース全体の累積値が減らないように残しておく
+#[derive(Debug, Clone, Copy, Default)]
+struct RetiredTraffic {
+    tx_bytes: u64,
+    rx_bytes: u64,
+    tx_packets: u64,
+    rx_packets: u64,
+}
 
-            // 累積値は一度だけ設定（reset使わない）
-            let tx_counter = self.ip_tx_bytes_total.with_label_values(&[&ip_str]);
-            let rx_counter = self.ip_rx_bytes_total.with_label_values(&[&ip_str]);
+// 1ワーカー分の統計。パケット処理側のスレッドだけが持つのでロックは要らない
+// 1秒のバケットを閉じるたびに、変わった分だけをスロットに公開する
+struct StatsShard {
+    table: HostTable,
+    clock: PacketClock,
+    published: Arc<PublishedStats>,
+    pending: Option<StatsDelta>, // 公開できずに残っている差分
+}
 
-            // 現在の値を取得して差分を計算
-            let current_tx = tx_counter.get();
-            let current_rx = rx_counter.get();
+impl StatsShard {
+    fn new(
+        published: Arc<PublishedStats>,
+        limits: StatsLimits,
+        accounting: Accounting,
+        services: Arc<ServiceMap>,
+        health: Arc<CaptureHealth>,
+    ) -> Self {
+        Self {
+            table: HostTable::new(limits, accounting, services, health),
+            clock: PacketClock::new(),
+            published,
+            pending: None,
+        }
+    }
 
-            if stat.tx_byte_count as f64 > current_tx {
-                tx_counter.inc_by(stat.tx_byte_count as f64 - current_tx);
+    fn advance(&mut self, ts: Duration) {
+        if advance_clock(&mut self.clock, &mut self.table, ts) {
+            self.queue_delta();
+        }
+        // 統計スレッドが読んでいる最中なら、次のパケットで公開し直す
+        if let Some(delta) = self.pending.take() {
+            if let Err(delta) = self.published.deltas.try_publish(delta) {
+                self.pending = Some(delta);
             }
-            if stat.rx_byte_count as f64 > current_rx {
-                rx_counter.inc_by(stat.rx_byte_count as f64 - current_rx);
+        }
+    }
+
+    // 途中のバケットの分も含めた現在の値を公開する（停止時）
+    fn publish_now(&mut self) {
+        self.queue_delta();
+        if let Some(delta) = self.pending.take() {
+            self.published.deltas.publish(delta);
+        }
+    }
+
+    fn queue_delta(&mut self) {
+        let delta = self.table.take_delta();
+        match &mut self.pending {
+            Some(pending) => pending.absorb(delta),
+            None => self.pending = Some(delta),
+        }
+    }
+}
+
+fn wall_clock_now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let config = Config::from_cli(&cli).unwrap_or_else(|e| {
+        eprintln!("Configuration error: {}", e);
+        process::exit(1);
+    });
+
+    #[cfg(feature = "bench")]
+    if let Some(packets) = config.bench {
+        bench::run(packets, config.backend, config.workers);
+        return;
+    }
+
+    match &config.source {
+        CaptureSource::Live(interfaces) => {
+            for interface in interfaces {
+                println!("Interface: {} (role {})", interface.name, interface.role);
             }
+        }
+        CaptureSource::File(path) => println!("Capture file: {}", path),
+    }
+    if config.include.is_empty() {
+        println!("Using auto-detected configuration");
+    } else {
+        println!("Monitored prefixes:");
+        for network in &config.include {
+            println!("  {}", network);
+        }
+    }
+    if !config.exclude.is_empty() {
+        println!("Excluded prefixes:");
+        for network in &config.exclude {
+            println!("  {}", network);
+        }
+    }
+    println!("Decapsulation: {}", config.decap);
+    println!("Accounting: {}", config.accounting);
+    println!("Service map: {}", config.services);
+
+    // Prometheusメトリクスを初期化
+    let prometheus_metrics = Arc::new(PrometheusMetrics::new());
+
+    // SIGINTはctrlc、SIGTERMはtokioで受ける（SIGHUPは設定の再読み込みに使う）
+    let running = Arc::new(AtomicBool::new(true));
+    let sigint_running = running.clone();
+    if let Err(e) = ctrlc::set_handler(move || on_shutdown_signal("SIGINT", &sigint_running)) {
+        eprintln!("Failed to install SIGINT handler: {}", e);
+    }
+    let rt = Runtime::new().unwrap();
+    rt.spawn(watch_sigterm(running.clone()));
+
+    match &config.source {
+        CaptureSource::Live(interfaces) => {
+            // 監視対象とWAN設定はSIGHUPや管理エンドポイントで差し替えられる
+            let runtime_settings = RuntimeSettings::from_config(&config, &HashMap::new())
+                .unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                });
+            let settings = Arc::new(SharedSettings::new(runtime_settings));
+            let reloader = Arc::new(Reloader::new(cli, config.clone(), settings.clone()));
+
+            // Prometheus HTTPサーバーを起動
+            let metrics_clone = prometheus_metrics.clone();
+            let metrics_listen = config.metrics_listen;
+            let reloader_http = reloader.clone();
+            rt.spawn(async move {
+                start_prometheus_server(metrics_clone, metrics_listen, reloader_http).await;
+            });
+            rt.spawn(reload::watch_detection(reloader.clone()));
+            rt.spawn(reload::watch_sighup(reloader));
 
-            // 1秒間の値はGaugeで設定
-            self.ip_tx_bytes_per_sec
-                .with_label_values(&[&ip_str])
-                .set(stat.tx_bytes_per_sec as f64);
-            self.ip_rx_bytes_per_sec
-                .with_label_values(&[&ip_str])
-                .set(stat.rx_bytes_per_sec as f64);
-
-            // bps値を設定（これが重要なメトリクス）
-            self.ip_tx_bps
-                .with_label_values(&[&ip_str])
-                .set(stat.tx_current_bps);
-            self.ip_rx_bps
-                .with_label_values(&[&ip_str])
-                .set(stat.rx_current_bps);
-
-            // パケットロス関連も同じように処理
-            self.ip_retransmissions_per_sec
-                .with_label_values(&[&ip_str])
-                .set(stat.retransmissions_per_sec as f64);
-            self.ip_duplicate_acks_per_sec
-                .with_label_values(&[&ip_str])
-                .set(stat.duplicate_acks_per_sec as f64);
-            self.ip_window_size_changes_per_sec
-                .with_label_values(&[&ip_str])
-                .set(stat.window_size_changes_per_sec as f64);
-
-            // NIC別の統計を集計（target_ipsに含まれるIPのみ）
-            let nic = wan_assignments.get_nic_for_ip(ip);
-            let entry = nic_stats.entry(nic).or_insert((0.0, 0.0, 0, 0));
-            entry.0 += stat.tx_current_bps;
-            entry.1 += stat.rx_current_bps;
-            entry.2 += stat.tx_bytes_per_sec;
-            entry.3 += stat.rx_bytes_per_sec;
-
-            // 全体統計に含める
-            total_tx_bytes += stat.tx_byte_count;
-            total_rx_bytes += stat.rx_byte_count;
-            total_tx_bytes_per_sec += stat.tx_bytes_per_sec;
-            total_rx_bytes_per_sec += stat.rx_bytes_per_sec;
-            total_tx_bps += stat.tx_current_bps;
-            total_rx_bps += stat.rx_current_bps;
-            total_retransmissions_per_sec += stat.retransmissions_per_sec;
-            total_duplicate_acks_per_sec += stat.duplicate_acks_per_sec;
-            total_window_size_changes_per_sec += stat.window_size_changes_per_sec;
-        }
-
-        // 全体のメトリクスを更新（累積値は適切に処理）
-        let current_total_tx = self.tx_bytes_total.get();
-        let current_total_rx = self.rx_bytes_total.get();
-
-        if total_tx_bytes as f64 > current_total_tx {
-            self.tx_bytes_total
-                .inc_by(total_tx_bytes as f64 - current_total_tx);
-        }
-        if total_rx_bytes as f64 > current_total_rx {
-            self.rx_bytes_total
-                .inc_by(total_rx_bytes as f64 - current_total_rx);
-        }
-
-        self.tx_bytes_per_sec.set(total_tx_bytes_per_sec as f64);
-        self.rx_bytes_per_sec.set(total_rx_bytes_per_sec as f64);
-        self.tx_bps.set(total_tx_bps);
-        self.rx_bps.set(total_rx_bps);
-        self.retransmissions_per_sec
-            .set(total_retransmissions_per_sec as f64);
-        self.duplicate_acks_per_sec
-            .set(total_duplicate_acks_per_sec as f64);
-        self.window_size_changes_per_sec
-            .set(total_window_size_changes_per_sec as f64);
-
-        // NIC別の合計メトリクスを更新
-        for (nic, (tx_bps, rx_bps, tx_bytes_per_sec, rx_bytes_per_sec)) in nic_stats {
-            self.nic_tx_bps_total.with_label_values(&[&nic]).set(tx_bps);
-            self.nic_rx_bps_total.with_label_values(&[&nic]).set(rx_bps);
-            self.nic_tx_bytes_per_sec_total
-                .with_label_values(&[&nic])
-                .set(tx_bytes_per_sec as f64);
-            self.nic_rx_bytes_per_sec_total
-                .with_label_values(&[&nic])
-                .set(rx_bytes_per_sec as f64);
+            // パケットキャプチャ部分に進む
+            start_packet_capture(interfaces, &config, settings, running, prometheus_metrics);
+        }
+        CaptureSource::File(path) => {
+            let targets = TargetNetworks::from_config(&config, None).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                process::exit(1);
+            });
+            // ファイルのリンク層は開いてから確かめる
+            let filter =
+                bpf::capture_filter(&config, &targets, LinkType::Ethernet).unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                });
+            // リプレイではHTTPサーバーを起動せず、最後にスナップショットを出力する
+            replay_capture_file(
+                path,
+                targets,
+                &filter,
+                &config,
+                &running,
+                prometheus_metrics,
+            );
         }
     }
 }
 
-struct IpStats {
-    tx_packet_count: u64, // 送信パケット数
-    rx_packet_count: u64, // 受信パケット数
-    tx_byte_count: u64,   // 送信バイト数
-    rx_byte_count: u64,   // 受信バイト数
-    tx_last_bytes: u64,
-    rx_last_bytes: u64,
-    last_time: Instant,
-    tx_current_bps: f64,   // 送信ビット/秒
-    rx_current_bps: f64,   // 受信ビット/秒
-    tx_bytes_per_sec: u64, // 1秒間の送信バイト数
-    rx_bytes_per_sec: u64, // 1秒間の受信バイト数
+// 1回目の停止シグナルでキャプチャループを止め、2回目は後始末を待たずに終了する
+fn on_shutdown_signal(name: &str, running: &AtomicBool) {
+    let count = SIGINT_COUNT.fetch_add(1, Ordering::SeqCst) + 1;
+    if count == 1 {
+        eprintln!(
+            "\n{} received, shutting down (send again to force exit)...",
+            name
+        );
+        running.store(false, Ordering::SeqCst);
+    } else {
+        eprintln!("{} received again, exiting immediately", name);
+        process::exit(130);
+    }
+}
 
-    // パケットロス関連
-    expected_seq: HashMap<u16, u32>, // ポート別の期待シーケンス番号
-    retransmissions: u64,            // 再送パケット数
-    duplicate_acks: u64,             // 重複ACK数
-    last_retransmissions: u64,       // 前回の再送パケット数
-    last_duplicate_acks: u64,        // 前回の重複ACK数
-    retransmissions_per_sec: u64,    // 1秒間の再送パケット数
-    duplicate_acks_per_sec: u64,     // 1秒間の重複ACK数
+// systemdの停止はSIGTERMで届く
+async fn watch_sigterm(running: Arc<AtomicBool>) {
+    let mut terminate =
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(signal) => signal,
+            Err(e) => {
+                eprintln!("Failed to install SIGTERM handler: {}", e);
+                return;
+            }
+        };
 
-    // TCPウィンドウサイズ関連
-    last_window_size: HashMap<u16, u16>, // ポート別の最後のウィンドウサイズ
-    window_size_changes: u64,            // ウィンドウサイズ変更回数
-    last_window_size_changes: u64,       // 前回のウィンドウサイズ変更回数
-    window_size_changes_per_sec: u64,    // 1秒間のウィンドウサイズ変更回数
+    while terminate.recv().await.is_some() {
+        on_shutdown_signal("SIGTERM", &running);
+    }
+}
+
+// IPヘッダーから取り出した、集計に必要な情報
+struct IpPacketInfo<'a> {
+    src_ip: IpAddr,
+    dst_ip: IpAddr,
+    vlan: VlanTags,
+    protocol: IpNextHeaderProtocol, // IPv6では拡張ヘッダーをたどった先の上位層
+    payload: &'a [u8],
+    fragment: Option<Fragment>, // 分割されたデータグラムの一部ならその位置
+    tunnel_overhead: u64,       // 外したトンネルのヘッダーのバイト数（数えない設定なら0）
 }
 
-fn get_interface_info(interface_name: &str) -> Option<(Ipv4Addr, u8)> {
-    let interfaces = datalink::interfaces();
+// キャプチャから受け取った1フレーム
+struct Frame<'a> {
+    link: LinkType,
+    data: &'a [u8],
+    len: usize, // スナップレンで切り詰められる前のフレームの長さ
+    // カーネルがフレームから外して別に渡してきた外側のVLAN ID（AF_PACKET）
+    vlan: Option<u16>,
+    ts: Duration,
+}
+
+// 1パケット分の解析と統計の更新（ライブ・リプレイ共通）
+// ヘッダーが短すぎて解析できなかった場合はその層を返す
+fn process_packet(
+    table: &mut HostTable,
+    targets: &TargetNetworks,
+    decap: &Decapsulation,
+    frame: &Frame,
+) -> Result<(), Malformed> {
+    let now = frame.ts;
+    let (ethertype, payload) = frame
+        .link
+        .strip_header(frame.data)
+        .ok_or(match frame.link {
+            LinkType::Ethernet => Malformed::Ethernet,
+            _ => Malformed::Link,
+        })?;
+
+    // Ethernetではフレーム全体の長さ（ヘッダー + ペイロード）
+    // cookedキャプチャなどの疑似ヘッダーは回線上にないので、それ以外ではIPパケットからの長さ
+    let mut l2_len = match frame.link {
+        LinkType::Ethernet => frame.len,
+        _ => frame.len.saturating_sub(frame.data.len() - payload.len()),
+    } as u64;
+
+    let mut vlan = VlanTags::default();
+    if let Some(id) = frame.vlan {
+        vlan.push(id);
+        // 回線上ではタグの分も流れている
+        l2_len += vlan::TAG_LEN as u64;
+    }
+    let (ethertype, payload) =
+        vlan::strip_tags(ethertype, payload, &mut vlan).ok_or(Malformed::Vlan)?;
+    if !targets.matches_vlan(&vlan) {
+        return Ok(());
+    }
+
+    // トンネルは内側のアドレスで集計する。バイト数はwire/l2では回線上のフレーム全体、l3/l4では内側のパケットから
+    let inner = decap.unwrap(ethertype, payload)?;
+    let (ethertype, payload) = (inner.ethertype, inner.payload);
+    let accounting = table.accounting;
+    let tunnel_overhead = if decap.count_overhead && accounting.includes_tunnel_headers() {
+        inner.overhead
+    } else {
+        0
+    };
+    let ethernet = frame.link == LinkType::Ethernet;
+
+    match EtherType(ethertype) {
+        EtherTypes::Ipv4 => {
+            let ipv4 = Ipv4Packet::new(payload).ok_or(Malformed::Ipv4)?;
+            let fragment = Fragment {
+                id: ipv4.get_identification() as u32,
+                offset: ipv4.get_fragment_offset() as usize * 8,
+                more: ipv4.get_flags() & Ipv4Flags::MoreFragments != 0,
+            };
+            let l3_len = ipv4.get_total_length() as u64;
+            let header_len = ipv4.get_header_length() as u64 * 4;
+            let lengths = PacketLengths {
+                l2: l2_len,
+                l3: l3_len,
+                l4: l4_payload_len(
+                    l3_len,
+                    header_len,
+                    ipv4.get_next_level_protocol().0,
+                    (fragment.offset == 0).then(|| ipv4.payload()),
+                ),
+            };
+            let info = IpPacketInfo {
+                src_ip: IpAddr::V4(ipv4.get_source()),
+                dst_ip: IpAddr::V4(ipv4.get_destination()),
+                vlan,
+                protocol: ipv4.get_next_level_protocol(),
+                payload: ipv4.payload(),
+                fragment: fragment.is_fragment().then_some(fragment),
+                tunnel_overhead,
+            };
+            let bytes = accounting.bytes(ethernet, &lengths);
+            account_ip_packet(table, targets, &info, bytes, now)
+        }
+        EtherTypes::Ipv6 => {
+            let ipv6 = Ipv6Packet::new(payload).ok_or(Malformed::Ipv6)?;
+            let upper = ipv6::upper_layer(ipv6.get_next_header().0, ipv6.payload())?;
+            let l3_len = IPV6_HEADER_LEN + ipv6.get_payload_length() as u64;
+            // 拡張ヘッダー（フラグメントヘッダーまで）もIPのヘッダーとして除く
+            let header_len = IPV6_HEADER_LEN + (ipv6.payload().len() - upper.payload.len()) as u64;
+            let lengths = PacketLengths {
+                l2: l2_len,
+                l3: l3_len,
+                l4: l4_payload_len(
+                    l3_len,
+                    header_len,
+                    upper.protocol,
+                    (!matches!(upper.fragment, Some(fragment) if fragment.offset != 0))
+                        .then_some(upper.payload),
+                ),
+            };
+            let info = IpPacketInfo {
+                src_ip: IpAddr::V6(ipv6.get_source()),
+                dst_ip: IpAddr::V6(ipv6.get_destination()),
+                vlan,
+                protocol: IpNextHeaderProtocol(upper.protocol),
+                payload: upper.payload,
+                fragment: upper.fragment,
+                tunnel_overhead,
+            };
+            let bytes = accounting.bytes(ethernet, &lengths);
+            account_ip_packet(table, targets, &info, bytes, now)
+        }
+        _ => Ok(()),
+    }
+}
+
+// IPv4/IPv6共通: 送受信の集計とTCPの解析
+// フラグメントはパケットごとにバイト数を数え、TCPの解析はデータグラムが揃ってから行う
+fn account_ip_packet(
+    table: &mut HostTable,
+    targets: &TargetNetworks,
+    info: &IpPacketInfo,
+    packet_size: u64,
+    now: Duration,
+) -> Result<(), Malformed> {
+    let (src_ip, dst_ip) = (info.src_ip, info.dst_ip);
+    let src_is_target = targets.contains(&src_ip);
+    let dst_is_target = targets.contains(&dst_ip);
+
+    // ソースまたはデスティネーションが監視対象に含まれている場合のみ処理
+    if !src_is_target && !dst_is_target {
+        return Ok(());
+    }
+    let src = HostKey {
+        ip: src_ip,
+        vlan: info.vlan,
+    };
+    let dst = HostKey {
+        ip: dst_ip,
+        vlan: info.vlan,
+    };
+
+    // 送信トラフィック（ソースIPが監視対象）と受信トラフィック（デスティネーションIPが監視対象）
+    let protocol = Protocol::from_number(info.protocol.0);
+    // 先頭以外のフラグメントにはTCP/UDPのヘッダーがないので、ポート別には数えない
+    let ports = match protocol {
+        Protocol::Tcp | Protocol::Udp if !matches!(info.fragment, Some(fragment) if fragment.offset != 0) => {
+            Ports::parse(info.payload)
+        }
+        _ => None,
+    };
+    if src_is_target {
+        update_tx_stats(
+            table,
+            src,
+            protocol,
+            ports,
+            packet_size,
+            info.tunnel_overhead,
+            now,
+        );
+    }
+    if dst_is_target {
+        update_rx_stats(
+            table,
+            dst,
+            protocol,
+            ports,
+            packet_size,
+            info.tunnel_overhead,
+            now,
+        );
+    }
+
+    let Some(fragment) = info.fragment else {
+        if info.protocol == IpNextHeaderProtocols::Tcp {
+            observe_tcp(
+                table,
+                (src, src_is_target),
+                (dst, dst_is_target),
+                info.payload,
+                now,
+            )?;
+        }
+        return Ok(());
+    };
+
+    let hosts: Vec<HostKey> = [(src, src_is_target), (dst, dst_is_target)]
+        .into_iter()
+        .filter(|(_, is_target)| *is_target)
+        .map(|(host, _)| host)
+        .collect();
+    for host in &hosts {
+        if let Some(stat) = table.hosts.get_mut(host) {
+            stat.fragments += 1;
+        }
+    }
+    let key = DatagramKey {
+        src,
+        dst,
+        protocol: info.protocol.0,
+        id: fragment.id,
+    };
+    match table
+        .fragments
+        .insert(key, fragment, info.payload, &hosts, now)
+    {
+        Reassembly::Pending | Reassembly::Discarded => Ok(()),
+        Reassembly::Failed => {
+            table.count_reassembly_failures(&hosts);
+            Ok(())
+        }
+        Reassembly::Complete(datagram) => {
+            // IPv6ではフラグメントヘッダーの後ろにも拡張ヘッダーがありうる
+            let (protocol, payload) = match src_ip {
+                IpAddr::V4(_) => (info.protocol.0, &datagram[..]),
+                IpAddr::V6(_) => {
+                    let upper = ipv6::upper_layer(info.protocol.0, &datagram)?;
+                    (upper.protocol, upper.payload)
+                }
+            };
+            if IpNextHeaderProtocol(protocol) == IpNextHeaderProtocols::Tcp {
+                observe_tcp(
+                    table,
+                    (src, src_is_target),
+                    (dst, dst_is_target),
+                    payload,
+                    now,
+                )?;
+            }
+            Ok(())
+        }
+    }
+}
+
+// TCPセグメントを監視対象側のフロー状態で解析する（再送・順序入れ替わり・ウィンドウ・RTT）
+// TCPヘッダーが壊れていてもバイト数はすでに数えてある
+fn observe_tcp(
+    table: &mut HostTable,
+    (src, src_is_target): (HostKey, bool),
+    (dst, dst_is_target): (HostKey, bool),
+    segment: &[u8],
+    now: Duration,
+) -> Result<(), Malformed> {
+    let tcp = TcpPacket::new(segment).ok_or(Malformed::Tcp)?;
+    if src_is_target {
+        observe_outbound_tcp(table, src, dst.ip, &tcp, now);
+    }
+    if dst_is_target {
+        observe_inbound_tcp(table, dst, src.ip, &tcp, now);
+    }
+    Ok(())
+}
+
+// キャプチャ中のインターフェースごとの状態
+#[derive(Clone)]
+struct InterfaceCapture {
+    name: String,
+    role: String,
+    // ワーカーごとに公開された最新の統計（pcapでは1つ）。各ワーカーは自分のスロットにだけ書く
+    shards: Arc<Vec<Arc<PublishedStats>>>,
+    up: Arc<AtomicBool>, // キャプチャが動いているか
+    health: Arc<CaptureHealth>,
+    backend: CaptureBackend,
+    buffer_size: Option<i32>,
+    limits: StatsLimits,
+    accounting: Accounting,
+    services: Arc<ServiceMap>, // サービス名の割り当て（ワーカーと同じもの）
+}
+
+impl InterfaceCapture {
+    // 全ワーカーのスナップショットをまとめたもの。ワーカーのハッシュマップには触れない
+    fn merged_stats(&self) -> Arc<StatsSnapshot> {
+        if let [shard] = self.shards.as_slice() {
+            return shard.load();
+        }
+        let mut hosts: HashMap<HostKey, IpStats> = HashMap::new();
+        let mut retired = RetiredTraffic::default();
+        for shard in self.shards.iter() {
+            let shard = shard.load();
+            for (ip, stat) in shard.hosts.iter() {
+                hosts
+                    .entry(*ip)
+                    .or_insert_with(|| IpStats::new(stat.last_time))
+                    .merge(stat);
+            }
+            retired.tx_bytes += shard.retired.tx_bytes;
+            retired.rx_bytes += shard.retired.rx_bytes;
+            retired.tx_packets += shard.retired.tx_packets;
+            retired.rx_packets += shard.retired.rx_packets;
+        }
+        let hosts = hosts
+            .into_iter()
+            .map(|(ip, mut stat)| {
+                stat.calculate_rtt();
+                (ip, Arc::new(stat))
+            })
+            .collect();
+        Arc::new(StatsSnapshot { hosts, retired })
+    }
+}
+
+// 開いたキャプチャ
+enum OpenedCapture {
+    Pcap(Capture<pcap::Active>, LinkType),
+    #[cfg(target_os = "linux")]
+    AfPacket(Vec<af_packet::Ring>), // ワーカーごとのfanoutリング
+}
+
+fn open_capture(
+    state: &InterfaceCapture,
+    settings: &SharedSettings,
+) -> Result<OpenedCapture, String> {
+    match state.backend {
+        CaptureBackend::Pcap => open_live_capture(&state.name, settings, state.buffer_size)
+            .map(|(cap, link)| OpenedCapture::Pcap(cap, link)),
+        #[cfg(target_os = "linux")]
+        CaptureBackend::AfPacket => {
+            let link = af_packet::interface_link_type(&state.name)?;
+            let filter = checked_filter(&state.name, link, settings)?;
+            // 全ワーカーのリングを同じfanoutグループで開く。1つでも失敗したらまとめて開き直す
+            let group = af_packet::new_fanout_group();
+            (0..state.shards.len())
+                .map(|_| af_packet::Ring::open(&state.name, group, &filter, state.buffer_size))
+                .collect::<Result<Vec<_>, _>>()
+                .map(OpenedCapture::AfPacket)
+        }
+        #[cfg(not(target_os = "linux"))]
+        CaptureBackend::AfPacket => Err("AF_PACKET backend is only available on Linux".to_string()),
+    }
+}
+
+// 開いたインターフェースの実際のリンク層を記録し、現在のフィルタがそのリンクで使えるか確かめる
+// 使えなければ設定を直して再読み込みするまで、開き直しを繰り返す
+fn checked_filter(
+    interface_name: &str,
+    link: LinkType,
+    settings: &SharedSettings,
+) -> Result<CaptureFilter, String> {
+    settings.record_link(interface_name, link);
+    let filter = settings.load().filter_for(interface_name).clone();
+    filter.validate(link).map_err(|e| {
+        format!(
+            "Cannot set BPF filter on '{}' (link type {}): {}; fix the filter and reload",
+            interface_name, link, e
+        )
+    })?;
+    Ok(filter)
+}
+
+fn open_live_capture(
+    interface_name: &str,
+    settings: &SharedSettings,
+    buffer_size: Option<i32>,
+) -> Result<(Capture<pcap::Active>, LinkType), String> {
+    // インターフェースを見つける
+    let device = Device::list()
+        .map_err(|e| format!("Failed to list interfaces: {}", e))?
+        .into_iter()
+        .find(|d| d.name == *interface_name)
+        .ok_or_else(|| format!("Interface '{}' not found", interface_name))?;
 
+    let mut cap = Capture::from_device(device)
+        .and_then(|cap| {
+            // anyデバイスはプロミスキャスモードにできない（libpcapが警告を返して開けなくなる）
+            // タイムアウトを短くして応答性を向上
+            let cap = cap
+                .promisc(interface_name != ANY_DEVICE)
+                .snaplen(65535)
+                .timeout(100);
+            // 取りこぼしが多い場合はバッファを大きくする
+            match buffer_size {
+                Some(size) => cap.buffer_size(size).open(),
+                None => cap.open(),
+            }
+        })
+        .map_err(|e| format!("Failed to open interface '{}': {}", interface_name, e))?;
+
+    let link = LinkType::from_pcap(cap.get_datalink())
+        .map_err(|e| format!("Cannot capture on '{}': {}", interface_name, e))?;
+
+    // 監視対象外のパケットはカーネル側で落とす
+    let filter = checked_filter(interface_name, link, settings)?;
+    if let Some(filter) = filter.expression(link) {
+        cap.filter(&filter, true)
+            .map_err(|e| format!("Failed to set BPF filter on '{}': {}", interface_name, e))?;
+    }
+    Ok((cap, link))
+}
+
+fn start_packet_capture(
+    interfaces: &[InterfaceConfig],
+    config: &Config,
+    settings: Arc<SharedSettings>,
+    running: Arc<AtomicBool>,
+    prometheus_metrics: Arc<PrometheusMetrics>,
+) {
+    // 開けないインターフェースがあっても起動し、キャプチャスレッドが再オープンを続ける
+    let services = Arc::new(config.services.clone());
+    let mut states = Vec::new();
     for interface in interfaces {
-        if interface.name == interface_name {
-            for network in interface.ips {
-                if let IpNetwork::V4(ipv4_network) = network {
-                    return Some((ipv4_network.ip(), ipv4_network.prefix()));
+        println!(
+            "Capturing on interface: {} (role {})",
+            interface.name, interface.role
+        );
+        println!(
+            "  Monitoring prefixes: {}",
+            settings.load().targets_for(&interface.name)
+        );
+        println!(
+            "  BPF filter: {}",
+            settings
+                .load()
+                .filter_for(&interface.name)
+                .describe(settings.link_for(&interface.name))
+        );
+        match config.backend {
+            CaptureBackend::Pcap => println!("  Backend: pcap"),
+            CaptureBackend::AfPacket => {
+                println!("  Backend: af-packet ({} workers)", config.workers)
+            }
+        }
+        states.push(InterfaceCapture {
+            name: interface.name.clone(),
+            role: interface.role.clone(),
+            shards: Arc::new(
+                (0..config.workers)
+                    .map(|_| Arc::new(PublishedStats::default()))
+                    .collect(),
+            ),
+            up: Arc::new(AtomicBool::new(false)),
+            health: Arc::new(CaptureHealth::default()),
+            backend: config.backend,
+            buffer_size: config.buffer_size,
+            limits: config.limits,
+            accounting: config.accounting,
+            services: services.clone(),
+        });
+    }
+    println!("version {}", version::VERSION);
+
+    // WAN割り当て情報を管理
+    let wan_assignments = Arc::new(Mutex::new(WanAssignments::new()));
+
+    // メトリクスはスクレイプのたびに各インターフェースの最新の統計から組み立てる
+    let scrape_settings = settings.clone();
+    prometheus_metrics.attach(
+        states.clone(),
+        Box::new(move |name| scrape_settings.load().targets_for(name).clone()),
+        wan_assignments.clone(),
+    );
+
+    // 起動時にWAN割り当て情報を取得
+    let rt_init = Runtime::new().unwrap();
+    rt_init.block_on(async {
+        match WanAssignments::fetch_from_api(&settings.load().wan_api_url).await {
+            Ok(assignments) => {
+                let mut wan_data = wan_assignments.lock().unwrap();
+                *wan_data = assignments;
+                println!(
+                    "Initial WAN assignments loaded: wan0={} IPs, wan1={} IPs",
+                    wan_data.wan0_ips.len(),
+                    wan_data.wan1_ips.len()
+                );
+            }
+            Err(e) => {
+                eprintln!("Failed to fetch initial WAN assignments: {}", e);
+                eprintln!("Will retry in background thread...");
+            }
+        }
+    });
+
+    // WAN割り当て情報を定期的に更新するスレッド
+    let wan_running = running.clone();
+    let wan_assignments_clone = wan_assignments.clone();
+    let wan_settings = settings.clone();
+    let rt_wan = Runtime::new().unwrap();
+    let wan_thread = thread::spawn(move || {
+        while wan_running.load(Ordering::SeqCst) {
+            // ポーリング間隔だけ待機してから更新
+            if !sleep_while_running(&wan_running, wan_settings.load().wan_poll_interval) {
+                return;
+            }
+
+            // 待機中にリロードされていれば新しいURLを使う
+            let wan_api_url = wan_settings.load().wan_api_url.clone();
+            rt_wan.block_on(async {
+                match WanAssignments::fetch_from_api(&wan_api_url).await {
+                    Ok(assignments) => {
+                        let mut wan_data = wan_assignments_clone.lock().unwrap();
+                        *wan_data = assignments;
+                        println!(
+                            "WAN assignments updated: wan0={} IPs, wan1={} IPs",
+                            wan_data.wan0_ips.len(),
+                            wan_data.wan1_ips.len()
+                        );
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to fetch WAN assignments: {}", e);
+                    }
+                }
+            });
+        }
+    });
+
+    // 統計表示用スレッド（全インターフェース分をまとめて更新する）
+    let stats_running = running.clone();
+    let stats_states = states.clone();
+    let stats_settings = settings.clone();
+    let stats_thread = thread::spawn(move || {
+        while stats_running.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(100)); // より短い間隔でチェック
+            if !stats_running.load(Ordering::SeqCst) {
+                break;
+            }
+            // レートはキャプチャループがパケット時刻のバケット境界で計算済み
+            let current = stats_settings.load();
+            if current.tui {
+                print_header();
+                for state in &stats_states {
+                    print_stats(
+                        &state.name,
+                        &state.role,
+                        state.up.load(Ordering::SeqCst),
+                        &state.merged_stats().hosts,
+                        current.targets_for(&state.name),
+                        &state.health,
+                        current.tui_protocols,
+                    );
+                }
+            }
+            // 更新間隔だけ待つが、100msごとに中断チェック
+            sleep_while_running(&stats_running, current.refresh_interval);
+        }
+    });
+
+    // インターフェースごとにキャプチャスレッドを起動
+    let capture_threads: Vec<_> = states
+        .iter()
+        .map(|state| {
+            let state = state.clone();
+            let settings = settings.clone();
+            let running = running.clone();
+            let metrics = prometheus_metrics.clone();
+            thread::spawn(move || supervise_capture(&state, &settings, &running, &metrics))
+        })
+        .collect();
+
+    println!("Press Ctrl+C to stop...");
+
+    for capture_thread in capture_threads {
+        let _ = capture_thread.join();
+    }
+
+    // キャプチャスレッドは停止要求でのみ終わるが、念のため他のスレッドにも停止を伝える
+    running.store(false, Ordering::SeqCst);
+
+    // 統計表示スレッドとWAN割り当ての更新スレッドの終了を待つ
+    let _ = stats_thread.join();
+    let _ = wan_thread.join();
+
+    println!("\nFinal statistics:");
+    {
+        // 途中までのバケットは含めず、最後に閉じた1秒間のレートを表示する
+        let current = settings.load();
+        let snapshots: Vec<_> = states
+            .iter()
+            .map(|state| (state, state.merged_stats()))
+            .collect();
+        if current.tui {
+            print_header();
+        }
+        for (state, final_stats) in &snapshots {
+            let targets = current.targets_for(&state.name);
+            if current.tui {
+                print_stats(
+                    &state.name,
+                    &state.role,
+                    state.up.load(Ordering::SeqCst),
+                    &final_stats.hosts,
+                    targets,
+                    &state.health,
+                    current.tui_protocols,
+                );
+            } else {
+                print_totals(&state.name, &final_stats.hosts, targets, &state.health);
+            }
+        }
+        if let Some(path) = &current.summary_file {
+            let sections: Vec<_> = snapshots
+                .iter()
+                .map(|(state, final_stats)| summary::InterfaceStats {
+                    name: &state.name,
+                    role: &state.role,
+                    targets: current.targets_for(&state.name),
+                    stats: &final_stats.hosts,
+                    services: &state.services,
+                    capture: state.health.snapshot(),
+                })
+                .collect();
+            save_summary(path, &sections);
+        }
+    }
+}
+
+// インターフェースの状態。Noneはインターフェースが存在しない
+fn link_is_up(interface_name: &str) -> Option<bool> {
+    // anyは実在するインターフェースではないので、常に開けるものとして扱う
+    if interface_name == ANY_DEVICE {
+        return Some(true);
+    }
+    datalink::interfaces()
+        .into_iter()
+        .find(|interface| interface.name == interface_name)
+        .map(|interface| interface.is_up())
+}
+
+// キャプチャを開いてループを回し、止まったらバックオフしながら開き直す
+// インターフェースが消えたりダウンしたりしても、停止要求があるまで戻らない
+fn supervise_capture(
+    state: &InterfaceCapture,
+    settings: &SharedSettings,
+    running: &AtomicBool,
+    metrics: &PrometheusMetrics,
+) {
+    let mut shards: Vec<StatsShard> = state
+        .shards
+        .iter()
+        .map(|slot| {
+            StatsShard::new(
+                slot.clone(),
+                state.limits,
+                state.accounting,
+                state.services.clone(),
+                state.health.clone(),
+            )
+        })
+        .collect();
+    let mut backoff = REOPEN_INITIAL_BACKOFF;
+    let mut opened_before = false;
+    let mut last_error = String::new();
+
+    while running.load(Ordering::SeqCst) {
+        let opened = match link_is_up(&state.name) {
+            None => Err(format!("Interface '{}' not found", state.name)),
+            Some(false) => Err(format!("Interface '{}' is down", state.name)),
+            Some(true) => open_capture(state, settings),
+        };
+
+        match opened {
+            Ok(opened) => {
+                if opened_before {
+                    metrics.count_restart(&state.name);
+                    println!("Capture on {} reopened", state.name);
+                }
+                opened_before = true;
+                backoff = REOPEN_INITIAL_BACKOFF;
+                last_error.clear();
+                state.up.store(true, Ordering::SeqCst);
+
+                let result = match opened {
+                    OpenedCapture::Pcap(cap, link) => {
+                        capture_loop(cap, link, state, &mut shards[0], settings, running)
+                    }
+                    #[cfg(target_os = "linux")]
+                    OpenedCapture::AfPacket(rings) => {
+                        af_packet_capture(rings, state, &mut shards, settings, running)
+                    }
+                };
+
+                state.up.store(false, Ordering::SeqCst);
+                match result {
+                    Ok(()) => break,
+                    Err(e) => eprintln!("Capture on {} stopped: {}; reopening", state.name, e),
+                }
+            }
+            Err(e) => {
+                // 同じ理由で失敗し続ける間はログを繰り返さない
+                if e != last_error {
+                    eprintln!("{}; retrying with backoff", e);
+                    last_error = e;
+                }
+                wait_for_reopen(&mut shards, running, backoff);
+                backoff = (backoff * 2).min(REOPEN_MAX_BACKOFF);
+            }
+        }
+    }
+
+    // 最終結果に途中のバケットの分も含める
+    for shard in &mut shards {
+        shard.publish_now();
+    }
+}
+
+// 再オープンまで待つ間もレートが0に落ちるよう、時計を進め続ける
+fn wait_for_reopen(shards: &mut [StatsShard], running: &AtomicBool, backoff: Duration) {
+    let deadline = Instant::now() + backoff;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        if !sleep_while_running(running, remaining.min(Duration::from_secs(1))) {
+            break;
+        }
+        let now = wall_clock_now().saturating_sub(CAPTURE_DELIVERY_GRACE);
+        for shard in shards.iter_mut() {
+            shard.advance(now);
+        }
+    }
+}
+
+// キャプチャループが手元に持つ設定。リロードされるまではパケットごとにロックを取らない
+struct CachedSettings {
+    generation: u64,
+    targets: TargetNetworks,
+    filter: CaptureFilter,
+    decap: Decapsulation,
+}
+
+impl CachedSettings {
+    fn new(settings: &SharedSettings, interface: &str) -> Self {
+        let generation = settings.generation();
+        let current = settings.load();
+        Self {
+            generation,
+            targets: current.targets_for(interface).clone(),
+            filter: current.filter_for(interface).clone(),
+            decap: current.decap,
+        }
+    }
+
+    // リロードされていれば監視対象を取り直し、フィルタが変わっていれば新しいフィルタを返す
+    // フィルタは呼び出し側がハンドルに設定できたらself.filterに反映する
+    fn refresh(&mut self, settings: &SharedSettings, interface: &str) -> Option<CaptureFilter> {
+        if settings.generation() == self.generation {
+            return None;
+        }
+        self.generation = settings.generation();
+        let current = settings.load();
+        self.targets = current.targets_for(interface).clone();
+        self.decap = current.decap;
+        let filter = current.filter_for(interface);
+        if *filter == self.filter {
+            return None;
+        }
+        Some(filter.clone())
+    }
+}
+
+// 1インターフェース分のキャプチャループ。停止要求でOk、読み込みエラーやリンクダウンでErrを返す
+fn capture_loop(
+    mut cap: Capture<pcap::Active>,
+    link: LinkType,
+    state: &InterfaceCapture,
+    shard: &mut StatsShard,
+    settings: &SharedSettings,
+    running: &AtomicBool,
+) -> Result<(), String> {
+    let mut consecutive_timeouts = 0;
+    const LINK_CHECK_TIMEOUTS: u32 = 10; // 1秒間パケットが来なければリンク状態を確認
+    const PCAP_STATS_INTERVAL: Duration = Duration::from_secs(1);
+    // pcap_statsは開いたハンドルごとの累計なので、再オープン時は0から数え直す
+    let mut last_pcap_stats = pcap::Stat {
+        received: 0,
+        dropped: 0,
+        if_dropped: 0,
+    };
+    let mut last_pcap_sample = Instant::now();
+    let mut cached = CachedSettings::new(settings, &state.name);
+
+    let result = loop {
+        if !running.load(Ordering::SeqCst) {
+            break Ok(());
+        }
+
+        if last_pcap_sample.elapsed() >= PCAP_STATS_INTERVAL {
+            last_pcap_sample = Instant::now();
+            sample_pcap_stats(&mut cap, &state.health, &mut last_pcap_stats);
+        }
+
+        // フィルタで絞り込むとパケットが来ないこともあるので、受信の有無にかかわらず確認する
+        if let Some(new_filter) = cached.refresh(settings, &state.name) {
+            // 検証済みの式なので通常は失敗しないが、失敗したら前のフィルタのまま続ける
+            match cap.filter(new_filter.expression(link).as_deref().unwrap_or(""), true) {
+                Ok(()) => {
+                    log_filter_change(&state.name, &new_filter, link);
+                    cached.filter = new_filter;
+                }
+                Err(e) => log_filter_error(&state.name, &e.to_string()),
+            }
+        }
+
+        match cap.next_packet() {
+            Ok(packet) => {
+                consecutive_timeouts = 0; // パケットを受信したらリセット
+                let frame = Frame {
+                    link,
+                    data: packet.data,
+                    len: packet.header.len as usize,
+                    vlan: None,
+                    ts: packet_timestamp(packet.header),
+                };
+                shard.advance(frame.ts);
+                if let Err(malformed) =
+                    process_packet(&mut shard.table, &cached.targets, &cached.decap, &frame)
+                {
+                    state.health.count_malformed(malformed);
+                }
+            }
+            Err(pcap::Error::TimeoutExpired) => {
+                consecutive_timeouts += 1;
+                // パケットが来ない間もレートが0に落ちるよう、遅延分の猶予を見て時計を進める
+                shard.advance(wall_clock_now().saturating_sub(CAPTURE_DELIVERY_GRACE));
+                // ダウンしたインターフェースではエラーにならずタイムアウトが続くことがあるので確認する
+                if consecutive_timeouts >= LINK_CHECK_TIMEOUTS {
+                    consecutive_timeouts = 0;
+                    match link_is_up(&state.name) {
+                        Some(true) => {}
+                        Some(false) => break Err("interface went down".to_string()),
+                        None => break Err("interface disappeared".to_string()),
+                    }
+                }
+            }
+            Err(e) => break Err(e.to_string()),
+        }
+    };
+
+    // 閉じる前に最後の取りこぼし数を反映しておく
+    sample_pcap_stats(&mut cap, &state.health, &mut last_pcap_stats);
+    result
+}
+
+// AF_PACKETのfanoutリングをワーカースレッドで読む。どれかが止まったら全ワーカーを止めてErrを返す
+#[cfg(target_os = "linux")]
+fn af_packet_capture(
+    rings: Vec<af_packet::Ring>,
+    state: &InterfaceCapture,
+    shards: &mut [StatsShard],
+    settings: &SharedSettings,
+    running: &AtomicBool,
+) -> Result<(), String> {
+    let stop = AtomicBool::new(false);
+    let error = Mutex::new(None);
+    thread::scope(|scope| {
+        for (worker, (ring, shard)) in rings.into_iter().zip(shards.iter_mut()).enumerate() {
+            let (stop, error) = (&stop, &error);
+            scope.spawn(move || {
+                let result = af_packet_worker(ring, worker, state, shard, settings, running, stop);
+                if let Err(e) = result {
+                    error.lock().unwrap().get_or_insert(e);
+                }
+                stop.store(true, Ordering::SeqCst);
+            });
+        }
+    });
+    match error.into_inner().unwrap() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn af_packet_worker(
+    mut ring: af_packet::Ring,
+    worker: usize,
+    state: &InterfaceCapture,
+    shard: &mut StatsShard,
+    settings: &SharedSettings,
+    running: &AtomicBool,
+    stop: &AtomicBool,
+) -> Result<(), String> {
+    const BLOCK_WAIT: Duration = Duration::from_millis(100);
+    const LINK_CHECK_TIMEOUTS: u32 = 10;
+    const STATS_INTERVAL: Duration = Duration::from_secs(1);
+    let mut cached = CachedSettings::new(settings, &state.name);
+    let mut consecutive_timeouts = 0;
+    let mut last_stats_sample = Instant::now();
+
+    while running.load(Ordering::SeqCst) && !stop.load(Ordering::SeqCst) {
+        if last_stats_sample.elapsed() >= STATS_INTERVAL {
+            last_stats_sample = Instant::now();
+            sample_ring_stats(&ring, &state.health);
+        }
+
+        if let Some(new_filter) = cached.refresh(settings, &state.name) {
+            match ring.set_filter(&new_filter) {
+                Ok(()) => {
+                    // 同じ変更を全ワーカーが適用するので、ログは1回だけ出す
+                    if worker == 0 {
+                        log_filter_change(&state.name, &new_filter, ring.link());
+                    }
+                    cached.filter = new_filter;
+                }
+                Err(e) => log_filter_error(&state.name, &e),
+            }
+        }
+
+        let (targets, decap) = (&cached.targets, &cached.decap);
+        let link = ring.link();
+        let received = ring.next_block(BLOCK_WAIT, |packet| {
+            let frame = Frame {
+                link,
+                data: packet.data,
+                len: packet.len,
+                vlan: packet.vlan,
+                ts: packet.ts,
+            };
+            shard.advance(frame.ts);
+            if let Err(malformed) = process_packet(&mut shard.table, targets, decap, &frame) {
+                state.health.count_malformed(malformed);
+            }
+        });
+        match received {
+            Ok(true) => consecutive_timeouts = 0,
+            Ok(false) => {
+                consecutive_timeouts += 1;
+                shard.advance(wall_clock_now().saturating_sub(CAPTURE_DELIVERY_GRACE));
+                if consecutive_timeouts >= LINK_CHECK_TIMEOUTS {
+                    consecutive_timeouts = 0;
+                    match link_is_up(&state.name) {
+                        Some(true) => {}
+                        Some(false) => return Err("interface went down".to_string()),
+                        None => return Err("interface disappeared".to_string()),
+                    }
                 }
             }
+            Err(e) => return Err(e),
         }
     }
-    None
-}
-
-fn ipv4_list(ip: Ipv4Addr, prefix: u8) -> HashSet<IpAddr> {
-    let ip_u32 = u32::from(ip);
-    let mask = !(0xFFFFFFFFu32 >> prefix);
-    let network_addr_u32 = ip_u32 & mask;
-    let broadcast_addr_u32 = network_addr_u32 | !mask;
-
-    let mut ip_address_set = HashSet::new();
-
-    // ネットワークアドレスとブロードキャストアドレスを除く
-    for ip_int in (network_addr_u32 + 1)..broadcast_addr_u32 {
-        ip_address_set.insert(IpAddr::V4(Ipv4Addr::from(ip_int)));
-    }
 
-    ip_address_set
+    sample_ring_stats(&ring, &state.health);
+    Ok(())
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: {} <interface_name>", args[0]);
-        process::exit(1);
+// カーネルのカウンタは読むたびにリセットされるので、そのまま足す
+#[cfg(target_os = "linux")]
+fn sample_ring_stats(ring: &af_packet::Ring, health: &CaptureHealth) {
+    if let Ok((received, dropped)) = ring.take_stats() {
+        health.add_counts(received as u64, dropped as u64, 0);
     }
+}
 
-    let interface_name = &args[1];
+fn log_filter_change(interface: &str, filter: &CaptureFilter, link: LinkType) {
+    println!(
+        "BPF filter on {} changed to: {}",
+        interface,
+        filter.describe(link)
+    );
+}
 
-    // 固定値が設定されている場合はそれを使用、なければ自動検出
-    let (ip, prefix) = if let Some((fixed_ip, fixed_prefix)) = FIXED_INTERFACE_CONFIG {
-        // コード内の固定値を使用
-        println!("Using fixed configuration from code:");
-        println!("  IP={}", fixed_ip);
-        println!("  PREFIX={}", fixed_prefix);
-        (fixed_ip, fixed_prefix)
-    } else {
-        // 自動検出
-        match get_interface_info(interface_name) {
-            Some((ip, prefix)) => {
-                println!("Using auto-detected configuration:");
-                (ip, prefix)
-            }
-            None => {
-                eprintln!(
-                    "Interface '{}' not found or has no IPv4 address",
-                    interface_name
-                );
-                eprintln!("\nTo use fixed values, edit FIXED_INTERFACE_CONFIG in the code:");
-                eprintln!("  const FIXED_INTERFACE_CONFIG: Option<(Ipv4Addr, u8)> = Some((Ipv4Addr::new(192, 168, 1, 1), 24));");
-                process::exit(1);
-            }
-        }
-    };
+fn log_filter_error(interface: &str, error: &str) {
+    eprintln!(
+        "Failed to update BPF filter on {}, keeping previous one: {}",
+        interface, error
+    );
+}
 
-    println!("Interface: {}", interface_name);
-    println!("IP Address: {}", ip);
-    println!("Subnet Mask: /{}", prefix);
+fn sample_pcap_stats(
+    cap: &mut Capture<pcap::Active>,
+    health: &CaptureHealth,
+    last: &mut pcap::Stat,
+) {
+    // 統計を取れないプラットフォームやダウン中のハンドルでは何もしない
+    if let Ok(current) = cap.stats() {
+        health.add_pcap_stats(last, &current);
+        *last = current;
+    }
+}
 
-    let ip_set = ipv4_list(ip, prefix);
+// コンソール表を使わない場合の最終結果（ログに残す1行）
+fn print_totals(
+    interface: &str,
+    stats: &HashMap<HostKey, Arc<IpStats>>,
+    targets: &TargetNetworks,
+    health: &CaptureHealth,
+) {
+    let (ips, tx_bytes, rx_bytes) = stats
+        .iter()
+        .filter(|(key, _)| key.is_target(targets))
+        .fold((0, 0, 0), |(ips, tx, rx), (_, s)| {
+            (ips + 1, tx + s.tx_byte_count, rx + s.rx_byte_count)
+        });
+    let capture = health.snapshot();
     println!(
-        "Available IP addresses in subnet: {} addresses",
-        ip_set.len()
+        "{}: {} monitored IPs, TX {} bytes, RX {} bytes, dropped {} (interface {}), malformed {}",
+        interface, ips, tx_bytes, rx_bytes, capture.dropped, capture.if_dropped, capture.malformed
     );
+}
 
-    // 最初の10個のIPアドレスを表示
-    let mut count = 0;
-    for ip_addr in &ip_set {
-        if count < 10 {
-            println!("  {}", ip_addr);
-            count += 1;
-        } else {
-            println!("  ... and {} more", ip_set.len() - 10);
-            break;
-        }
+fn save_summary(path: &Path, interfaces: &[summary::InterfaceStats]) {
+    match summary::write_summary(path, interfaces) {
+        Ok(()) => println!("Summary written to {}", path.display()),
+        Err(e) => eprintln!("Failed to write summary to {}: {}", path.display(), e),
     }
+}
 
-    // Prometheusメトリクスを初期化
-    let prometheus_metrics = Arc::new(PrometheusMetrics::new());
-
-    // Prometheus HTTPサーバーを起動
-    let metrics_clone = prometheus_metrics.clone();
-    let rt = Runtime::new().unwrap();
-    rt.spawn(async move {
-        start_prometheus_server(metrics_clone).await;
-    });
-
-    // パケットキャプチャ部分に進む
-    start_packet_capture(interface_name, ip_set, prometheus_metrics);
+// runningがfalseになるまで最大durationだけ待つ。最後まで待てたらtrueを返す
+fn sleep_while_running(running: &AtomicBool, duration: Duration) -> bool {
+    let step = Duration::from_millis(100);
+    let mut remaining = duration;
+    while !remaining.is_zero() {
+        if !running.load(Ordering::SeqCst) {
+            return false;
+        }
+        let slice = remaining.min(step);
+        thread::sleep(slice);
+        remaining -= slice;
+    }
+    running.load(Ordering::SeqCst)
 }
 
-fn start_packet_capture(
-    interface_name: &str,
-    target_ips: HashSet<IpAddr>,
+fn replay_capture_file(
+    path: &str,
+    targets: TargetNetworks,
+    filter: &CaptureFilter,
+    config: &Config,
+    running: &AtomicBool,
     prometheus_metrics: Arc<PrometheusMetrics>,
 ) {
-    // インターフェースを見つける
-    let device = Device::list()
-        .unwrap()
-        .into_iter()
-        .find(|d| d.name == *interface_name)
-        .unwrap_or_else(|| {
-            eprintln!("Interface '{}' not found", interface_name);
-            process::exit(1);
-        });
-
-    println!("Capturing on interface: {}", device.name);
-    println!("Monitoring {} IP addresses in the subnet", target_ips.len());
-    println!("version {}", version::VERSION);
-
-    // キャプチャを開始
-    let mut cap = Capture::from_device(device)
-        .unwrap()
-        .promisc(true)
-        .snaplen(65535)
-        .timeout(100) // タイムアウトを短くして応答性を向上
-        .open()
-        .unwrap();
-
-    let ip_stats = Arc::new(Mutex::new(HashMap::new()));
-    let running = Arc::new(AtomicBool::new(true));
-
-    // WAN割り当て情報を管理
-    let wan_assignments = Arc::new(Mutex::new(WanAssignments::new()));
-
-    // 起動時にWAN割り当て情報を取得
-    let rt_init = Runtime::new().unwrap();
-    rt_init.block_on(async {
-        match WanAssignments::fetch_from_api().await {
-            Ok(assignments) => {
-                let mut wan_data = wan_assignments.lock().unwrap();
-                *wan_data = assignments;
-                println!(
-                    "Initial WAN assignments loaded: wan0={} IPs, wan1={} IPs",
-                    wan_data.wan0_ips.len(),
-                    wan_data.wan1_ips.len()
-                );
-            }
-            Err(e) => {
-                eprintln!("Failed to fetch initial WAN assignments: {}", e);
-                eprintln!("Will retry in background thread...");
-            }
-        }
+    // pcap/pcapngのどちらもlibpcapが自動判別する
+    let mut cap = Capture::from_file(path).unwrap_or_else(|e| {
+        eprintln!("Failed to open capture file '{}': {}", path, e);
+        process::exit(1);
     });
-
-    // WAN割り当て情報を定期的に更新するスレッド
-    let wan_running = running.clone();
-    let wan_assignments_clone = wan_assignments.clone();
-    let rt_wan = Runtime::new().unwrap();
-    let wan_thread = thread::spawn(move || {
-        while wan_running.load(Ordering::SeqCst) {
-            // 30秒待機してから更新
-            for _ in 0..300 {
-                if !wan_running.load(Ordering::SeqCst) {
-                    return;
-                }
-                thread::sleep(Duration::from_millis(100));
-            }
-
-            rt_wan.block_on(async {
-                match WanAssignments::fetch_from_api().await {
-                    Ok(assignments) => {
-                        let mut wan_data = wan_assignments_clone.lock().unwrap();
-                        *wan_data = assignments;
-                        println!(
-                            "WAN assignments updated: wan0={} IPs, wan1={} IPs",
-                            wan_data.wan0_ips.len(),
-                            wan_data.wan1_ips.len()
-                        );
-                    }
-                    Err(e) => {
-                        eprintln!("Failed to fetch WAN assignments: {}", e);
-                    }
-                }
-            });
-        }
+    let link = LinkType::from_pcap(cap.get_datalink()).unwrap_or_else(|e| {
+        eprintln!("Cannot replay '{}': {}", path, e);
+        process::exit(1);
     });
-
-    // 統計表示用スレッド
-    let stats_running = running.clone();
-    let ip_stats_clone = Arc::clone(&ip_stats);
-    let target_ips_clone = target_ips.clone();
-    let prometheus_metrics_clone = prometheus_metrics.clone();
-    let wan_assignments_stats = wan_assignments.clone();
-    let stats_thread = thread::spawn(move || {
-        while stats_running.load(Ordering::SeqCst) {
-            thread::sleep(Duration::from_millis(100)); // より短い間隔でチェック
-            if !stats_running.load(Ordering::SeqCst) {
-                break;
-            }
-            {
-                let mut stats = ip_stats_clone.lock().unwrap();
-                calculate_bps(&mut stats);
-                let wan_data = wan_assignments_stats.lock().unwrap();
-                prometheus_metrics_clone.update_metrics(&stats, &target_ips_clone, &wan_data);
-                print_stats(&stats, &target_ips_clone);
-            }
-            // 1秒待つが、100msごとに中断チェック
-            for _ in 0..10 {
-                if !stats_running.load(Ordering::SeqCst) {
-                    break;
-                }
-                thread::sleep(Duration::from_millis(100));
-            }
+    if let Err(e) = filter.validate(link) {
+        eprintln!(
+            "Cannot set BPF filter on '{}' (link type {}): {}",
+            path, link, e
+        );
+        process::exit(1);
+    }
+    let filter = filter.expression(link);
+    if let Some(filter) = &filter {
+        if let Err(e) = cap.filter(filter, true) {
+            eprintln!("Failed to set BPF filter: {}", e);
+            process::exit(1);
         }
-    });
+    }
 
-    println!("Press Ctrl+C to stop...");
+    println!("Replaying capture file: {} (link type {})", path, link);
+    println!("Monitoring prefixes: {}", targets);
+    println!("BPF filter: {}", filter.as_deref().unwrap_or("none"));
+    println!("version {}", version::VERSION);
 
-    let mut consecutive_timeouts = 0;
-    const MAX_CONSECUTIVE_TIMEOUTS: u32 = 50; // 5秒間タイムアウトが続いたら強制チェック
+    // 最後にすべてのホストを出力するので、リプレイではホストを捨てない（フローは捨てる）
+    let limits = StatsLimits {
+        host_idle_timeout: Duration::MAX,
+        ..config.limits
+    };
+    let health = Arc::new(CaptureHealth::default());
+    let services = Arc::new(config.services.clone());
+    let mut table = HostTable::new(limits, config.accounting, services.clone(), health.clone());
+    let mut clock = PacketClock::new();
+    let mut packet_count = 0u64;
 
+    // 途中で停止シグナルを受けたら、そこまでの結果を出力する
     while running.load(Ordering::SeqCst) {
         match cap.next_packet() {
             Ok(packet) => {
-                consecutive_timeouts = 0; // パケットを受信したらリセット
-                if let Some(ethernet) = EthernetPacket::new(packet.data) {
-                    match ethernet.get_ethertype() {
-                        EtherTypes::Ipv4 => {
-                            if let Some(ipv4) = Ipv4Packet::new(ethernet.payload()) {
-                                let src_ip = IpAddr::V4(ipv4.get_source());
-                                let dst_ip = IpAddr::V4(ipv4.get_destination());
-
-                                // ソースまたはデスティネーションがターゲットIPセットに含まれている場合のみ処理
-                                if target_ips.contains(&src_ip) || target_ips.contains(&dst_ip) {
-                                    let mut stats = ip_stats.lock().unwrap();
-
-                                    // パケット全体のサイズを使用（ヘッダー + ペイロード）
-                                    let packet_size = packet.data.len() as u64;
-
-                                    // TCPパケットの場合、追加情報を解析
-                                    if ipv4.get_next_level_protocol()
-                                        == pnet::packet::ip::IpNextHeaderProtocols::Tcp
-                                    {
-                                        if let Some(tcp) = TcpPacket::new(ipv4.payload()) {
-                                            // 送信トラフィック（ソースIPがターゲットセット内）
-                                            if target_ips.contains(&src_ip) {
-                                                update_tx_stats_with_tcp(
-                                                    &mut stats,
-                                                    src_ip,
-                                                    packet_size,
-                                                    &tcp,
-                                                );
-                                            }
-
-                                            // 受信トラフィック（デスティネーションIPがターゲットセット内）
-                                            if target_ips.contains(&dst_ip) {
-                                                update_rx_stats_with_tcp(
-                                                    &mut stats,
-                                                    dst_ip,
-                                                    packet_size,
-                                                    &tcp,
-                                                );
-                                            }
-                                        }
-                                    } else {
-                                        // 非TCPパケット
-                                        if target_ips.contains(&src_ip) {
-                                            update_tx_stats(&mut stats, src_ip, packet_size);
-                                        }
-
-                                        if target_ips.contains(&dst_ip) {
-                                            update_rx_stats(&mut stats, dst_ip, packet_size);
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                        EtherTypes::Ipv6 => {
-                            if let Some(ipv6) = Ipv6Packet::new(ethernet.payload()) {
-                                let src_ip = IpAddr::V6(ipv6.get_source());
-                                let dst_ip = IpAddr::V6(ipv6.get_destination());
-
-                                // IPv6の場合も同様に処理（target_ipsには含まれないため記録されるが集計されない）
-                                if target_ips.contains(&src_ip) || target_ips.contains(&dst_ip) {
-                                    let mut stats = ip_stats.lock().unwrap();
-                                    let packet_size = packet.data.len() as u64;
-
-                                    if target_ips.contains(&src_ip) {
-                                        update_tx_stats(&mut stats, src_ip, packet_size);
-                                    }
-                                    if target_ips.contains(&dst_ip) {
-                                        update_rx_stats(&mut stats, dst_ip, packet_size);
-                                    }
-                                }
-                            }
-                        }
-                        _ => {}
-                    }
-                }
-            }
-            Err(pcap::Error::TimeoutExpired) => {
-                consecutive_timeouts += 1;
-                // タイムアウト時にrunningフラグをチェック
-                if !running.load(Ordering::SeqCst) {
-                    break;
-                }
-                // 長時間タイムアウトが続く場合も強制的にrunningフラグをチェック
-                if consecutive_timeouts >= MAX_CONSECUTIVE_TIMEOUTS {
-                    consecutive_timeouts = 0;
-                    if !running.load(Ordering::SeqCst) {
-                        break;
-                    }
+                packet_count += 1;
+                let frame = Frame {
+                    link,
+                    data: packet.data,
+                    len: packet.header.len as usize,
+                    vlan: None,
+                    ts: packet_timestamp(packet.header),
+                };
+                advance_clock(&mut clock, &mut table, frame.ts);
+                if let Err(malformed) = process_packet(&mut table, &targets, &config.decap, &frame)
+                {
+                    health.count_malformed(malformed);
                 }
-                continue;
             }
+            Err(pcap::Error::NoMorePackets) => break,
             Err(e) => {
                 eprintln!("Error reading packet: {}", e);
                 break;
@@ -854,254 +2042,285 @@ fn start_packet_capture(
         }
     }
 
-    // 統計表示スレッドの終了を待つ
-    let _ = stats_thread.join();
-    let _ = wan_thread.join();
+    println!("Replayed {} packets", packet_count);
 
     println!("\nFinal statistics:");
-    {
-        let mut final_stats = ip_stats.lock().unwrap();
-        calculate_bps(&mut final_stats);
-        let wan_data = wan_assignments.lock().unwrap();
-        prometheus_metrics.update_metrics(&final_stats, &target_ips, &wan_data);
-        print_stats(&final_stats, &target_ips);
-    }
-}
-
-fn update_tx_stats(stats: &mut HashMap<IpAddr, IpStats>, ip: IpAddr, bytes: u64) {
-    let now = Instant::now();
-    let entry = stats.entry(ip).or_insert(IpStats {
-        tx_packet_count: 0,
-        rx_packet_count: 0,
-        tx_byte_count: 0,
-        rx_byte_count: 0,
-        tx_last_bytes: 0,
-        rx_last_bytes: 0,
-        last_time: now,
-        tx_current_bps: 0.0,
-        rx_current_bps: 0.0,
-        tx_bytes_per_sec: 0,
-        rx_bytes_per_sec: 0,
-        expected_seq: HashMap::new(),
-        retransmissions: 0,
-        duplicate_acks: 0,
-        last_retransmissions: 0,
-        last_duplicate_acks: 0,
-        retransmissions_per_sec: 0,
-        duplicate_acks_per_sec: 0,
-        last_window_size: HashMap::new(),
-        window_size_changes: 0,
-        last_window_size_changes: 0,
-        window_size_changes_per_sec: 0,
-    });
-
-    entry.tx_packet_count += 1;
-    entry.tx_byte_count += bytes;
-}
-
-fn update_rx_stats(stats: &mut HashMap<IpAddr, IpStats>, ip: IpAddr, bytes: u64) {
-    let now = Instant::now();
-    let entry = stats.entry(ip).or_insert(IpStats {
-        tx_packet_count: 0,
-        rx_packet_count: 0,
-        tx_byte_count: 0,
-        rx_byte_count: 0,
-        tx_last_bytes: 0,
-        rx_last_bytes: 0,
-        last_time: now,
-        tx_current_bps: 0.0,
-        rx_current_bps: 0.0,
-        tx_bytes_per_sec: 0,
-        rx_bytes_per_sec: 0,
-        expected_seq: HashMap::new(),
-        retransmissions: 0,
-        duplicate_acks: 0,
-        last_retransmissions: 0,
-        last_duplicate_acks: 0,
-        retransmissions_per_sec: 0,
-        duplicate_acks_per_sec: 0,
-        last_window_size: HashMap::new(),
-        window_size_changes: 0,
-        last_window_size_changes: 0,
-        window_size_changes_per_sec: 0,
-    });
+    if let Some(boundary) = clock.finish() {
+        table.calculate_rates(&[boundary]);
+    }
+    // リプレイではWAN APIに問い合わせず、すべてwan0として扱う
+    // interfaceラベルにはファイル名を使う
+    let label = Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_string());
+    let published = Arc::new(PublishedStats::default());
+    published.deltas.publish(table.take_delta());
+    // ファイルからの読み込みではpcap_statsは使えないので、健全性カウンタはmalformedだけが意味を持つ
+    let state = InterfaceCapture {
+        name: label.clone(),
+        role: "lan".to_string(),
+        shards: Arc::new(vec![published]),
+        up: Arc::new(AtomicBool::new(true)),
+        health: health.clone(),
+        backend: CaptureBackend::Pcap,
+        buffer_size: None,
+        limits,
+        accounting: config.accounting,
+        services,
+    };
+    let final_stats = state.merged_stats();
+    let scrape_targets = targets.clone();
+    prometheus_metrics.attach(
+        vec![state],
+        Box::new(move |_| scrape_targets.clone()),
+        Arc::new(Mutex::new(WanAssignments::new())),
+    );
+    print_header();
+    print_stats(
+        &label,
+        "lan",
+        true,
+        &final_stats.hosts,
+        &targets,
+        &health,
+        config.tui_protocols,
+    );
 
-    entry.rx_packet_count += 1;
-    entry.rx_byte_count += bytes;
+    println!("\nFinal Prometheus snapshot:");
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder
+        .encode(&prometheus_metrics.registry.gather(), &mut buffer)
+        .unwrap();
+    print!("{}", String::from_utf8_lossy(&buffer));
+
+    if let Some(summary_path) = &config.summary_file {
+        let section = summary::InterfaceStats {
+            name: &label,
+            role: "lan",
+            targets: &targets,
+            stats: &final_stats.hosts,
+            services: &config.services,
+            capture: health.snapshot(),
+        };
+        save_summary(summary_path, &[section]);
+    }
 }
 
-fn update_tx_stats_with_tcp(
-    stats: &mut HashMap<IpAddr, IpStats>,
-    ip: IpAddr,
+fn update_tx_stats(
+    table: &mut HostTable,
+    key: HostKey,
+    protocol: Protocol,
+    ports: Option<Ports>,
     bytes: u64,
-    tcp: &TcpPacket,
+    tunnel_overhead: u64,
+    now: Duration,
 ) {
-    let now = Instant::now();
-    let entry = stats.entry(ip).or_insert(IpStats {
-        tx_packet_count: 0,
-        rx_packet_count: 0,
-        tx_byte_count: 0,
-        rx_byte_count: 0,
-        tx_last_bytes: 0,
-        rx_last_bytes: 0,
-        last_time: now,
-        tx_current_bps: 0.0,
-        rx_current_bps: 0.0,
-        tx_bytes_per_sec: 0,
-        rx_bytes_per_sec: 0,
-        expected_seq: HashMap::new(),
-        retransmissions: 0,
-        duplicate_acks: 0,
-        last_retransmissions: 0,
-        last_duplicate_acks: 0,
-        retransmissions_per_sec: 0,
-        duplicate_acks_per_sec: 0,
-        last_window_size: HashMap::new(),
-        window_size_changes: 0,
-        last_window_size_changes: 0,
-        window_size_changes_per_sec: 0,
+    let classified = ports.map(|ports| {
+        let service = table.services.classify(protocol, ports.dst, ports.src);
+        (ports.dst, service)
     });
+    let max_ports = table.limits.max_ports;
+    let Some(entry) = table.host_mut(key, now) else {
+        return;
+    };
 
     entry.tx_packet_count += 1;
     entry.tx_byte_count += bytes;
-
-    let src_port = tcp.get_source();
-    let seq_num = tcp.get_sequence();
-    let _ack_num = tcp.get_acknowledgement();
-    let window_size = tcp.get_window();
-
-    // パケットロス検出（簡易版）
-    if let Some(&expected) = entry.expected_seq.get(&src_port) {
-        if seq_num < expected {
-            // 再送パケットの可能性
-            entry.retransmissions += 1;
-        }
-    }
-
-    // 期待シーケンス番号の更新
-    let payload_len = tcp.payload().len() as u32;
-    if payload_len > 0
-        || (tcp.get_flags() & TcpFlags::SYN) != 0
-        || (tcp.get_flags() & TcpFlags::FIN) != 0
-    {
-        entry
-            .expected_seq
-            .insert(src_port, seq_num + payload_len + 1);
+    entry.tx_tunnel_overhead_bytes += tunnel_overhead;
+
+    entry.protocols[protocol as usize].count_tx(bytes);
+    if let Some((remote_port, service)) = classified {
+        let remote = RemotePort {
+            protocol,
+            port: Some(remote_port),
+        };
+        entry.port_traffic(remote, max_ports).count_tx(bytes);
+        Arc::make_mut(&mut entry.services)
+            .entry(service)
+            .or_default()
+            .count_tx(bytes);
     }
-
-    // ウィンドウサイズ変更の検出
-    if let Some(&last_window) = entry.last_window_size.get(&src_port) {
-        if window_size != last_window {
-            entry.window_size_changes += 1;
-        }
-    }
-    entry.last_window_size.insert(src_port, window_size);
 }
 
-fn update_rx_stats_with_tcp(
-    stats: &mut HashMap<IpAddr, IpStats>,
-    ip: IpAddr,
+fn update_rx_stats(
+    table: &mut HostTable,
+    key: HostKey,
+    protocol: Protocol,
+    ports: Option<Ports>,
     bytes: u64,
-    tcp: &TcpPacket,
+    tunnel_overhead: u64,
+    now: Duration,
 ) {
-    let now = Instant::now();
-    let entry = stats.entry(ip).or_insert(IpStats {
-        tx_packet_count: 0,
-        rx_packet_count: 0,
-        tx_byte_count: 0,
-        rx_byte_count: 0,
-        tx_last_bytes: 0,
-        rx_last_bytes: 0,
-        last_time: now,
-        tx_current_bps: 0.0,
-        rx_current_bps: 0.0,
-        tx_bytes_per_sec: 0,
-        rx_bytes_per_sec: 0,
-        expected_seq: HashMap::new(),
-        retransmissions: 0,
-        duplicate_acks: 0,
-        last_retransmissions: 0,
-        last_duplicate_acks: 0,
-        retransmissions_per_sec: 0,
-        duplicate_acks_per_sec: 0,
-        last_window_size: HashMap::new(),
-        window_size_changes: 0,
-        last_window_size_changes: 0,
-        window_size_changes_per_sec: 0,
+    let classified = ports.map(|ports| {
+        let service = table.services.classify(protocol, ports.src, ports.dst);
+        (ports.src, service)
     });
+    let max_ports = table.limits.max_ports;
+    let Some(entry) = table.host_mut(key, now) else {
+        return;
+    };
 
     entry.rx_packet_count += 1;
     entry.rx_byte_count += bytes;
-
-    let dst_port = tcp.get_destination();
-    let _ack_num = tcp.get_acknowledgement();
-    let window_size = tcp.get_window();
-
-    // 重複ACKの検出（簡易版）
-    if (tcp.get_flags() & TcpFlags::ACK) != 0 && tcp.payload().is_empty() {
-        // 同じACK番号が連続して来た場合は重複ACKとみなす
-        entry.duplicate_acks += 1;
+    entry.rx_tunnel_overhead_bytes += tunnel_overhead;
+
+    entry.protocols[protocol as usize].count_rx(bytes);
+    if let Some((remote_port, service)) = classified {
+        let remote = RemotePort {
+            protocol,
+            port: Some(remote_port),
+        };
+        entry.port_traffic(remote, max_ports).count_rx(bytes);
+        Arc::make_mut(&mut entry.services)
+            .entry(service)
+            .or_default()
+            .count_rx(bytes);
     }
-
-    // ウィンドウサイズ変更の検出
-    if let Some(&last_window) = entry.last_window_size.get(&dst_port) {
-        if window_size != last_window {
-            entry.window_size_changes += 1;
-        }
-    }
-    entry.last_window_size.insert(dst_port, window_size);
 }
 
-fn calculate_bps(stats: &mut HashMap<IpAddr, IpStats>) {
-    let now = Instant::now();
-
-    for (_, stat) in stats.iter_mut() {
-        let time_diff = now.duration_since(stat.last_time).as_secs_f64();
-
-        // 最低0.1秒経過していれば計算（より頻繁に更新）
-        if time_diff >= 0.1 {
-            let tx_bytes_diff = stat.tx_byte_count.saturating_sub(stat.tx_last_bytes);
-            let rx_bytes_diff = stat.rx_byte_count.saturating_sub(stat.rx_last_bytes);
-
-            // バイトをビットに変換してからビット/秒を計算
-            stat.tx_current_bps = (tx_bytes_diff as f64 * 8.0) / time_diff;
-            stat.rx_current_bps = (rx_bytes_diff as f64 * 8.0) / time_diff;
+fn observe_outbound_tcp(
+    table: &mut HostTable,
+    key: HostKey,
+    remote_ip: IpAddr,
+    tcp: &TcpPacket,
+    now: Duration,
+) {
+    // 4タプル単位のフロー状態で再送・順序入れ替わり・ウィンドウ変更を判定
+    let flow = FlowKey::outbound(key.ip, remote_ip, tcp);
+    table.observe_flow(key, flow, now, |state| state.on_outbound(tcp, now));
+}
 
-            // 1秒あたりのバイト数に正規化
-            stat.tx_bytes_per_sec = (tx_bytes_diff as f64 / time_diff) as u64;
-            stat.rx_bytes_per_sec = (rx_bytes_diff as f64 / time_diff) as u64;
+fn observe_inbound_tcp(
+    table: &mut HostTable,
+    key: HostKey,
+    remote_ip: IpAddr,
+    tcp: &TcpPacket,
+    now: Duration,
+) {
+    let flow = FlowKey::inbound(remote_ip, key.ip, tcp);
+    table.observe_flow(key, flow, now, |state| state.on_inbound(tcp, now));
+}
 
-            // パケットロスの1秒あたりの値を計算
-            let retrans_diff = stat
-                .retransmissions
-                .saturating_sub(stat.last_retransmissions);
-            let dup_ack_diff = stat.duplicate_acks.saturating_sub(stat.last_duplicate_acks);
-            let win_chg_diff = stat
-                .window_size_changes
-                .saturating_sub(stat.last_window_size_changes);
+fn calculate_bps(stat: &mut IpStats, now: Duration) {
+    let time_diff = now.saturating_sub(stat.last_time).as_secs_f64();
+
+    // 最低0.1秒経過していれば計算（より頻繁に更新）
+    if time_diff >= 0.1 {
+        let active = stat.has_rates()
+            || stat.tx_packet_count != stat.tx_last_packets
+            || stat.rx_packet_count != stat.rx_last_packets;
+        let tx_bytes_diff = stat.tx_byte_count.saturating_sub(stat.tx_last_bytes);
+        let rx_bytes_diff = stat.rx_byte_count.saturating_sub(stat.rx_last_bytes);
+
+        // バイトをビットに変換してからビット/秒を計算
+        stat.tx_current_bps = (tx_bytes_diff as f64 * 8.0) / time_diff;
+        stat.rx_current_bps = (rx_bytes_diff as f64 * 8.0) / time_diff;
+
+        // 1秒あたりのバイト数に正規化
+        stat.tx_bytes_per_sec = (tx_bytes_diff as f64 / time_diff) as u64;
+        stat.rx_bytes_per_sec = (rx_bytes_diff as f64 / time_diff) as u64;
+
+        // 1秒あたりのパケット数
+        let tx_packets_diff = stat.tx_packet_count.saturating_sub(stat.tx_last_packets);
+        let rx_packets_diff = stat.rx_packet_count.saturating_sub(stat.rx_last_packets);
+        stat.tx_packets_per_sec = (tx_packets_diff as f64 / time_diff) as u64;
+        stat.rx_packets_per_sec = (rx_packets_diff as f64 / time_diff) as u64;
+        for traffic in stat.protocols.iter_mut() {
+            traffic.calculate_bps(time_diff);
+        }
+        // 前回も今回もトラフィックがなければ内訳のレートは0のままなので、スナップショットと共有したままにする
+        if active {
+            for traffic in Arc::make_mut(&mut stat.ports)
+                .values_mut()
+                .chain(Arc::make_mut(&mut stat.services).values_mut())
+            {
+                traffic.calculate_bps(time_diff);
+            }
+        }
 
-            stat.retransmissions_per_sec = (retrans_diff as f64 / time_diff) as u64;
-            stat.duplicate_acks_per_sec = (dup_ack_diff as f64 / time_diff) as u64;
-            stat.window_size_changes_per_sec = (win_chg_diff as f64 / time_diff) as u64;
+        // パケットロスの1秒あたりの値を計算
+        let retrans_diff = stat
+            .retransmissions
+            .saturating_sub(stat.last_retransmissions);
+        let spurious_diff = stat
+            .spurious_retransmissions
+            .saturating_sub(stat.last_spurious_retransmissions);
+        let ooo_diff = stat.out_of_order.saturating_sub(stat.last_out_of_order);
+        let dup_ack_diff = stat.duplicate_acks.saturating_sub(stat.last_duplicate_acks);
+        let triple_dup_ack_diff = stat
+            .triple_duplicate_acks
+            .saturating_sub(stat.last_triple_duplicate_acks);
+        let win_chg_diff = stat
+            .window_size_changes
+            .saturating_sub(stat.last_window_size_changes);
+
+        stat.retransmissions_per_sec = (retrans_diff as f64 / time_diff) as u64;
+        stat.spurious_retransmissions_per_sec = (spurious_diff as f64 / time_diff) as u64;
+        stat.out_of_order_per_sec = (ooo_diff as f64 / time_diff) as u64;
+        stat.duplicate_acks_per_sec = (dup_ack_diff as f64 / time_diff) as u64;
+        stat.triple_duplicate_acks_per_sec = (triple_dup_ack_diff as f64 / time_diff) as u64;
+        stat.window_size_changes_per_sec = (win_chg_diff as f64 / time_diff) as u64;
+
+        stat.tx_last_bytes = stat.tx_byte_count;
+        stat.rx_last_bytes = stat.rx_byte_count;
+        stat.tx_last_packets = stat.tx_packet_count;
+        stat.rx_last_packets = stat.rx_packet_count;
+        stat.last_retransmissions = stat.retransmissions;
+        stat.last_spurious_retransmissions = stat.spurious_retransmissions;
+        stat.last_out_of_order = stat.out_of_order;
+        stat.last_duplicate_acks = stat.duplicate_acks;
+        stat.last_triple_duplicate_acks = stat.triple_duplicate_acks;
+        stat.last_window_size_changes = stat.window_size_changes;
+        stat.last_time = now;
+    }
 
-            stat.tx_last_bytes = stat.tx_byte_count;
-            stat.rx_last_bytes = stat.rx_byte_count;
-            stat.last_retransmissions = stat.retransmissions;
-            stat.last_duplicate_acks = stat.duplicate_acks;
-            stat.last_window_size_changes = stat.window_size_changes;
-            stat.last_time = now;
+    // 古いRTTサンプルを捨ててから集計
+    while let Some(&(measured_at, _)) = stat.rtt_samples.front() {
+        if now.saturating_sub(measured_at) <= RTT_WINDOW {
+            break;
         }
+        stat.rtt_samples.pop_front();
     }
+    stat.calculate_rtt();
 }
 
-fn print_stats(stats: &HashMap<IpAddr, IpStats>, target_ips: &HashSet<IpAddr>) {
+fn print_header() {
     // Clear screen and move cursor to top
     print!("\x1B[2J\x1B[1;1H");
 
     println!("=== Subnet Network Traffic Monitor ===");
+}
+
+fn print_stats(
+    interface: &str,
+    role: &str,
+    up: bool,
+    stats: &HashMap<HostKey, Arc<IpStats>>,
+    targets: &TargetNetworks,
+    health: &CaptureHealth,
+    protocols: bool,
+) {
+    let state = if up { "" } else { " DOWN (reopening)" };
+    let capture = health.snapshot();
+    println!(
+        "\n[{} ({})]{} dropped: {} | if_dropped: {} | malformed: {}",
+        interface, role, state, capture.dropped, capture.if_dropped, capture.malformed
+    );
     println!(
-        "{:<30} {:>10} {:>10} {:>10} {:>10} {:>6} {:>6} {:>6}",
-        "IP Address", "TX/s", "RX/s", "↑ Up", "↓ Down", "PLoss/s", "DupAck/s", "WinChg/s"
+        "{:<30} {:>10} {:>10} {:>10} {:>10} {:>6} {:>6} {:>6} {:>6} {:>6} {:>8}",
+        "IP Address",
+        "TX/s",
+        "RX/s",
+        "↑ Up",
+        "↓ Down",
+        "PLoss/s",
+        "Spur/s",
+        "OOO/s",
+        "DupAck/s",
+        "WinChg/s",
+        "RTT(ms)"
     );
     println!("{:-<120}", "");
 
@@ -1117,38 +2336,73 @@ fn print_stats(stats: &HashMap<IpAddr, IpStats>, target_ips: &HashSet<IpAddr>) {
     if sorted_stats.is_empty() {
         println!("No traffic detected from monitored subnet IPs...");
     } else {
-        for (ip, stat) in sorted_stats.iter().take(20) {
-            let is_subnet_ip = target_ips.contains(ip);
+        for (key, stat) in sorted_stats.iter().take(20) {
+            let is_subnet_ip = key.is_target(targets);
             let ip_prefix = if is_subnet_ip { "" } else { "*" };
 
             println!(
-                "{}{:<29} {:>10} {:>10} {:>10} {:>10} {:>6} {:>6} {:>6}",
+                "{}{:<29} {:>10} {:>10} {:>10} {:>10} {:>6} {:>6} {:>6} {:>6} {:>6} {:>8}",
                 ip_prefix,
-                ip.to_string(),
+                host_label(key),
                 format_bytes_short(stat.tx_bytes_per_sec),
                 format_bytes_short(stat.rx_bytes_per_sec),
                 format_bps_short(stat.tx_current_bps),
                 format_bps_short(stat.rx_current_bps),
                 stat.retransmissions_per_sec,
+                stat.spurious_retransmissions_per_sec,
+                stat.out_of_order_per_sec,
                 stat.duplicate_acks_per_sec,
-                stat.window_size_changes_per_sec
+                stat.window_size_changes_per_sec,
+                stat.rtt
+                    .map(|rtt| format!("{:.1}", rtt.mean * 1000.0))
+                    .unwrap_or_else(|| "-".to_string())
             );
+            if protocols {
+                print_protocol_split(stat);
+            }
         }
     }
 
     let subnet_ips_with_traffic = sorted_stats
         .iter()
-        .filter(|(ip, _)| target_ips.contains(ip))
+        .filter(|(key, _)| key.is_target(targets))
         .count();
     let external_ips_with_traffic = sorted_stats.len() - subnet_ips_with_traffic;
     println!(
-        "Subnet IPs: {} | External IPs: {} (*) | Total subnet: {}",
-        subnet_ips_with_traffic,
-        external_ips_with_traffic,
-        target_ips.len()
+        "Subnet IPs: {} | External IPs: {} (*) | Monitored: {}",
+        subnet_ips_with_traffic, external_ips_with_traffic, targets
     );
 }
 
+// IPの行の下に、パケットのあったプロトコルごとの送受信ビット/秒を表示する
+fn print_protocol_split(stat: &IpStats) {
+    let split: Vec<String> = Protocol::ALL
+        .iter()
+        .zip(&stat.protocols)
+        .filter(|(_, counts)| !counts.is_empty())
+        .map(|(protocol, counts)| {
+            format!(
+                "{} ↑{} ↓{}",
+                protocol.label(),
+                format_bps_short(counts.tx_bps),
+                format_bps_short(counts.rx_bps)
+            )
+        })
+        .collect();
+    if !split.is_empty() {
+        println!("{:<30} {}", "", split.join(" | "));
+    }
+}
+
+// タグ付きのフレームで見えたアドレスは「IP@VLAN」と表示する
+fn host_label(key: &HostKey) -> String {
+    if key.vlan.is_tagged() {
+        format!("{}@{}", key.ip, key.vlan)
+    } else {
+        key.ip.to_string()
+    }
+}
+
 fn format_bps_short(bps: f64) -> String {
     if bps >= 1_000_000_000.0 {
         format!("{:.1}G", bps / 1_000_000_000.0)
@@ -1173,12 +2427,18 @@ fn format_bytes_short(bytes: u64) -> String {
     }
 }
 
-async fn start_prometheus_server(metrics: Arc<PrometheusMetrics>) {
+async fn start_prometheus_server(
+    metrics: Arc<PrometheusMetrics>,
+    addr: SocketAddr,
+    reloader: Arc<Reloader>,
+) {
     let make_svc = make_service_fn(move |_conn| {
         let metrics = metrics.clone();
+        let reloader = reloader.clone();
         async move {
             Ok::<_, hyper::Error>(service_fn(move |req| {
                 let metrics = metrics.clone();
+                let reloader = reloader.clone();
                 async move {
                     match req.uri().path() {
                         "/metrics" => {
@@ -1188,6 +2448,46 @@ async fn start_prometheus_server(metrics: Arc<PrometheusMetrics>) {
                             encoder.encode(&metric_families, &mut buffer).unwrap();
                             Ok::<_, hyper::Error>(Response::new(Body::from(buffer)))
                         }
+                        // 設定の再読み込み（SIGHUPと同じ処理）
+                        "/-/reload" if req.method() == Method::POST => {
+                            let result = tokio::task::spawn_blocking(move || reloader.reload())
+                                .await
+                                .unwrap_or_else(|e| Err(e.to_string()));
+                            let (status, body) = match result {
+                                Ok(message) => {
+                                    println!("Admin API: {}", message);
+                                    (StatusCode::OK, message)
+                                }
+                                Err(e) => {
+                                    eprintln!(
+                                        "Admin API: reload rejected, keeping previous config: {}",
+                                        e
+                                    );
+                                    (StatusCode::BAD_REQUEST, e)
+                                }
+                            };
+                            let response = Response::builder()
+                                .status(status)
+                                .body(Body::from(body + "\n"))
+                                .unwrap();
+                            Ok(response)
+                        }
+                        // 現在キャプチャに設定しているBPFフィルタ（インターフェース名 → 式）
+                        "/-/filters" if req.method() == Method::GET => {
+                            let body = serde_json::to_string_pretty(&reloader.filters()).unwrap();
+                            let response = Response::builder()
+                                .header("Content-Type", "application/json")
+                                .body(Body::from(body + "\n"))
+                                .unwrap();
+                            Ok(response)
+                        }
+                        "/-/reload" => {
+                            let response = Response::builder()
+                                .status(StatusCode::METHOD_NOT_ALLOWED)
+                                .body(Body::from("Use POST to reload the configuration\n"))
+                                .unwrap();
+                            Ok(response)
+                        }
                         _ => {
                             let response = Response::builder()
                                 .status(404)
@@ -1201,8 +2501,13 @@ async fn start_prometheus_server(metrics: Arc<PrometheusMetrics>) {
         }
     });
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], 59122));
-    let server = Server::bind(&addr).serve(make_svc);
+    let server = match Server::try_bind(&addr) {
+        Ok(builder) => builder.serve(make_svc),
+        Err(e) => {
+            eprintln!("Failed to bind metrics server on {}: {}", addr, e);
+            return;
+        }
+    };
 
     println!(
         "Prometheus metrics server listening on http://{}/metrics",
@@ -1213,3 +2518,171 @@ async fn start_prometheus_server(metrics: Arc<PrometheusMetrics>) {
         eprintln!("Server error: {}", e);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: f64) -> Duration {
+        Duration::from_secs_f64(secs)
+    }
+
+    #[test]
+    fn first_packet_opens_a_bucket_without_closing_one() {
+        let mut clock = PacketClock::new();
+        assert!(clock.advance(secs(100.25)).is_empty());
+        assert!(clock.advance(secs(100.999)).is_empty());
+        assert_eq!(clock.finish(), Some(secs(101.0)));
+    }
+
+    #[test]
+    fn closes_the_bucket_at_the_next_whole_second() {
+        let mut clock = PacketClock::new();
+        clock.advance(secs(100.5));
+        // 境界ちょうどのパケットは次のバケットに入る
+        assert_eq!(clock.advance(secs(101.0)), vec![secs(101.0)]);
+        assert!(clock.advance(secs(101.5)).is_empty());
+        assert_eq!(clock.advance(secs(102.2)), vec![secs(102.0)]);
+    }
+
+    #[test]
+    fn closes_every_empty_bucket_in_a_gap() {
+        let mut clock = PacketClock::new();
+        clock.advance(secs(100.5));
+        assert_eq!(
+            clock.advance(secs(103.5)),
+            vec![secs(101.0), secs(102.0), secs(103.0)]
+        );
+        assert_eq!(clock.finish(), Some(secs(104.0)));
+    }
+
+    #[test]
+    fn long_gaps_close_only_the_first_and_last_bucket() {
+        let mut clock = PacketClock::new();
+        clock.advance(secs(100.5));
+        let gap = PacketClock::MAX_EMPTY_BUCKETS as f64 + 10.0;
+        assert_eq!(
+            clock.advance(secs(100.5 + gap)),
+            vec![secs(101.0), secs(100.0 + gap)]
+        );
+        assert!(clock.advance(secs(100.9 + gap)).is_empty());
+    }
+
+    #[test]
+    fn finish_starts_over_with_the_next_packet() {
+        let mut clock = PacketClock::new();
+        clock.advance(secs(100.5));
+        assert_eq!(clock.finish(), Some(secs(101.0)));
+        assert_eq!(clock.finish(), None);
+        assert!(clock.advance(secs(205.0)).is_empty());
+        assert_eq!(clock.advance(secs(206.0)), vec![secs(206.0)]);
+    }
+
+    fn host(ip: &str) -> HostKey {
+        HostKey {
+            ip: ip.parse().unwrap(),
+            vlan: VlanTags::default(),
+        }
+    }
+
+    // パケットを数えるときと同じように、バケットを進めてから1500バイトの送信を数える
+    fn send(table: &mut HostTable, clock: &mut PacketClock, key: HostKey, ts: Duration) {
+        advance_clock(clock, table, ts);
+        table.host_mut(key, ts).unwrap().tx_byte_count += 1500;
+    }
+
+    fn table() -> HostTable {
+        HostTable::new(
+            StatsLimits::default(),
+            Accounting::default(),
+            Arc::new(ServiceMap::default()),
+            Arc::new(CaptureHealth::default()),
+        )
+    }
+
+    #[test]
+    fn deltas_carry_only_the_hosts_that_changed() {
+        let (mut table, mut clock) = (table(), PacketClock::new());
+        let published = PublishedStats::default();
+        let (busy, idle) = (host("10.0.0.1"), host("10.0.0.2"));
+        send(&mut table, &mut clock, busy, secs(100.5));
+        send(&mut table, &mut clock, idle, secs(100.5));
+        send(&mut table, &mut clock, busy, secs(101.5));
+        let delta = table.take_delta();
+        assert_eq!(delta.hosts.len(), 2);
+        published.deltas.publish(delta);
+        assert_eq!(published.load().hosts[&idle].tx_bytes_per_sec, 1500);
+
+        // レートが0に戻ったホストはもう一度渡す
+        send(&mut table, &mut clock, busy, secs(102.5));
+        let delta = table.take_delta();
+        assert_eq!(delta.hosts.len(), 2);
+        published.deltas.publish(delta);
+        assert_eq!(published.load().hosts[&idle].tx_bytes_per_sec, 0);
+
+        // 何も変わらなければ渡さず、統計スレッド側の値がそのまま残る
+        send(&mut table, &mut clock, busy, secs(103.5));
+        let delta = table.take_delta();
+        assert!(!delta.hosts.contains_key(&idle));
+        published.deltas.publish(delta);
+        let folded = published.load();
+        assert_eq!(folded.hosts[&idle].tx_byte_count, 1500);
+        assert_eq!(folded.hosts[&busy].tx_byte_count, 4 * 1500);
+        assert_eq!(folded.hosts[&busy].tx_bytes_per_sec, 1500);
+
+        // 捨てたホストは統計スレッド側からも消える
+        send(&mut table, &mut clock, busy, secs(400.5));
+        published.deltas.publish(table.take_delta());
+        let folded = published.load();
+        assert!(!folded.hosts.contains_key(&idle));
+        assert!(folded.hosts.contains_key(&busy));
+        assert_eq!(folded.retired.tx_bytes, 1500);
+    }
+
+    #[test]
+    fn a_host_that_was_left_alone_counts_from_the_start_of_its_bucket() {
+        let (mut table, mut clock) = (table(), PacketClock::new());
+        let key = host("10.0.0.1");
+        send(&mut table, &mut clock, key, secs(100.5));
+        send(&mut table, &mut clock, key, secs(101.5));
+        send(&mut table, &mut clock, key, secs(110.5));
+        advance_clock(&mut clock, &mut table, secs(111.5));
+        assert_eq!(table.hosts[&key].tx_bytes_per_sec, 1500);
+    }
+
+    #[test]
+    fn idle_checks_are_limited_per_bucket() {
+        let (mut table, mut clock) = (table(), PacketClock::new());
+        let hosts = IDLE_CHECKS_PER_BUCKET + 10;
+        advance_clock(&mut clock, &mut table, secs(100.5));
+        for index in 0..hosts {
+            let key = HostKey {
+                ip: IpAddr::from([10, 0, (index >> 8) as u8, index as u8]),
+                vlan: VlanTags::default(),
+            };
+            table.host_mut(key, secs(100.5));
+        }
+        advance_clock(&mut clock, &mut table, secs(1000.5));
+        assert_eq!(table.hosts.len(), 10);
+        advance_clock(&mut clock, &mut table, secs(1001.5));
+        assert!(table.hosts.is_empty());
+        assert_eq!(table.take_delta().hosts.len(), hosts);
+    }
+
+    #[test]
+    fn snapshots_share_the_port_breakdown_until_it_is_written() {
+        let mut stat = IpStats::new(secs(100.0));
+        let remote = RemotePort {
+            protocol: Protocol::Tcp,
+            port: Some(443),
+        };
+        stat.port_traffic(remote, 64).count_tx(1500);
+        let snapshot = stat.snapshot();
+        assert!(Arc::ptr_eq(&stat.ports, &snapshot.ports));
+
+        stat.port_traffic(remote, 64).count_tx(1500);
+        assert!(!Arc::ptr_eq(&stat.ports, &snapshot.ports));
+        assert_eq!(snapshot.ports[&remote].tx_bytes, 1500);
+        assert_eq!(stat.ports[&remote].tx_bytes, 3000);
+    }
+}
@@ -0,0 +1,1000 @@
+use crate::health::{Malformed, Untracked};
+use crate::protocol::Protocol;
+use crate::targets::TargetNetworks;
+use crate::traffic::TrafficStats;
+use crate::{InterfaceCapture, RttSummary, WanAssignments};
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::{Counter, Gauge, LabelPair, Metric, MetricFamily, MetricType};
+use prometheus::Registry;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, RwLock};
+
+const INTERFACE: &[&str] = &["interface"];
+const HOST: &[&str] = &["interface", "vlan", "ip_address"];
+const HOST_PROTOCOL: &[&str] = &["interface", "vlan", "ip_address", "protocol"];
+const HOST_PORT: &[&str] = &["interface", "vlan", "ip_address", "protocol", "port"];
+const HOST_SERVICE: &[&str] = &["interface", "vlan", "ip_address", "service"];
+const NIC: &[&str] = &["interface", "nic"];
+
+// 出力するメトリクスの名前、説明、種類、ラベル
+struct MetricSpec {
+    name: &'static str,
+    help: &'static str,
+    kind: MetricType,
+    labels: &'static [&'static str],
+}
+
+const fn counter(
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+) -> MetricSpec {
+    MetricSpec {
+        name,
+        help,
+        kind: MetricType::COUNTER,
+        labels,
+    }
+}
+
+const fn gauge(
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+) -> MetricSpec {
+    MetricSpec {
+        name,
+        help,
+        kind: MetricType::GAUGE,
+        labels,
+    }
+}
+
+// 全体のメトリクス
+const TX_BYTES_TOTAL: MetricSpec = counter(
+    "network_tx_bytes_total",
+    "Total transmitted bytes",
+    INTERFACE,
+);
+const RX_BYTES_TOTAL: MetricSpec =
+    counter("network_rx_bytes_total", "Total received bytes", INTERFACE);
+const TX_BYTES_PER_SEC: MetricSpec = gauge(
+    "network_tx_bytes_per_sec",
+    "Transmitted bytes per second",
+    INTERFACE,
+);
+const RX_BYTES_PER_SEC: MetricSpec = gauge(
+    "network_rx_bytes_per_sec",
+    "Received bytes per second",
+    INTERFACE,
+);
+const TX_PACKETS_TOTAL: MetricSpec = counter(
+    "network_tx_packets_total",
+    "Total transmitted packets",
+    INTERFACE,
+);
+const RX_PACKETS_TOTAL: MetricSpec = counter(
+    "network_rx_packets_total",
+    "Total received packets",
+    INTERFACE,
+);
+const TX_PACKETS_PER_SEC: MetricSpec = gauge(
+    "network_tx_packets_per_sec",
+    "Transmitted packets per second",
+    INTERFACE,
+);
+const RX_PACKETS_PER_SEC: MetricSpec = gauge(
+    "network_rx_packets_per_sec",
+    "Received packets per second",
+    INTERFACE,
+);
+const TX_BPS: MetricSpec = gauge("network_tx_bps", "Transmitted bits per second", INTERFACE);
+const RX_BPS: MetricSpec = gauge("network_rx_bps", "Received bits per second", INTERFACE);
+const RETRANSMISSIONS_PER_SEC: MetricSpec = gauge(
+    "network_retransmissions_per_sec",
+    "Retransmissions per second",
+    INTERFACE,
+);
+const SPURIOUS_RETRANSMISSIONS_PER_SEC: MetricSpec = gauge(
+    "network_spurious_retransmissions_per_sec",
+    "Spurious retransmissions (already acknowledged data) per second",
+    INTERFACE,
+);
+const OUT_OF_ORDER_PER_SEC: MetricSpec = gauge(
+    "network_out_of_order_per_sec",
+    "Out-of-order TCP segments per second",
+    INTERFACE,
+);
+const DUPLICATE_ACKS_PER_SEC: MetricSpec = gauge(
+    "network_duplicate_acks_per_sec",
+    "Duplicate ACKs per second",
+    INTERFACE,
+);
+const TRIPLE_DUPLICATE_ACKS_PER_SEC: MetricSpec = gauge(
+    "network_triple_duplicate_acks_per_sec",
+    "Triple duplicate ACK (fast retransmit trigger) events per second",
+    INTERFACE,
+);
+const WINDOW_SIZE_CHANGES_PER_SEC: MetricSpec = gauge(
+    "network_window_size_changes_per_sec",
+    "Window size changes per second",
+    INTERFACE,
+);
+
+// IPごとのメトリクス
+const IP_TX_BYTES_TOTAL: MetricSpec = counter(
+    "network_ip_tx_bytes_total",
+    "Total transmitted bytes per IP",
+    HOST,
+);
+const IP_RX_BYTES_TOTAL: MetricSpec = counter(
+    "network_ip_rx_bytes_total",
+    "Total received bytes per IP",
+    HOST,
+);
+const IP_TX_PACKETS_TOTAL: MetricSpec = counter(
+    "network_ip_tx_packets_total",
+    "Total transmitted packets per IP",
+    HOST,
+);
+const IP_RX_PACKETS_TOTAL: MetricSpec = counter(
+    "network_ip_rx_packets_total",
+    "Total received packets per IP",
+    HOST,
+);
+const IP_TX_TUNNEL_OVERHEAD_BYTES_TOTAL: MetricSpec = counter(
+    "network_ip_tx_tunnel_overhead_bytes_total",
+    "Transmitted bytes per IP spent on decapsulated tunnel headers",
+    HOST,
+);
+const IP_RX_TUNNEL_OVERHEAD_BYTES_TOTAL: MetricSpec = counter(
+    "network_ip_rx_tunnel_overhead_bytes_total",
+    "Received bytes per IP spent on decapsulated tunnel headers",
+    HOST,
+);
+const IP_FRAGMENTS_TOTAL: MetricSpec = counter(
+    "network_ip_fragments_total",
+    "IP fragments sent or received per IP",
+    HOST,
+);
+const IP_REASSEMBLY_FAILURES_TOTAL: MetricSpec = counter(
+    "network_ip_fragment_reassembly_failures_total",
+    "Fragmented datagrams per IP that could not be reassembled",
+    HOST,
+);
+const IP_RETRANSMISSIONS_TOTAL: MetricSpec = counter(
+    "network_ip_retransmissions_total",
+    "Total retransmissions per IP",
+    HOST,
+);
+const IP_DUPLICATE_ACKS_TOTAL: MetricSpec = counter(
+    "network_ip_duplicate_acks_total",
+    "Total duplicate ACKs per IP",
+    HOST,
+);
+const IP_WINDOW_SIZE_CHANGES_TOTAL: MetricSpec = counter(
+    "network_ip_window_size_changes_total",
+    "Total window size changes per IP",
+    HOST,
+);
+const IP_TX_BYTES_PER_SEC: MetricSpec = gauge(
+    "network_ip_tx_bytes_per_sec",
+    "Transmitted bytes per second per IP",
+    HOST,
+);
+const IP_RX_BYTES_PER_SEC: MetricSpec = gauge(
+    "network_ip_rx_bytes_per_sec",
+    "Received bytes per second per IP",
+    HOST,
+);
+const IP_TX_PACKETS_PER_SEC: MetricSpec = gauge(
+    "network_ip_tx_packets_per_sec",
+    "Transmitted packets per second per IP",
+    HOST,
+);
+const IP_RX_PACKETS_PER_SEC: MetricSpec = gauge(
+    "network_ip_rx_packets_per_sec",
+    "Received packets per second per IP",
+    HOST,
+);
+const IP_TX_BPS: MetricSpec = gauge(
+    "network_ip_tx_bps",
+    "Transmitted bits per second per IP",
+    HOST,
+);
+const IP_RX_BPS: MetricSpec = gauge("network_ip_rx_bps", "Received bits per second per IP", HOST);
+
+// IP別の送受信の内訳（L4プロトコル、相手側のポート、サービスごと）
+const IP_PROTOCOL: [MetricSpec; 6] = [
+    counter(
+        "network_ip_protocol_tx_bytes_total",
+        "Total transmitted bytes per IP and protocol",
+        HOST_PROTOCOL,
+    ),
+    counter(
+        "network_ip_protocol_rx_bytes_total",
+        "Total received bytes per IP and protocol",
+        HOST_PROTOCOL,
+    ),
+    counter(
+        "network_ip_protocol_tx_packets_total",
+        "Total transmitted packets per IP and protocol",
+        HOST_PROTOCOL,
+    ),
+    counter(
+        "network_ip_protocol_rx_packets_total",
+        "Total received packets per IP and protocol",
+        HOST_PROTOCOL,
+    ),
+    gauge(
+        "network_ip_protocol_tx_bps",
+        "Transmitted bits per second per IP and protocol",
+        HOST_PROTOCOL,
+    ),
+    gauge(
+        "network_ip_protocol_rx_bps",
+        "Received bits per second per IP and protocol",
+        HOST_PROTOCOL,
+    ),
+];
+const IP_PORT: [MetricSpec; 6] = [
+    counter(
+        "network_ip_port_tx_bytes_total",
+        "Total transmitted bytes per IP and remote port",
+        HOST_PORT,
+    ),
+    counter(
+        "network_ip_port_rx_bytes_total",
+        "Total received bytes per IP and remote port",
+        HOST_PORT,
+    ),
+    counter(
+        "network_ip_port_tx_packets_total",
+        "Total transmitted packets per IP and remote port",
+        HOST_PORT,
+    ),
+    counter(
+        "network_ip_port_rx_packets_total",
+        "Total received packets per IP and remote port",
+        HOST_PORT,
+    ),
+    gauge(
+        "network_ip_port_tx_bps",
+        "Transmitted bits per second per IP and remote port",
+        HOST_PORT,
+    ),
+    gauge(
+        "network_ip_port_rx_bps",
+        "Received bits per second per IP and remote port",
+        HOST_PORT,
+    ),
+];
+const IP_SERVICE: [MetricSpec; 6] = [
+    counter(
+        "network_ip_service_tx_bytes_total",
+        "Total transmitted bytes per IP and service",
+        HOST_SERVICE,
+    ),
+    counter(
+        "network_ip_service_rx_bytes_total",
+        "Total received bytes per IP and service",
+        HOST_SERVICE,
+    ),
+    counter(
+        "network_ip_service_tx_packets_total",
+        "Total transmitted packets per IP and service",
+        HOST_SERVICE,
+    ),
+    counter(
+        "network_ip_service_rx_packets_total",
+        "Total received packets per IP and service",
+        HOST_SERVICE,
+    ),
+    gauge(
+        "network_ip_service_tx_bps",
+        "Transmitted bits per second per IP and service",
+        HOST_SERVICE,
+    ),
+    gauge(
+        "network_ip_service_rx_bps",
+        "Received bits per second per IP and service",
+        HOST_SERVICE,
+    ),
+];
+const IP_RETRANSMISSIONS_PER_SEC: MetricSpec = gauge(
+    "network_ip_retransmissions_per_sec",
+    "Retransmissions per second per IP",
+    HOST,
+);
+const IP_SPURIOUS_RETRANSMISSIONS_PER_SEC: MetricSpec = gauge(
+    "network_ip_spurious_retransmissions_per_sec",
+    "Spurious retransmissions (already acknowledged data) per second per IP",
+    HOST,
+);
+const IP_OUT_OF_ORDER_PER_SEC: MetricSpec = gauge(
+    "network_ip_out_of_order_per_sec",
+    "Out-of-order TCP segments per second per IP",
+    HOST,
+);
+const IP_DUPLICATE_ACKS_PER_SEC: MetricSpec = gauge(
+    "network_ip_duplicate_acks_per_sec",
+    "Duplicate ACKs per second per IP",
+    HOST,
+);
+const IP_TRIPLE_DUPLICATE_ACKS_PER_SEC: MetricSpec = gauge(
+    "network_ip_triple_duplicate_acks_per_sec",
+    "Triple duplicate ACK (fast retransmit trigger) events per second per IP",
+    HOST,
+);
+const IP_WINDOW_SIZE_CHANGES_PER_SEC: MetricSpec = gauge(
+    "network_ip_window_size_changes_per_sec",
+    "Window size changes per second per IP",
+    HOST,
+);
+const IP_RTT: [MetricSpec; 3] = [
+    gauge("network_ip_rtt_min_seconds", "Minimum TCP RTT per IP", HOST),
+    gauge("network_ip_rtt_mean_seconds", "Mean TCP RTT per IP", HOST),
+    gauge(
+        "network_ip_rtt_p95_seconds",
+        "95th percentile TCP RTT per IP",
+        HOST,
+    ),
+];
+
+// NIC別の合計メトリクス
+const NIC_TX_BPS_TOTAL: MetricSpec = gauge(
+    "network_ip_tx_bps_total",
+    "Total transmitted bits per second by NIC",
+    NIC,
+);
+const NIC_RX_BPS_TOTAL: MetricSpec = gauge(
+    "network_ip_rx_bps_total",
+    "Total received bits per second by NIC",
+    NIC,
+);
+const NIC_TX_BYTES_PER_SEC_TOTAL: MetricSpec = gauge(
+    "network_ip_tx_bytes_per_sec_total",
+    "Total transmitted bytes per second by NIC",
+    NIC,
+);
+const NIC_RX_BYTES_PER_SEC_TOTAL: MetricSpec = gauge(
+    "network_ip_rx_bytes_per_sec_total",
+    "Total received bytes per second by NIC",
+    NIC,
+);
+const NIC_RTT: [MetricSpec; 3] = [
+    gauge("network_nic_rtt_min_seconds", "Minimum TCP RTT by NIC", NIC),
+    gauge("network_nic_rtt_mean_seconds", "Mean TCP RTT by NIC", NIC),
+    gauge(
+        "network_nic_rtt_p95_seconds",
+        "95th percentile TCP RTT by NIC",
+        NIC,
+    ),
+];
+
+// キャプチャの状態
+const CAPTURE_UP: MetricSpec = gauge(
+    "network_capture_up",
+    "Whether the capture on the interface is running (1) or down (0)",
+    INTERFACE,
+);
+const CAPTURE_RECEIVED_TOTAL: MetricSpec = counter(
+    "network_capture_received_packets_total",
+    "Packets received by libpcap",
+    INTERFACE,
+);
+const CAPTURE_DROPPED_TOTAL: MetricSpec = counter(
+    "network_capture_dropped_packets_total",
+    "Packets dropped because the capture buffer was full",
+    INTERFACE,
+);
+const CAPTURE_IF_DROPPED_TOTAL: MetricSpec = counter(
+    "network_capture_if_dropped_packets_total",
+    "Packets dropped by the network interface or its driver",
+    INTERFACE,
+);
+const MALFORMED_PACKETS_TOTAL: MetricSpec = counter(
+    "network_malformed_packets_total",
+    "Packets that could not be parsed, by layer",
+    &["interface", "layer"],
+);
+const UNTRACKED_PACKETS_TOTAL: MetricSpec = counter(
+    "network_stats_untracked_packets_total",
+    "Packets whose new host or TCP flow was not tracked because the limit was reached",
+    &["interface", "kind"],
+);
+
+const ALL: [&MetricSpec; 73] = [
+    &TX_BYTES_TOTAL,
+    &RX_BYTES_TOTAL,
+    &TX_BYTES_PER_SEC,
+    &RX_BYTES_PER_SEC,
+    &TX_PACKETS_TOTAL,
+    &RX_PACKETS_TOTAL,
+    &TX_PACKETS_PER_SEC,
+    &RX_PACKETS_PER_SEC,
+    &TX_BPS,
+    &RX_BPS,
+    &RETRANSMISSIONS_PER_SEC,
+    &SPURIOUS_RETRANSMISSIONS_PER_SEC,
+    &OUT_OF_ORDER_PER_SEC,
+    &DUPLICATE_ACKS_PER_SEC,
+    &TRIPLE_DUPLICATE_ACKS_PER_SEC,
+    &WINDOW_SIZE_CHANGES_PER_SEC,
+    &IP_TX_BYTES_TOTAL,
+    &IP_RX_BYTES_TOTAL,
+    &IP_TX_PACKETS_TOTAL,
+    &IP_RX_PACKETS_TOTAL,
+    &IP_TX_TUNNEL_OVERHEAD_BYTES_TOTAL,
+    &IP_RX_TUNNEL_OVERHEAD_BYTES_TOTAL,
+    &IP_FRAGMENTS_TOTAL,
+    &IP_REASSEMBLY_FAILURES_TOTAL,
+    &IP_RETRANSMISSIONS_TOTAL,
+    &IP_DUPLICATE_ACKS_TOTAL,
+    &IP_WINDOW_SIZE_CHANGES_TOTAL,
+    &IP_TX_BYTES_PER_SEC,
+    &IP_RX_BYTES_PER_SEC,
+    &IP_TX_PACKETS_PER_SEC,
+    &IP_RX_PACKETS_PER_SEC,
+    &IP_TX_BPS,
+    &IP_RX_BPS,
+    &IP_PROTOCOL[0],
+    &IP_PROTOCOL[1],
+    &IP_PROTOCOL[2],
+    &IP_PROTOCOL[3],
+    &IP_PROTOCOL[4],
+    &IP_PROTOCOL[5],
+    &IP_PORT[0],
+    &IP_PORT[1],
+    &IP_PORT[2],
+    &IP_PORT[3],
+    &IP_PORT[4],
+    &IP_PORT[5],
+    &IP_SERVICE[0],
+    &IP_SERVICE[1],
+    &IP_SERVICE[2],
+    &IP_SERVICE[3],
+    &IP_SERVICE[4],
+    &IP_SERVICE[5],
+    &IP_RETRANSMISSIONS_PER_SEC,
+    &IP_SPURIOUS_RETRANSMISSIONS_PER_SEC,
+    &IP_OUT_OF_ORDER_PER_SEC,
+    &IP_DUPLICATE_ACKS_PER_SEC,
+    &IP_TRIPLE_DUPLICATE_ACKS_PER_SEC,
+    &IP_WINDOW_SIZE_CHANGES_PER_SEC,
+    &IP_RTT[0],
+    &IP_RTT[1],
+    &IP_RTT[2],
+    &NIC_TX_BPS_TOTAL,
+    &NIC_RX_BPS_TOTAL,
+    &NIC_TX_BYTES_PER_SEC_TOTAL,
+    &NIC_RX_BYTES_PER_SEC_TOTAL,
+    &NIC_RTT[0],
+    &NIC_RTT[1],
+    &NIC_RTT[2],
+    &CAPTURE_UP,
+    &CAPTURE_RECEIVED_TOTAL,
+    &CAPTURE_DROPPED_TOTAL,
+    &CAPTURE_IF_DROPPED_TOTAL,
+    &MALFORMED_PACKETS_TOTAL,
+    &UNTRACKED_PACKETS_TOTAL,
+];
+
+// インターフェース名から現在の監視対象を引く（ライブキャプチャでは再読み込み後の設定から）
+pub type TargetLookup = Box<dyn Fn(&str) -> TargetNetworks + Send + Sync>;
+
+// スクレイプのたびに読むもの
+struct ScrapeSource {
+    interfaces: Vec<InterfaceCapture>,
+    targets: TargetLookup,
+    wan_assignments: Arc<Mutex<WanAssignments>>,
+}
+
+pub struct PrometheusMetrics {
+    pub registry: Registry,
+    source: Arc<RwLock<Option<ScrapeSource>>>,
+    capture_restarts_total: prometheus::CounterVec,
+}
+
+impl PrometheusMetrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let source = Arc::new(RwLock::new(None));
+        registry
+            .register(Box::new(StatsCollector::new(source.clone())))
+            .unwrap();
+
+        // 再オープンはその場で数える
+        let capture_restarts_total = prometheus::CounterVec::new(
+            prometheus::Opts::new(
+                "network_capture_restarts_total",
+                "Number of times the capture was reopened after stopping",
+            ),
+            INTERFACE,
+        )
+        .unwrap();
+        registry
+            .register(Box::new(capture_restarts_total.clone()))
+            .unwrap();
+
+        Self {
+            registry,
+            source,
+            capture_restarts_total,
+        }
+    }
+
+    // スクレイプで読むインターフェースを設定する。それまでのスクレイプは空になる
+    pub fn attach(
+        &self,
+        interfaces: Vec<InterfaceCapture>,
+        targets: TargetLookup,
+        wan_assignments: Arc<Mutex<WanAssignments>>,
+    ) {
+        *self.source.write().unwrap() = Some(ScrapeSource {
+            interfaces,
+            targets,
+            wan_assignments,
+        });
+    }
+
+    pub fn count_restart(&self, interface: &str) {
+        self.capture_restarts_total
+            .with_label_values(&[interface])
+            .inc();
+    }
+}
+
+// スクレイプのたびに、各ワーカーが公開した最新のスナップショットからメトリクスを組み立てる
+// 途中で値を書き写さないので、スクレイプと統計の更新が競合しても値が巻き戻らない
+struct StatsCollector {
+    descs: Vec<Desc>,
+    source: Arc<RwLock<Option<ScrapeSource>>>,
+}
+
+impl StatsCollector {
+    fn new(source: Arc<RwLock<Option<ScrapeSource>>>) -> Self {
+        let descs = ALL
+            .iter()
+            .map(|spec| {
+                Desc::new(
+                    spec.name.to_string(),
+                    spec.help.to_string(),
+                    spec.labels.iter().map(|label| label.to_string()).collect(),
+                    HashMap::new(),
+                )
+                .unwrap()
+            })
+            .collect();
+        Self { descs, source }
+    }
+}
+
+impl Collector for StatsCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut families = Families::default();
+        if let Some(source) = self.source.read().unwrap().as_ref() {
+            let wan_assignments = source.wan_assignments.lock().unwrap();
+            for state in &source.interfaces {
+                let targets = (source.targets)(&state.name);
+                collect_interface(&mut families, state, &targets, &wan_assignments);
+                collect_capture(&mut families, state);
+            }
+        }
+        families.families.into_values().collect()
+    }
+}
+
+// 1回のスクレイプで組み立てる、メトリクスの名前ごとの系列
+#[derive(Default)]
+struct Families {
+    families: HashMap<&'static str, MetricFamily>,
+}
+
+impl Families {
+    fn add(&mut self, spec: &MetricSpec, label_values: &[&str], value: f64) {
+        let family = self.families.entry(spec.name).or_insert_with(|| {
+            let mut family = MetricFamily::default();
+            family.set_name(spec.name.to_string());
+            family.set_help(spec.help.to_string());
+            family.set_field_type(spec.kind);
+            family
+        });
+
+        // ラベルは名前順に並べる（CounterVecなどと同じ）
+        let mut labels: Vec<(&str, &str)> = spec
+            .labels
+            .iter()
+            .copied()
+            .zip(label_values.iter().copied())
+            .collect();
+        labels.sort_by_key(|(name, _)| *name);
+        let mut metric = Metric::default();
+        for (name, value) in labels {
+            let mut pair = LabelPair::default();
+            pair.set_name(name.to_string());
+            pair.set_value(value.to_string());
+            metric.mut_label().push(pair);
+        }
+        if spec.kind == MetricType::COUNTER {
+            let mut counter = Counter::default();
+            counter.set_value(value);
+            metric.set_counter(counter);
+        } else {
+            let mut gauge = Gauge::default();
+            gauge.set_value(value);
+            metric.set_gauge(gauge);
+        }
+        family.mut_metric().push(metric);
+    }
+
+    fn add_traffic(
+        &mut self,
+        specs: &[MetricSpec; 6],
+        label_values: &[&str],
+        traffic: &TrafficStats,
+    ) {
+        let [tx_bytes, rx_bytes, tx_packets, rx_packets, tx_bps, rx_bps] = specs;
+        self.add(tx_bytes, label_values, traffic.tx_bytes as f64);
+        self.add(rx_bytes, label_values, traffic.rx_bytes as f64);
+        self.add(tx_packets, label_values, traffic.tx_packets as f64);
+        self.add(rx_packets, label_values, traffic.rx_packets as f64);
+        self.add(tx_bps, label_values, traffic.tx_bps);
+        self.add(rx_bps, label_values, traffic.rx_bps);
+    }
+
+    fn add_rtt(&mut self, specs: &[MetricSpec; 3], label_values: &[&str], rtt: RttSummary) {
+        let [min, mean, p95] = specs;
+        self.add(min, label_values, rtt.min);
+        self.add(mean, label_values, rtt.mean);
+        self.add(p95, label_values, rtt.p95);
+    }
+}
+
+// 1インターフェース分の統計を、interfaceラベルを付けて出力する
+// LAN側ではWAN割り当てからNICを決め、WAN側（role が lan 以外）では役割をそのままNICとする
+fn collect_interface(
+    out: &mut Families,
+    state: &InterfaceCapture,
+    targets: &TargetNetworks,
+    wan_assignments: &WanAssignments,
+) {
+    let interface = state.name.as_str();
+    let snapshot = state.merged_stats();
+
+    // インターフェース全体の累積バイト数とパケット数には、捨てたホストや監視対象から外れたホストの分も含める
+    // プロセスが動いている間は減らない
+    let mut total_tx_bytes = snapshot.retired.tx_bytes;
+    let mut total_rx_bytes = snapshot.retired.rx_bytes;
+    let mut total_tx_packets = snapshot.retired.tx_packets;
+    let mut total_rx_packets = snapshot.retired.rx_packets;
+    let mut total_tx_packets_per_sec = 0u64;
+    let mut total_rx_packets_per_sec = 0u64;
+    let mut total_tx_bytes_per_sec = 0u64;
+    let mut total_rx_bytes_per_sec = 0u64;
+    let mut total_tx_bps = 0f64;
+    let mut total_rx_bps = 0f64;
+    let mut total_retransmissions_per_sec = 0u64;
+    let mut total_spurious_retransmissions_per_sec = 0u64;
+    let mut total_out_of_order_per_sec = 0u64;
+    let mut total_duplicate_acks_per_sec = 0u64;
+    let mut total_triple_duplicate_acks_per_sec = 0u64;
+    let mut total_window_size_changes_per_sec = 0u64;
+
+    // NIC別の合計値を計算するためのマップ
+    let mut nic_stats: HashMap<String, (f64, f64, u64, u64)> = HashMap::new(); // (tx_bps, rx_bps, tx_bytes_per_sec, rx_bytes_per_sec)
+    let mut nic_rtt_samples: HashMap<String, Vec<f64>> = HashMap::new();
+
+    for (key, stat) in &snapshot.hosts {
+        total_tx_bytes += stat.tx_byte_count;
+        total_rx_bytes += stat.rx_byte_count;
+        total_tx_packets += stat.tx_packet_count;
+        total_rx_packets += stat.rx_packet_count;
+
+        // IP別のメトリクスは監視対象に含まれるIPだけ
+        if !key.is_target(targets) {
+            continue;
+        }
+        let ip_str = key.ip.to_string();
+        let vlan = key.vlan.to_string();
+        let labels = [interface, vlan.as_str(), ip_str.as_str()];
+
+        out.add(&IP_TX_BYTES_TOTAL, &labels, stat.tx_byte_count as f64);
+        out.add(&IP_RX_BYTES_TOTAL, &labels, stat.rx_byte_count as f64);
+        out.add(&IP_TX_PACKETS_TOTAL, &labels, stat.tx_packet_count as f64);
+        out.add(&IP_RX_PACKETS_TOTAL, &labels, stat.rx_packet_count as f64);
+        out.add(
+            &IP_RETRANSMISSIONS_TOTAL,
+            &labels,
+            stat.retransmissions as f64,
+        );
+        out.add(
+            &IP_DUPLICATE_ACKS_TOTAL,
+            &labels,
+            stat.duplicate_acks as f64,
+        );
+        out.add(
+            &IP_WINDOW_SIZE_CHANGES_TOTAL,
+            &labels,
+            stat.window_size_changes as f64,
+        );
+        // トンネルやフラグメントのカウンタは、該当するパケットがあったホストだけ出力する
+        for (spec, value) in [
+            (
+                &IP_TX_TUNNEL_OVERHEAD_BYTES_TOTAL,
+                stat.tx_tunnel_overhead_bytes,
+            ),
+            (
+                &IP_RX_TUNNEL_OVERHEAD_BYTES_TOTAL,
+                stat.rx_tunnel_overhead_bytes,
+            ),
+            (&IP_FRAGMENTS_TOTAL, stat.fragments),
+            (&IP_REASSEMBLY_FAILURES_TOTAL, stat.reassembly_failures),
+        ] {
+            if value > 0 {
+                out.add(spec, &labels, value as f64);
+            }
+        }
+
+        out.add(&IP_TX_BYTES_PER_SEC, &labels, stat.tx_bytes_per_sec as f64);
+        out.add(&IP_RX_BYTES_PER_SEC, &labels, stat.rx_bytes_per_sec as f64);
+        out.add(
+            &IP_TX_PACKETS_PER_SEC,
+            &labels,
+            stat.tx_packets_per_sec as f64,
+        );
+        out.add(
+            &IP_RX_PACKETS_PER_SEC,
+            &labels,
+            stat.rx_packets_per_sec as f64,
+        );
+        out.add(&IP_TX_BPS, &labels, stat.tx_current_bps);
+        out.add(&IP_RX_BPS, &labels, stat.rx_current_bps);
+        // プロトコル別の系列は、そのプロトコルのパケットを見たものだけ
+        for (protocol, traffic) in Protocol::ALL.iter().zip(&stat.protocols) {
+            if traffic.is_empty() {
+                continue;
+            }
+            let labels = [interface, vlan.as_str(), ip_str.as_str(), protocol.label()];
+            out.add_traffic(&IP_PROTOCOL, &labels, traffic);
+        }
+        // ポート別とサービス別は、系列の数を抑えるため送受信バイト数の多いものだけ
+        for (remote, traffic) in busiest(&stat.ports, state.limits.top_ports) {
+            let port = remote.port_label();
+            let labels = [
+                interface,
+                vlan.as_str(),
+                ip_str.as_str(),
+                remote.protocol.label(),
+                port.as_str(),
+            ];
+            out.add_traffic(&IP_PORT, &labels, traffic);
+        }
+        for (service, traffic) in busiest(&stat.services, state.limits.top_services) {
+            let labels = [
+                interface,
+                vlan.as_str(),
+                ip_str.as_str(),
+                state.services.name(service),
+            ];
+            out.add_traffic(&IP_SERVICE, &labels, traffic);
+        }
+        out.add(
+            &IP_RETRANSMISSIONS_PER_SEC,
+            &labels,
+            stat.retransmissions_per_sec as f64,
+        );
+        out.add(
+            &IP_SPURIOUS_RETRANSMISSIONS_PER_SEC,
+            &labels,
+            stat.spurious_retransmissions_per_sec as f64,
+        );
+        out.add(
+            &IP_OUT_OF_ORDER_PER_SEC,
+            &labels,
+            stat.out_of_order_per_sec as f64,
+        );
+        out.add(
+            &IP_DUPLICATE_ACKS_PER_SEC,
+            &labels,
+            stat.duplicate_acks_per_sec as f64,
+        );
+        out.add(
+            &IP_TRIPLE_DUPLICATE_ACKS_PER_SEC,
+            &labels,
+            stat.triple_duplicate_acks_per_sec as f64,
+        );
+        out.add(
+            &IP_WINDOW_SIZE_CHANGES_PER_SEC,
+            &labels,
+            stat.window_size_changes_per_sec as f64,
+        );
+        // RTTはサンプルがあるときだけ
+        if let Some(rtt) = stat.rtt {
+            out.add_rtt(&IP_RTT, &labels, rtt);
+        }
+
+        // NIC別の統計を集計（監視対象のIPのみ）
+        let nic = if state.role == "lan" {
+            wan_assignments.get_nic_for_ip(&key.ip)
+        } else {
+            state.role.clone()
+        };
+        nic_rtt_samples
+            .entry(nic.clone())
+            .or_default()
+            .extend(stat.rtt_samples.iter().map(|&(_, rtt)| rtt));
+        let entry = nic_stats.entry(nic).or_insert((0.0, 0.0, 0, 0));
+        entry.0 += stat.tx_current_bps;
+        entry.1 += stat.rx_current_bps;
+        entry.2 += stat.tx_bytes_per_sec;
+        entry.3 += stat.rx_bytes_per_sec;
+
+        // 全体統計に含める
+        total_tx_bytes_per_sec += stat.tx_bytes_per_sec;
+        total_rx_bytes_per_sec += stat.rx_bytes_per_sec;
+        total_tx_packets_per_sec += stat.tx_packets_per_sec;
+        total_rx_packets_per_sec += stat.rx_packets_per_sec;
+        total_tx_bps += stat.tx_current_bps;
+        total_rx_bps += stat.rx_current_bps;
+        total_retransmissions_per_sec += stat.retransmissions_per_sec;
+        total_spurious_retransmissions_per_sec += stat.spurious_retransmissions_per_sec;
+        total_out_of_order_per_sec += stat.out_of_order_per_sec;
+        total_duplicate_acks_per_sec += stat.duplicate_acks_per_sec;
+        total_triple_duplicate_acks_per_sec += stat.triple_duplicate_acks_per_sec;
+        total_window_size_changes_per_sec += stat.window_size_changes_per_sec;
+    }
+
+    let labels = [interface];
+    out.add(&TX_BYTES_TOTAL, &labels, total_tx_bytes as f64);
+    out.add(&RX_BYTES_TOTAL, &labels, total_rx_bytes as f64);
+    out.add(&TX_BYTES_PER_SEC, &labels, total_tx_bytes_per_sec as f64);
+    out.add(&RX_BYTES_PER_SEC, &labels, total_rx_bytes_per_sec as f64);
+    out.add(&TX_PACKETS_TOTAL, &labels, total_tx_packets as f64);
+    out.add(&RX_PACKETS_TOTAL, &labels, total_rx_packets as f64);
+    out.add(
+        &TX_PACKETS_PER_SEC,
+        &labels,
+        total_tx_packets_per_sec as f64,
+    );
+    out.add(
+        &RX_PACKETS_PER_SEC,
+        &labels,
+        total_rx_packets_per_sec as f64,
+    );
+    out.add(&TX_BPS, &labels, total_tx_bps);
+    out.add(&RX_BPS, &labels, total_rx_bps);
+    out.add(
+        &RETRANSMISSIONS_PER_SEC,
+        &labels,
+        total_retransmissions_per_sec as f64,
+    );
+    out.add(
+        &SPURIOUS_RETRANSMISSIONS_PER_SEC,
+        &labels,
+        total_spurious_retransmissions_per_sec as f64,
+    );
+    out.add(
+        &OUT_OF_ORDER_PER_SEC,
+        &labels,
+        total_out_of_order_per_sec as f64,
+    );
+    out.add(
+        &DUPLICATE_ACKS_PER_SEC,
+        &labels,
+        total_duplicate_acks_per_sec as f64,
+    );
+    out.add(
+        &TRIPLE_DUPLICATE_ACKS_PER_SEC,
+        &labels,
+        total_triple_duplicate_acks_per_sec as f64,
+    );
+    out.add(
+        &WINDOW_SIZE_CHANGES_PER_SEC,
+        &labels,
+        total_window_size_changes_per_sec as f64,
+    );
+
+    // NIC別の合計メトリクス
+    for (nic, samples) in &nic_rtt_samples {
+        if let Some(rtt) = RttSummary::from_samples(samples) {
+            out.add_rtt(&NIC_RTT, &[interface, nic], rtt);
+        }
+    }
+    for (nic, (tx_bps, rx_bps, tx_bytes_per_sec, rx_bytes_per_sec)) in &nic_stats {
+        let labels = [interface, nic.as_str()];
+        out.add(&NIC_TX_BPS_TOTAL, &labels, *tx_bps);
+        out.add(&NIC_RX_BPS_TOTAL, &labels, *rx_bps);
+        out.add(
+            &NIC_TX_BYTES_PER_SEC_TOTAL,
+            &labels,
+            *tx_bytes_per_sec as f64,
+        );
+        out.add(
+            &NIC_RX_BYTES_PER_SEC_TOTAL,
+            &labels,
+            *rx_bytes_per_sec as f64,
+        );
+    }
+}
+
+// キャプチャの状態と健全性カウンタ
+fn collect_capture(out: &mut Families, state: &InterfaceCapture) {
+    let interface = state.name.as_str();
+    let up = state.up.load(Ordering::SeqCst);
+    out.add(&CAPTURE_UP, &[interface], if up { 1.0 } else { 0.0 });
+
+    let health = state.health.snapshot();
+    out.add(
+        &CAPTURE_RECEIVED_TOTAL,
+        &[interface],
+        health.received as f64,
+    );
+    out.add(&CAPTURE_DROPPED_TOTAL, &[interface], health.dropped as f64);
+    out.add(
+        &CAPTURE_IF_DROPPED_TOTAL,
+        &[interface],
+        health.if_dropped as f64,
+    );
+    for malformed in Malformed::ALL {
+        out.add(
+            &MALFORMED_PACKETS_TOTAL,
+            &[interface, malformed.layer()],
+            state.health.malformed(malformed) as f64,
+        );
+    }
+    for untracked in Untracked::ALL {
+        out.add(
+            &UNTRACKED_PACKETS_TOTAL,
+            &[interface, untracked.kind()],
+            state.health.untracked(untracked) as f64,
+        );
+    }
+}
+
+// 送受信バイト数の累計（現在のレートではない）の多い順に上位n件
+// 累計が同じものはキーの順に並べ、スクレイプのたびに出力する系列が入れ替わらないようにする
+fn busiest<K: Copy + Ord>(traffic: &HashMap<K, TrafficStats>, n: usize) -> Vec<(K, &TrafficStats)> {
+    let mut entries: Vec<_> = traffic
+        .iter()
+        .map(|(key, traffic)| (*key, traffic))
+        .collect();
+    entries.sort_unstable_by_key(|(key, traffic)| (std::cmp::Reverse(traffic.total_bytes()), *key));
+    entries.truncate(n);
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::RemotePort;
+
+    fn traffic(tx_bytes: u64) -> TrafficStats {
+        let mut traffic = TrafficStats::default();
+        traffic.tx_bytes = tx_bytes;
+        traffic
+    }
+
+    #[test]
+    fn busiest_breaks_ties_by_key() {
+        let port = |port| RemotePort {
+            protocol: Protocol::Tcp,
+            port: Some(port),
+        };
+        let ports: HashMap<_, _> = [
+            (port(443), traffic(100)),
+            (port(22), traffic(100)),
+            (port(80), traffic(500)),
+            (port(8080), traffic(100)),
+        ]
+        .into_iter()
+        .collect();
+        let top: Vec<_> = busiest(&ports, 3).into_iter().map(|(key, _)| key).collect();
+        assert_eq!(top, [port(80), port(22), port(443)]);
+    }
+}
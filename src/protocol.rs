@@ -0,0 +1,48 @@
+// IPの上位層のプロトコル（IP別のプロトコル内訳のprotocolラベル）
+// IPv6では拡張ヘッダーをたどった先、トンネルを外した場合は内側のパケットのもの
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    Icmp,
+    Icmpv6,
+    Gre, // 外さなかったGRE
+    Esp, // IPsec
+    Other,
+}
+
+impl Protocol {
+    pub const ALL: [Protocol; 7] = [
+        Protocol::Tcp,
+        Protocol::Udp,
+        Protocol::Icmp,
+        Protocol::Icmpv6,
+        Protocol::Gre,
+        Protocol::Esp,
+        Protocol::Other,
+    ];
+
+    pub fn from_number(number: u8) -> Self {
+        match number {
+            6 => Protocol::Tcp,
+            17 => Protocol::Udp,
+            1 => Protocol::Icmp,
+            58 => Protocol::Icmpv6,
+            47 => Protocol::Gre,
+            50 => Protocol::Esp,
+            _ => Protocol::Other,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+            Protocol::Icmp => "icmp",
+            Protocol::Icmpv6 => "icmpv6",
+            Protocol::Gre => "gre",
+            Protocol::Esp => "esp",
+            Protocol::Other => "other",
+        }
+    }
+}
@@ -0,0 +1,289 @@
+use crate::bpf::{self, CaptureFilter};
+use crate::config::{CaptureSource, Cli, Config};
+use crate::decap::Decapsulation;
+use crate::link::LinkType;
+use crate::targets::TargetNetworks;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+// 自動検出できていないインターフェースのプレフィックスを検出し直す間隔
+const DETECTION_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+// 実行中に差し替え可能な設定
+#[derive(Debug)]
+pub struct RuntimeSettings {
+    pub targets: HashMap<String, TargetNetworks>, // インターフェース名ごとの監視対象
+    pub filters: HashMap<String, CaptureFilter>,  // インターフェース名ごとのBPFフィルタ
+    pub decap: Decapsulation,
+    pub wan_api_url: String,
+    pub wan_poll_interval: Duration,
+    pub refresh_interval: Duration,
+    pub tui: bool,
+    pub tui_protocols: bool,
+    pub summary_file: Option<PathBuf>,
+}
+
+impl RuntimeSettings {
+    // ライブキャプチャの各インターフェースについて監視対象を決める
+    // フィルタはキャプチャを開いたことのあるインターフェースならそのリンク層で確かめる
+    pub fn from_config(config: &Config, links: &HashMap<String, LinkType>) -> Result<Self, String> {
+        let mut targets = HashMap::new();
+        let mut filters = HashMap::new();
+        if let CaptureSource::Live(interfaces) = &config.source {
+            for interface in interfaces {
+                let networks = TargetNetworks::from_config(config, Some(&interface.name))?;
+                let filter =
+                    bpf::capture_filter(config, &networks, link_of(links, &interface.name))
+                        .map_err(|e| format!("{} (on {})", e, interface.name))?;
+                filters.insert(interface.name.clone(), filter);
+                targets.insert(interface.name.clone(), networks);
+            }
+        }
+        Ok(Self {
+            targets,
+            filters,
+            decap: config.decap,
+            wan_api_url: config.wan_api_url.clone(),
+            wan_poll_interval: config.wan_poll_interval,
+            refresh_interval: config.refresh_interval,
+            tui: config.tui,
+            tui_protocols: config.tui_protocols,
+            summary_file: config.summary_file.clone(),
+        })
+    }
+
+    pub fn targets_for(&self, interface: &str) -> &TargetNetworks {
+        &self.targets[interface]
+    }
+
+    pub fn filter_for(&self, interface: &str) -> &CaptureFilter {
+        &self.filters[interface]
+    }
+}
+
+// キャプチャを開いたときに記録したデータリンクタイプ。まだ開いていなければEthernetとして扱う
+fn link_of(links: &HashMap<String, LinkType>, interface: &str) -> LinkType {
+    links.get(interface).copied().unwrap_or(LinkType::Ethernet)
+}
+
+// 各スレッドが参照する現在の設定。差し替えはArcごと行うので読み手が途中の状態を見ることはない
+pub struct SharedSettings {
+    current: RwLock<Arc<RuntimeSettings>>,
+    generation: AtomicU64,
+    links: Mutex<HashMap<String, LinkType>>, // 開いたキャプチャの実際のデータリンクタイプ
+}
+
+impl SharedSettings {
+    pub fn new(settings: RuntimeSettings) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(settings)),
+            generation: AtomicU64::new(0),
+            links: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn load(&self) -> Arc<RuntimeSettings> {
+        self.current.read().unwrap().clone()
+    }
+
+    // 差し替えのたびに増える。キャプチャループはこれを見て手元のArcを取り直す
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    // キャプチャを開くたびに記録し、再読み込みのときにフィルタの検証に使う
+    pub fn record_link(&self, interface: &str, link: LinkType) {
+        self.links
+            .lock()
+            .unwrap()
+            .insert(interface.to_string(), link);
+    }
+
+    pub fn link_for(&self, interface: &str) -> LinkType {
+        link_of(&self.links.lock().unwrap(), interface)
+    }
+
+    fn links(&self) -> HashMap<String, LinkType> {
+        self.links.lock().unwrap().clone()
+    }
+
+    // 各インターフェースに設定しているBPFフィルタ（インターフェース名 → そのリンクでの式）
+    pub fn filters(&self) -> HashMap<String, Option<String>> {
+        let links = self.links();
+        self.load()
+            .filters
+            .iter()
+            .map(|(name, filter)| (name.clone(), filter.expression(link_of(&links, name))))
+            .collect()
+    }
+
+    fn store(&self, settings: RuntimeSettings) {
+        *self.current.write().unwrap() = Arc::new(settings);
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+}
+
+// SIGHUPと管理用HTTPエンドポイントから呼ばれる再読み込み処理
+pub struct Reloader {
+    cli: Cli,
+    settings: Arc<SharedSettings>,
+    applied: Mutex<Config>, // 最後に適用した設定（再読み込みの排他にも使う）
+}
+
+impl Reloader {
+    pub fn new(cli: Cli, initial: Config, settings: Arc<SharedSettings>) -> Self {
+        Self {
+            cli,
+            applied: Mutex::new(initial),
+            settings,
+        }
+    }
+
+    pub fn filters(&self) -> HashMap<String, Option<String>> {
+        self.settings.filters()
+    }
+
+    // 設定ファイルを読み直して検証し、問題がなければ差し替える
+    // エラーの場合は現在の設定をそのまま使い続ける
+    pub fn reload(&self) -> Result<String, String> {
+        let mut applied = self.applied.lock().unwrap();
+
+        let mut config = Config::from_cli(&self.cli).map_err(|e| e.to_string())?;
+
+        // キャプチャ元や待ち受けアドレスなどは再起動しないと変えられないので、実行中の値に戻す
+        // 比べる相手は最後に適用した設定（ファイルを元に戻せば注記も出なくなる）
+        let mut notes = Vec::new();
+        if config.source != applied.source {
+            notes.push("interface changes ignored until restart".to_string());
+            config.source = applied.source.clone();
+        }
+        if config.metrics_listen != applied.metrics_listen {
+            notes.push(format!(
+                "metrics.listen change ignored until restart (still {})",
+                applied.metrics_listen
+            ));
+            config.metrics_listen = applied.metrics_listen;
+        }
+        if config.buffer_size != applied.buffer_size {
+            notes.push("buffer_size change ignored until restart".to_string());
+            config.buffer_size = applied.buffer_size;
+        }
+        if config.backend != applied.backend || config.workers != applied.workers {
+            notes.push("backend/workers change ignored until restart".to_string());
+            config.backend = applied.backend;
+            config.workers = applied.workers;
+        }
+        if config.limits != applied.limits {
+            notes.push("stats limits change ignored until restart".to_string());
+            config.limits = applied.limits;
+        }
+        if config.accounting != applied.accounting {
+            notes.push("accounting change ignored until restart".to_string());
+            config.accounting = applied.accounting;
+        }
+        if config.services != applied.services {
+            notes.push("services change ignored until restart".to_string());
+            config.services = applied.services.clone();
+        }
+
+        let links = self.settings.links();
+        let settings = RuntimeSettings::from_config(&config, &links)?;
+        let mut message = "Configuration reloaded".to_string();
+        if let CaptureSource::Live(interfaces) = &config.source {
+            for interface in interfaces {
+                message.push_str(&format!(
+                    "; {}: {} (filter: {})",
+                    interface.name,
+                    settings.targets_for(&interface.name),
+                    settings
+                        .filter_for(&interface.name)
+                        .describe(link_of(&links, &interface.name))
+                ));
+            }
+        }
+        message.push_str(&format!("; decapsulation: {}", settings.decap));
+        for note in notes {
+            message.push_str("; ");
+            message.push_str(&note);
+        }
+
+        self.settings.store(settings);
+        *applied = config;
+        Ok(message)
+    }
+
+    // 自動検出を待っているインターフェースでプレフィックスが見つかったら、監視対象とフィルタを差し替える
+    pub fn redetect(&self) -> Option<String> {
+        let applied = self.applied.lock().unwrap();
+        let current = self.settings.load();
+        let mut waiting: Vec<&String> = current
+            .targets
+            .iter()
+            .filter(|(_, targets)| targets.awaiting_detection())
+            .map(|(name, _)| name)
+            .collect();
+        if waiting.is_empty() {
+            return None;
+        }
+        waiting.sort();
+
+        let links = self.settings.links();
+        let settings = RuntimeSettings::from_config(&applied, &links).ok()?;
+        let detected: Vec<String> = waiting
+            .into_iter()
+            .filter(|name| !settings.targets_for(name).awaiting_detection())
+            .map(|name| {
+                format!(
+                    "{}: {} (filter: {})",
+                    name,
+                    settings.targets_for(name),
+                    settings.filter_for(name).describe(link_of(&links, name))
+                )
+            })
+            .collect();
+        if detected.is_empty() {
+            return None;
+        }
+        self.settings.store(settings);
+        Some(format!("Prefixes detected; {}", detected.join("; ")))
+    }
+}
+
+// 起動時や再読み込み時にインターフェースのアドレスが見つからなかった場合に備え、定期的に検出し直す
+pub async fn watch_detection(reloader: Arc<Reloader>) {
+    let mut interval = tokio::time::interval(DETECTION_RETRY_INTERVAL);
+    loop {
+        interval.tick().await;
+        let reloader = reloader.clone();
+        // ネットワークインターフェースの列挙を含むため別スレッドで実行
+        match tokio::task::spawn_blocking(move || reloader.redetect()).await {
+            Ok(Some(message)) => println!("{}", message),
+            Ok(None) => {}
+            Err(e) => eprintln!("Prefix detection failed: {}", e),
+        }
+    }
+}
+
+// SIGHUPを受けるたびに設定を再読み込みする
+pub async fn watch_sighup(reloader: Arc<Reloader>) {
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(signal) => signal,
+        Err(e) => {
+            eprintln!("Failed to install SIGHUP handler: {}", e);
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        let reloader = reloader.clone();
+        // ネットワークインターフェースの列挙などブロッキング処理を含むため別スレッドで実行
+        match tokio::task::spawn_blocking(move || reloader.reload()).await {
+            Ok(Ok(message)) => println!("SIGHUP: {}", message),
+            Ok(Err(e)) => eprintln!("SIGHUP: reload rejected, keeping previous config: {}", e),
+            Err(e) => eprintln!("SIGHUP: reload failed: {}", e),
+        }
+    }
+}
@@ -0,0 +1,263 @@
+use crate::protocol::Protocol;
+use std::collections::HashMap;
+use std::fmt;
+
+// どのサービスにも当てはまらない通信のserviceラベル
+pub const OTHER: &str = "other";
+
+// 組み込みのサービスの割り当て（設定ファイルと--serviceで上書き・追加できる）
+const DEFAULT_SERVICES: &[(&str, &[&str])] = &[
+    ("http", &["tcp/80", "tcp/8080"]),
+    ("https", &["tcp/443", "tcp/8443"]),
+    ("quic", &["udp/443"]),
+    ("dns", &["udp/53", "tcp/53", "tcp/853", "udp/853"]),
+    ("ssh", &["tcp/22"]),
+    ("ntp", &["udp/123"]),
+    ("smtp", &["tcp/25", "tcp/465", "tcp/587"]),
+    ("imap", &["tcp/143", "tcp/993"]),
+    ("smb", &["tcp/445"]),
+    ("rdp", &["tcp/3389", "udp/3389"]),
+    ("stun", &["udp/3478", "tcp/3478"]),
+    ("ipsec", &["udp/500", "udp/4500"]),
+    ("openvpn", &["udp/1194", "tcp/1194"]),
+    ("wireguard", &["udp/51820"]),
+    ("bittorrent", &["tcp/6881-6889", "udp/6881-6889"]),
+];
+
+// TCP/UDPのポート番号の組
+#[derive(Debug, Clone, Copy)]
+pub struct Ports {
+    pub src: u16,
+    pub dst: u16,
+}
+
+impl Ports {
+    // TCP/UDPのヘッダーの先頭からポート番号を読む
+    pub fn parse(segment: &[u8]) -> Option<Self> {
+        let header = segment.get(..4)?;
+        Some(Self {
+            src: u16::from_be_bytes([header[0], header[1]]),
+            dst: u16::from_be_bytes([header[2], header[3]]),
+        })
+    }
+}
+
+// ホストから見た相手側のポート（IP別のリモートポートの内訳のキー）
+// 上限を超えて追跡できなかったポートはportがNoneの1つにまとめる
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RemotePort {
+    pub protocol: Protocol,
+    pub port: Option<u16>,
+}
+
+impl RemotePort {
+    pub fn port_label(&self) -> String {
+        match self.port {
+            Some(port) => port.to_string(),
+            None => OTHER.to_string(),
+        }
+    }
+}
+
+// サービス名の番号（ServiceMap::nameで名前に戻す）。0はどれにも当てはまらない通信
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ServiceId(u16);
+
+// プロトコルとポート番号からサービス名への割り当て
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServiceMap {
+    names: Vec<String>, // ServiceIdの順。先頭はOTHER
+    ports: HashMap<(Protocol, u16), ServiceId>,
+}
+
+impl ServiceMap {
+    // 組み込みの割り当てに、(サービス名, "tcp/443"のようなポートの指定)の順に上書きしていく
+    pub fn new<'a>(
+        overrides: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Result<Self, String> {
+        let mut map = Self {
+            names: vec![OTHER.to_string()],
+            ports: HashMap::new(),
+        };
+        for (name, ports) in DEFAULT_SERVICES {
+            for spec in *ports {
+                map.assign(name, spec)?;
+            }
+        }
+        for (name, spec) in overrides {
+            map.assign(name, spec)?;
+        }
+        Ok(map)
+    }
+
+    fn assign(&mut self, name: &str, spec: &str) -> Result<(), String> {
+        let valid_name = !name.is_empty()
+            && name != OTHER
+            && name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-');
+        if !valid_name {
+            return Err(format!(
+                "service name '{}' must be lowercase letters, digits, '_' or '-' (and not '{}')",
+                name, OTHER
+            ));
+        }
+        let (protocol, first, last) = parse_port_spec(spec).ok_or_else(|| {
+            format!(
+                "service '{}': '{}' is not PROTO/PORT[-PORT] (PROTO is tcp or udp)",
+                name, spec
+            )
+        })?;
+
+        let id = match self.names.iter().position(|existing| existing == name) {
+            Some(index) => ServiceId(index as u16),
+            None => {
+                self.names.push(name.to_string());
+                ServiceId(self.names.len() as u16 - 1)
+            }
+        };
+        for port in first..=last {
+            self.ports.insert((protocol, port), id);
+        }
+        Ok(())
+    }
+
+    // 相手側のポートを優先し、当てはまらなければ自分側のポートで決める（監視対象がサーバーの場合）
+    pub fn classify(&self, protocol: Protocol, remote: u16, local: u16) -> ServiceId {
+        self.ports
+            .get(&(protocol, remote))
+            .or_else(|| self.ports.get(&(protocol, local)))
+            .copied()
+            .unwrap_or(ServiceId(0))
+    }
+
+    pub fn name(&self, id: ServiceId) -> &str {
+        &self.names[id.0 as usize]
+    }
+}
+
+impl Default for ServiceMap {
+    fn default() -> Self {
+        Self::new([]).expect("built-in service map is valid")
+    }
+}
+
+impl fmt::Display for ServiceMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} services on {} ports",
+            self.names.len() - 1,
+            self.ports.len()
+        )
+    }
+}
+
+// "tcp/443"や"udp/6881-6889"を読む
+fn parse_port_spec(spec: &str) -> Option<(Protocol, u16, u16)> {
+    let (protocol, ports) = spec.split_once('/')?;
+    let protocol = match protocol {
+        "tcp" => Protocol::Tcp,
+        "udp" => Protocol::Udp,
+        _ => return None,
+    };
+    let (first, last) = match ports.split_once('-') {
+        Some((first, last)) => (first.parse().ok()?, last.parse().ok()?),
+        None => {
+            let port = ports.parse().ok()?;
+            (port, port)
+        }
+    };
+    (first <= last).then_some((protocol, first, last))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_single_ports_and_ranges() {
+        assert_eq!(parse_port_spec("tcp/443"), Some((Protocol::Tcp, 443, 443)));
+        assert_eq!(
+            parse_port_spec("udp/6881-6889"),
+            Some((Protocol::Udp, 6881, 6889))
+        );
+        assert_eq!(
+            parse_port_spec("udp/0-65535"),
+            Some((Protocol::Udp, 0, 65535))
+        );
+        assert_eq!(parse_port_spec("tcp/53-53"), Some((Protocol::Tcp, 53, 53)));
+    }
+
+    #[test]
+    fn rejects_malformed_port_specs() {
+        for spec in [
+            "443",
+            "tcp443",
+            "icmp/1",
+            "TCP/443",
+            "tcp/",
+            "tcp/65536",
+            "tcp/-1",
+            "tcp/10-",
+            "tcp/10-5",
+            "tcp/1-2-3",
+            "tcp/ 443",
+        ] {
+            assert_eq!(parse_port_spec(spec), None, "{}", spec);
+        }
+    }
+
+    #[test]
+    fn parses_ports_from_the_segment_header() {
+        let ports = Ports::parse(&[0x9c, 0x40, 0x01, 0xbb, 0, 0]).unwrap();
+        assert_eq!((ports.src, ports.dst), (40000, 443));
+        assert!(Ports::parse(&[0x9c, 0x40, 0x01]).is_none());
+    }
+
+    #[test]
+    fn classifies_by_the_remote_port_first() {
+        let services = ServiceMap::default();
+        let name =
+            |protocol, remote, local| services.name(services.classify(protocol, remote, local));
+        assert_eq!(name(Protocol::Tcp, 443, 51000), "https");
+        assert_eq!(name(Protocol::Udp, 443, 51000), "quic");
+        // 監視対象がサーバーなら自分側のポートで決まる
+        assert_eq!(name(Protocol::Tcp, 51000, 22), "ssh");
+        // 両方に当てはまるときは相手側を優先する
+        assert_eq!(name(Protocol::Tcp, 53, 80), "dns");
+        assert_eq!(name(Protocol::Tcp, 6885, 51000), "bittorrent");
+        assert_eq!(name(Protocol::Udp, 22, 51000), OTHER);
+        assert_eq!(name(Protocol::Icmp, 0, 0), OTHER);
+    }
+
+    #[test]
+    fn later_assignments_override_earlier_ones() {
+        let services = ServiceMap::new([
+            ("backup", "tcp/9000-9010"),
+            ("minecraft", "tcp/25565"),
+            ("alt-https", "tcp/443"),
+            ("backup", "tcp/9005"),
+        ])
+        .unwrap();
+        let name = |remote| services.name(services.classify(Protocol::Tcp, remote, 51000));
+        assert_eq!(name(9000), "backup");
+        assert_eq!(name(9005), "backup");
+        assert_eq!(name(25565), "minecraft");
+        assert_eq!(name(443), "alt-https");
+        assert_eq!(name(8443), "https");
+        assert_eq!(
+            services.to_string(),
+            format!("{} services on 56 ports", DEFAULT_SERVICES.len() + 3)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_names_and_specs() {
+        assert!(ServiceMap::new([("other", "tcp/1")]).is_err());
+        assert!(ServiceMap::new([("", "tcp/1")]).is_err());
+        assert!(ServiceMap::new([("Backup", "tcp/1")]).is_err());
+        let error = ServiceMap::new([("backup", "tcp/9010-9000")]).unwrap_err();
+        assert!(error.starts_with("service 'backup'"), "{}", error);
+    }
+}
@@ -0,0 +1,85 @@
+use std::sync::{Mutex, TryLockError};
+
+// 後から公開された差分を、まだ取り出されていない差分に重ねる
+pub trait Delta {
+    fn absorb(&mut self, newer: Self);
+}
+
+// ワーカーが公開した差分を、統計スレッドが取り出すまで溜めておく
+// 書き込み側はロックが取れなければ諦めて後で再試行するので、パケット処理が統計スレッドを待つことはない
+// 読み出し側も溜まった差分を取り出すだけなので、ロックを持つのは一瞬
+pub struct DeltaSlot<T> {
+    pending: Mutex<Option<T>>,
+}
+
+impl<T: Delta> DeltaSlot<T> {
+    pub fn new() -> Self {
+        Self {
+            pending: Mutex::new(None),
+        }
+    }
+
+    // 公開できなかった場合は差分をそのまま返す
+    pub fn try_publish(&self, delta: T) -> Result<(), T> {
+        match self.pending.try_lock() {
+            Ok(mut pending) => absorb(&mut pending, delta),
+            Err(TryLockError::Poisoned(poisoned)) => absorb(&mut poisoned.into_inner(), delta),
+            Err(TryLockError::WouldBlock) => return Err(delta),
+        }
+        Ok(())
+    }
+
+    // 停止時など、必ず公開したい場合に使う
+    pub fn publish(&self, delta: T) {
+        absorb(&mut self.pending.lock().unwrap(), delta);
+    }
+
+    // 前回取り出してから公開された差分をまとめて取り出す
+    pub fn take(&self) -> Option<T> {
+        self.pending.lock().unwrap().take()
+    }
+}
+
+impl<T: Delta> Default for DeltaSlot<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn absorb<T: Delta>(pending: &mut Option<T>, delta: T) {
+    match pending {
+        Some(pending) => pending.absorb(delta),
+        None => *pending = Some(delta),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl Delta for Vec<u32> {
+        fn absorb(&mut self, newer: Self) {
+            self.extend(newer);
+        }
+    }
+
+    #[test]
+    fn keeps_every_delta_until_it_is_taken() {
+        let slot = DeltaSlot::new();
+        assert_eq!(slot.take(), None);
+        slot.publish(vec![1]);
+        assert!(slot.try_publish(vec![2, 3]).is_ok());
+        assert_eq!(slot.take(), Some(vec![1, 2, 3]));
+        assert_eq!(slot.take(), None);
+    }
+
+    #[test]
+    fn hands_the_delta_back_while_the_reader_holds_the_lock() {
+        let slot = DeltaSlot::new();
+        let pending = slot.pending.lock().unwrap();
+        assert_eq!(slot.try_publish(vec![1]), Err(vec![1]));
+        drop(pending);
+        assert!(slot.try_publish(vec![1]).is_ok());
+        assert_eq!(slot.take(), Some(vec![1]));
+    }
+}
@@ -0,0 +1,187 @@
+use crate::health::HealthSnapshot;
+use crate::protocol::Protocol;
+use crate::service::ServiceMap;
+use crate::targets::TargetNetworks;
+use crate::traffic::TrafficStats;
+use crate::{HostKey, IpStats};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// 集計結果を書き出す1インターフェース分の統計
+pub struct InterfaceStats<'a> {
+    pub name: &'a str,
+    pub role: &'a str,
+    pub targets: &'a TargetNetworks,
+    pub stats: &'a HashMap<HostKey, Arc<IpStats>>,
+    pub services: &'a ServiceMap,
+    pub capture: HealthSnapshot,
+}
+
+// 終了時に書き出す集計結果
+#[derive(Debug, Serialize)]
+struct Summary<'a> {
+    version: &'a str,
+    stopped_at: u64, // UNIXエポック秒
+    interfaces: Vec<InterfaceSummary<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct InterfaceSummary<'a> {
+    name: &'a str,
+    role: &'a str,
+    monitored_prefixes: Vec<String>,
+    excluded_prefixes: Vec<String>,
+    vlans: Vec<u16>,
+    capture: HealthSnapshot,
+    ips: Vec<IpSummary<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct IpSummary<'a> {
+    ip: IpAddr,
+    vlan: String, // タグなしは空文字列、QinQは「外側.内側」
+    tx_packets: u64,
+    rx_packets: u64,
+    tx_bytes: u64,
+    rx_bytes: u64,
+    tx_tunnel_overhead_bytes: u64, // tx_bytesのうちトンネルのヘッダー分（数える設定のときだけ）
+    rx_tunnel_overhead_bytes: u64,
+    fragments: u64,
+    reassembly_failures: u64,
+    retransmissions: u64,
+    spurious_retransmissions: u64,
+    out_of_order: u64,
+    duplicate_acks: u64,
+    triple_duplicate_acks: u64,
+    window_size_changes: u64,
+    rtt_mean_seconds: Option<f64>,
+    protocols: BTreeMap<&'static str, TrafficSummary>, // パケットのあったプロトコルだけ
+    services: BTreeMap<&'a str, TrafficSummary>,
+    ports: Vec<PortSummary>, // 追跡した相手側のポート（送受信バイト数の多い順）
+}
+
+#[derive(Debug, Serialize)]
+struct PortSummary {
+    protocol: &'static str,
+    port: Option<u16>, // 上限を超えてまとめて数えたポートはnull
+    #[serde(flatten)]
+    traffic: TrafficSummary,
+}
+
+#[derive(Debug, Serialize)]
+struct TrafficSummary {
+    tx_packets: u64,
+    rx_packets: u64,
+    tx_bytes: u64,
+    rx_bytes: u64,
+}
+
+impl From<&TrafficStats> for TrafficSummary {
+    fn from(s: &TrafficStats) -> Self {
+        Self {
+            tx_packets: s.tx_packets,
+            rx_packets: s.rx_packets,
+            tx_bytes: s.tx_bytes,
+            rx_bytes: s.rx_bytes,
+        }
+    }
+}
+
+// 監視対象IPの累計値をインターフェースごとにJSONで書き出す
+// 一時ファイルに書いてからrenameするので、途中で止められても壊れたファイルは残らない
+pub fn write_summary(path: &Path, interfaces: &[InterfaceStats]) -> std::io::Result<()> {
+    let summary = Summary {
+        version: crate::version::VERSION,
+        stopped_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        interfaces: interfaces.iter().map(summarize_interface).collect(),
+    };
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let mut file = fs::File::create(&tmp_path)?;
+    serde_json::to_writer_pretty(&mut file, &summary)?;
+    file.write_all(b"\n")?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+fn summarize_interface<'a>(interface: &InterfaceStats<'a>) -> InterfaceSummary<'a> {
+    let targets = interface.targets;
+    let mut hosts: Vec<_> = interface
+        .stats
+        .iter()
+        .filter(|(key, _)| key.is_target(targets))
+        .collect();
+    hosts.sort_by_key(|(key, _)| **key);
+    let ips = hosts
+        .into_iter()
+        .map(|(key, s)| IpSummary {
+            ip: key.ip,
+            vlan: key.vlan.to_string(),
+            tx_packets: s.tx_packet_count,
+            rx_packets: s.rx_packet_count,
+            tx_bytes: s.tx_byte_count,
+            rx_bytes: s.rx_byte_count,
+            tx_tunnel_overhead_bytes: s.tx_tunnel_overhead_bytes,
+            rx_tunnel_overhead_bytes: s.rx_tunnel_overhead_bytes,
+            fragments: s.fragments,
+            reassembly_failures: s.reassembly_failures,
+            retransmissions: s.retransmissions,
+            spurious_retransmissions: s.spurious_retransmissions,
+            out_of_order: s.out_of_order,
+            duplicate_acks: s.duplicate_acks,
+            triple_duplicate_acks: s.triple_duplicate_acks,
+            window_size_changes: s.window_size_changes,
+            rtt_mean_seconds: s.rtt.map(|rtt| rtt.mean),
+            protocols: Protocol::ALL
+                .iter()
+                .zip(&s.protocols)
+                .filter(|(_, counts)| !counts.is_empty())
+                .map(|(protocol, counts)| (protocol.label(), counts.into()))
+                .collect(),
+            services: s
+                .services
+                .iter()
+                .map(|(service, traffic)| (interface.services.name(*service), traffic.into()))
+                .collect(),
+            ports: {
+                let mut ports: Vec<_> = s.ports.iter().collect();
+                // 累計の多い順。同じならプロトコルとポート番号の順
+                ports.sort_by_key(|(remote, traffic)| {
+                    (std::cmp::Reverse(traffic.total_bytes()), **remote)
+                });
+                ports
+                    .into_iter()
+                    .map(|(remote, traffic)| PortSummary {
+                        protocol: remote.protocol.label(),
+                        port: remote.port,
+                        traffic: traffic.into(),
+                    })
+                    .collect()
+            },
+        })
+        .collect();
+
+    InterfaceSummary {
+        name: interface.name,
+        role: interface.role,
+        monitored_prefixes: targets.networks().iter().map(|n| n.to_string()).collect(),
+        excluded_prefixes: targets
+            .excluded_networks()
+            .iter()
+            .map(|n| n.to_string())
+            .collect(),
+        vlans: targets.vlans().to_vec(),
+        capture: interface.capture,
+        ips,
+    }
+}
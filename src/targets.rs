@@ -0,0 +1,372 @@
+use crate::config::Config;
+use crate::vlan::VlanTags;
+use pnet::datalink;
+use pnet::ipnetwork::IpNetwork;
+use std::fmt;
+use std::net::IpAddr;
+
+// 2分木によるプレフィックスの集合（ビット単位のトライ）
+#[derive(Debug, Clone)]
+struct PrefixTrie {
+    nodes: Vec<TrieNode>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct TrieNode {
+    children: [Option<usize>; 2],
+    terminal: bool, // このノードまでのビット列がプレフィックスとして登録されている
+}
+
+impl PrefixTrie {
+    fn new() -> Self {
+        Self {
+            nodes: vec![TrieNode::default()],
+        }
+    }
+
+    // bitsは上位ビットに詰めたアドレス
+    fn insert(&mut self, bits: u128, prefix: u8) {
+        let mut index = 0;
+        for depth in 0..prefix {
+            let bit = ((bits >> (127 - depth)) & 1) as usize;
+            index = match self.nodes[index].children[bit] {
+                Some(child) => child,
+                None => {
+                    self.nodes.push(TrieNode::default());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[index].children[bit] = Some(child);
+                    child
+                }
+            };
+        }
+        self.nodes[index].terminal = true;
+    }
+
+    // アドレスに一致する登録済みプレフィックスの長さを短い順に返す
+    fn matches(&self, bits: u128, width: u8) -> impl Iterator<Item = u8> + '_ {
+        let mut index = Some(0);
+        (0..=width)
+            .map_while(move |depth| {
+                let node = &self.nodes[index?];
+                index = if depth < width {
+                    node.children[((bits >> (127 - depth)) & 1) as usize]
+                } else {
+                    None
+                };
+                Some((depth, node.terminal))
+            })
+            .filter(|&(_, terminal)| terminal)
+            .map(|(depth, _)| depth)
+    }
+}
+
+// アドレスをトライ用のビット列とビット幅に変換
+fn address_bits(ip: &IpAddr) -> (u128, u8) {
+    match ip {
+        IpAddr::V4(v4) => ((u32::from(*v4) as u128) << 96, 32),
+        IpAddr::V6(v6) => (u128::from(*v6), 128),
+    }
+}
+
+// 監視対象のプレフィックス（IPv4/IPv6のCIDR、包含と除外）とVLAN
+#[derive(Debug, Clone)]
+pub struct TargetNetworks {
+    include: Vec<IpNetwork>,
+    exclude: Vec<IpNetwork>,
+    vlans: Vec<u16>,          // 空ならすべて。0はタグなしのフレーム
+    awaiting: Option<String>, // プレフィックスを自動検出できていないインターフェース
+    include_v4: PrefixTrie,
+    include_v6: PrefixTrie,
+    exclude_v4: PrefixTrie,
+    exclude_v6: PrefixTrie,
+}
+
+impl TargetNetworks {
+    pub fn new(include: Vec<IpNetwork>, exclude: Vec<IpNetwork>) -> Self {
+        let include = normalize(include);
+        let exclude = normalize(exclude);
+
+        let mut include_v4 = PrefixTrie::new();
+        let mut include_v6 = PrefixTrie::new();
+        let mut exclude_v4 = PrefixTrie::new();
+        let mut exclude_v6 = PrefixTrie::new();
+        for network in &include {
+            let trie = if network.is_ipv4() {
+                &mut include_v4
+            } else {
+                &mut include_v6
+            };
+            trie.insert(address_bits(&network.network()).0, network.prefix());
+        }
+        for network in &exclude {
+            let trie = if network.is_ipv4() {
+                &mut exclude_v4
+            } else {
+                &mut exclude_v6
+            };
+            trie.insert(address_bits(&network.network()).0, network.prefix());
+        }
+
+        Self {
+            include,
+            exclude,
+            vlans: Vec::new(),
+            awaiting: None,
+            include_v4,
+            include_v6,
+            exclude_v4,
+            exclude_v6,
+        }
+    }
+
+    pub fn with_vlans(mut self, mut vlans: Vec<u16>) -> Self {
+        vlans.sort_unstable();
+        vlans.dedup();
+        self.vlans = vlans;
+        self
+    }
+
+    // 設定の包含・除外プレフィックスとVLANから作る
+    // 包含が指定されていなければキャプチャするインターフェースのプレフィックスを使用
+    // インターフェースが存在しないかアドレスがまだなければ、空の監視対象で始めて後で検出し直す
+    pub fn from_config(config: &Config, interface: Option<&str>) -> Result<Self, String> {
+        if !config.include.is_empty() {
+            return Ok(Self::new(config.include.clone(), config.exclude.clone())
+                .with_vlans(config.vlans.clone()));
+        }
+
+        let interface_name = match interface {
+            Some(name) => name,
+            None => {
+                return Err("Subnet cannot be auto-detected when reading from a file\n\nPass --include <cidr> or set `include` in the config file.".to_string());
+            }
+        };
+        let networks = get_interface_networks(interface_name).unwrap_or_default();
+        Ok(
+            Self::detected(interface_name, networks, config.exclude.clone())
+                .with_vlans(config.vlans.clone()),
+        )
+    }
+
+    // インターフェースから検出したプレフィックスで作る（IPv6だけのインターフェースも含む）
+    fn detected(interface_name: &str, networks: Vec<IpNetwork>, exclude: Vec<IpNetwork>) -> Self {
+        let mut targets = Self::new(networks, exclude);
+        if targets.include.is_empty() {
+            targets.awaiting = Some(interface_name.to_string());
+        }
+        targets
+    }
+
+    pub fn awaiting_detection(&self) -> bool {
+        self.awaiting.is_some()
+    }
+
+    pub fn networks(&self) -> &[IpNetwork] {
+        &self.include
+    }
+
+    pub fn excluded_networks(&self) -> &[IpNetwork] {
+        &self.exclude
+    }
+
+    // 包含プレフィックスのいずれかが送信元か宛先に含まれるパケットに一致するBPF式
+    // 除外プレフィックスは監視対象との通信もあるためフィルタには含めない
+    pub fn bpf_expression(&self) -> Option<String> {
+        if self.include.is_empty() {
+            return None;
+        }
+        let nets: Vec<String> = self.include.iter().map(|n| format!("net {}", n)).collect();
+        Some(nets.join(" or "))
+    }
+
+    pub fn vlans(&self) -> &[u16] {
+        &self.vlans
+    }
+
+    // VLANで絞り込んでいなければ常にtrue
+    // QinQのフレームはどちらかのタグが一致すればよい
+    pub fn matches_vlan(&self, tags: &VlanTags) -> bool {
+        if self.vlans.is_empty() {
+            return true;
+        }
+        if !tags.is_tagged() {
+            return self.vlans.contains(&0);
+        }
+        tags.ids().iter().any(|id| self.vlans.contains(id))
+    }
+
+    // いずれかの包含プレフィックスに含まれ、どの除外プレフィックスにも含まれない
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let (bits, width) = address_bits(ip);
+        let (include, exclude) = match ip {
+            IpAddr::V4(_) => (&self.include_v4, &self.exclude_v4),
+            IpAddr::V6(_) => (&self.include_v6, &self.exclude_v6),
+        };
+
+        if exclude.matches(bits, width).next().is_some() {
+            return false;
+        }
+        include
+            .matches(bits, width)
+            .any(|prefix| is_host_address(bits, width, prefix))
+    }
+}
+
+// インターフェースに設定されたプレフィックス（IPv4と、リンクローカルを除くIPv6）
+fn get_interface_networks(interface_name: &str) -> Option<Vec<IpNetwork>> {
+    let interface = datalink::interfaces()
+        .into_iter()
+        .find(|interface| interface.name == interface_name)?;
+
+    Some(
+        interface
+            .ips
+            .into_iter()
+            .filter(|network| match network {
+                IpNetwork::V4(_) => true,
+                IpNetwork::V6(v6) => {
+                    let first = v6.ip().segments()[0];
+                    !v6.ip().is_loopback() && (first & 0xffc0) != 0xfe80
+                }
+            })
+            .collect(),
+    )
+}
+
+// IPv4ではネットワークアドレスとブロードキャストアドレスを除く
+fn is_host_address(bits: u128, width: u8, prefix: u8) -> bool {
+    if width != 32 || prefix >= 31 {
+        return true;
+    }
+    let host_mask = (1u128 << (32 - prefix)) - 1;
+    let host = (bits >> 96) & host_mask;
+    host != 0 && host != host_mask
+}
+
+// アドレス部分をネットワークアドレスに揃え、重複を取り除く
+fn normalize(networks: Vec<IpNetwork>) -> Vec<IpNetwork> {
+    let mut normalized: Vec<IpNetwork> = Vec::new();
+    for network in networks {
+        let network = IpNetwork::new(network.network(), network.prefix()).unwrap();
+        if !normalized.contains(&network) {
+            normalized.push(network);
+        }
+    }
+    normalized
+}
+
+impl fmt::Display for TargetNetworks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(interface) = &self.awaiting {
+            write!(f, "none yet (waiting for an address on {})", interface)?;
+        }
+        let include: Vec<String> = self.include.iter().map(|n| n.to_string()).collect();
+        write!(f, "{}", include.join(", "))?;
+        if !self.exclude.is_empty() {
+            let exclude: Vec<String> = self.exclude.iter().map(|n| n.to_string()).collect();
+            write!(f, " (excluding {})", exclude.join(", "))?;
+        }
+        if !self.vlans.is_empty() {
+            let vlans: Vec<String> = self
+                .vlans
+                .iter()
+                .map(|id| match id {
+                    0 => "untagged".to_string(),
+                    id => id.to_string(),
+                })
+                .collect();
+            write!(f, " on VLAN {}", vlans.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn targets(include: &[&str], exclude: &[&str]) -> TargetNetworks {
+        let parse = |networks: &[&str]| networks.iter().map(|n| n.parse().unwrap()).collect();
+        TargetNetworks::new(parse(include), parse(exclude))
+    }
+
+    fn contains(targets: &TargetNetworks, ip: &str) -> bool {
+        targets.contains(&ip.parse().unwrap())
+    }
+
+    #[test]
+    fn exclude_takes_precedence_over_include() {
+        let targets = targets(&["10.0.0.0/8", "10.1.2.0/24"], &["10.1.0.0/16"]);
+        assert!(contains(&targets, "10.2.0.1"));
+        // より長い包含プレフィックスがあっても除外が優先
+        assert!(!contains(&targets, "10.1.2.3"));
+        assert!(!contains(&targets, "10.1.255.1"));
+        assert!(!contains(&targets, "192.168.0.1"));
+    }
+
+    #[test]
+    fn excludes_ipv4_network_and_broadcast_addresses() {
+        let targets = targets(&["192.168.1.0/24"], &[]);
+        assert!(!contains(&targets, "192.168.1.0"));
+        assert!(contains(&targets, "192.168.1.1"));
+        assert!(contains(&targets, "192.168.1.254"));
+        assert!(!contains(&targets, "192.168.1.255"));
+    }
+
+    #[test]
+    fn short_prefixes_match_everything_in_their_family() {
+        let targets = targets(&["0.0.0.0/0", "::/0"], &[]);
+        assert!(contains(&targets, "203.0.113.7"));
+        assert!(contains(&targets, "2001:db8::1"));
+        assert!(!contains(&targets, "0.0.0.0"));
+        assert!(!contains(&targets, "255.255.255.255"));
+    }
+
+    #[test]
+    fn point_to_point_and_host_prefixes_keep_every_address() {
+        let targets = targets(&["10.0.0.0/31", "10.0.1.5/32", "2001:db8::1/128"], &[]);
+        // /31と/32にはネットワークアドレスとブロードキャストアドレスがない（RFC 3021）
+        assert!(contains(&targets, "10.0.0.0"));
+        assert!(contains(&targets, "10.0.0.1"));
+        assert!(!contains(&targets, "10.0.0.2"));
+        assert!(contains(&targets, "10.0.1.5"));
+        assert!(!contains(&targets, "10.0.1.4"));
+        assert!(contains(&targets, "2001:db8::1"));
+        assert!(!contains(&targets, "2001:db8::2"));
+    }
+
+    #[test]
+    fn ipv6_prefixes_do_not_match_ipv4_addresses() {
+        // 0a00::/8はトライ上で10.0.0.0/8と同じビット列になる
+        let v6_only = targets(&["a00::/8", "::ffff:0:0/96"], &[]);
+        assert!(!contains(&v6_only, "10.0.0.1"));
+        assert!(contains(&v6_only, "a00::1"));
+        assert!(contains(&v6_only, "::ffff:10.0.0.1"));
+
+        // IPv4の除外プレフィックスはIPv6アドレスに影響しない
+        let v4_excluded = targets(&["a00::/8"], &["10.0.0.0/8"]);
+        assert!(contains(&v4_excluded, "a00::1"));
+    }
+
+    #[test]
+    fn normalizes_host_bits_and_duplicates() {
+        let targets = targets(&["10.0.0.77/24", "10.0.0.0/24"], &[]);
+        assert_eq!(targets.networks(), &["10.0.0.0/24".parse().unwrap()]);
+        assert!(contains(&targets, "10.0.0.1"));
+    }
+
+    #[test]
+    fn detects_prefixes_on_an_ipv6_only_interface() {
+        let targets =
+            TargetNetworks::detected("eth0", vec!["2001:db8:1::/64".parse().unwrap()], vec![]);
+        assert!(!targets.awaiting_detection());
+        assert!(contains(&targets, "2001:db8:1::10"));
+
+        let targets = TargetNetworks::detected("eth0", vec![], vec![]);
+        assert!(targets.awaiting_detection());
+        assert_eq!(
+            targets.to_string(),
+            "none yet (waiting for an address on eth0)"
+        );
+    }
+}
@@ -0,0 +1,706 @@
+use pnet::packet::tcp::{TcpFlags, TcpOptionNumbers, TcpPacket};
+use pnet::packet::Packet;
+use std::net::IpAddr;
+use std::time::Duration;
+
+// 監視対象IPから見たTCPフローの4タプル
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    pub local_ip: IpAddr,
+    pub local_port: u16,
+    pub remote_ip: IpAddr,
+    pub remote_port: u16,
+}
+
+impl FlowKey {
+    // 監視対象IPが送信元のパケット
+    pub fn outbound(src_ip: IpAddr, dst_ip: IpAddr, tcp: &TcpPacket) -> Self {
+        Self {
+            local_ip: src_ip,
+            local_port: tcp.get_source(),
+            remote_ip: dst_ip,
+            remote_port: tcp.get_destination(),
+        }
+    }
+
+    // 監視対象IPが宛先のパケット
+    pub fn inbound(src_ip: IpAddr, dst_ip: IpAddr, tcp: &TcpPacket) -> Self {
+        Self {
+            local_ip: dst_ip,
+            local_port: tcp.get_destination(),
+            remote_ip: src_ip,
+            remote_port: tcp.get_source(),
+        }
+    }
+}
+
+// RFC 1982 形式のシーケンス番号比較（2^32 の折り返しを考慮）
+pub fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+pub fn seq_le(a: u32, b: u32) -> bool {
+    a == b || seq_lt(a, b)
+}
+
+pub fn seq_gt(a: u32, b: u32) -> bool {
+    seq_lt(b, a)
+}
+
+pub fn seq_ge(a: u32, b: u32) -> bool {
+    a == b || seq_gt(a, b)
+}
+
+// セグメントの分類結果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentKind {
+    New,
+    Retransmission,
+    SpuriousRetransmission, // 既にACK済みの範囲の再送
+    OutOfOrder,             // 先に見えていた欠落区間を埋めるセグメント
+}
+
+// 欠落区間を覚えておく上限（古いものから捨てる）
+const MAX_HOLES: usize = 8;
+
+// 片方向のシーケンス番号の追跡
+#[derive(Debug, Default)]
+struct SeqTracker {
+    next_seq: Option<u32>,    // 次に期待するシーケンス番号
+    highest_ack: Option<u32>, // 相手から受け取った最大のACK番号
+    holes: Vec<(u32, u32)>,   // 観測されていない区間 [開始, 終了)
+}
+
+impl SeqTracker {
+    fn on_segment(&mut self, seq: u32, len: u32, syn: bool) -> SegmentKind {
+        // SYNで新しい接続が始まった場合は状態を作り直す（SYNの再送は除く）
+        if syn && self.is_new_syn(seq, len) {
+            *self = Self::default();
+        }
+
+        let end = seq.wrapping_add(len);
+        let next_seq = match self.next_seq {
+            Some(next_seq) => next_seq,
+            None => {
+                self.next_seq = Some(end);
+                return SegmentKind::New;
+            }
+        };
+
+        if seq_ge(seq, next_seq) {
+            if seq_gt(seq, next_seq) {
+                if self.holes.len() >= MAX_HOLES {
+                    self.holes.remove(0);
+                }
+                self.holes.push((next_seq, seq));
+            }
+            self.next_seq = Some(end);
+            return SegmentKind::New;
+        }
+
+        if seq_gt(end, next_seq) {
+            self.next_seq = Some(end);
+        }
+
+        if let Some(ack) = self.highest_ack {
+            if seq_le(end, ack) {
+                return SegmentKind::SpuriousRetransmission;
+            }
+        }
+
+        if let Some(index) = self
+            .holes
+            .iter()
+            .position(|&(start, stop)| seq_ge(seq, start) && seq_lt(seq, stop))
+        {
+            let (start, stop) = self.holes.remove(index);
+            if seq_gt(seq, start) {
+                self.holes.push((start, seq));
+            }
+            if seq_lt(end, stop) {
+                self.holes.push((end, stop));
+            }
+            return SegmentKind::OutOfOrder;
+        }
+
+        SegmentKind::Retransmission
+    }
+
+    // SYNの再送ではなく、新しい接続のSYNか
+    fn is_new_syn(&self, seq: u32, len: u32) -> bool {
+        self.next_seq != Some(seq.wrapping_add(len))
+    }
+
+    fn on_ack(&mut self, ack: u32) {
+        match self.highest_ack {
+            Some(current) if seq_le(ack, current) => {}
+            _ => self.highest_ack = Some(ack),
+        }
+        // ACK済みの欠落区間は、キャプチャで見えなかっただけなので捨てる
+        self.holes.retain(|&(_, stop)| seq_gt(stop, ack));
+    }
+}
+
+// 片方向のACKの追跡（重複ACKの判定用）
+#[derive(Debug, Default)]
+struct AckTracker {
+    last_ack: Option<u32>,       // 最後に見たACK番号
+    last_window: Option<u16>,    // 最後に見たウィンドウ
+    highest_sacked: Option<u32>, // SACKで通知された最大の右端
+    duplicate_count: u32,        // 同じACK番号の連続重複回数
+    synchronized: bool,          // この接続のACK番号を追えているか
+}
+
+// ACKの判定結果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckKind {
+    Duplicate,
+    TripleDuplicate, // 高速再送のきっかけになる3回目の重複ACK
+}
+
+impl AckTracker {
+    // RFC 5681 の定義に従って重複ACKを判定する
+    // sender: ACKを受け取る側（データ送信側）のシーケンス追跡
+    fn on_ack(&mut self, tcp: &TcpPacket, sender: &SeqTracker) -> Option<AckKind> {
+        let flags = tcp.get_flags();
+        let ack = tcp.get_acknowledgement();
+        let window = tcp.get_window();
+        let sack_edge = sack_right_edge(tcp);
+
+        // 途中から見えた接続では、last_ackが前の接続のものかもしれないので
+        // ハンドシェイクかACKが進むのを見るまでは数えない
+        let is_duplicate = self.synchronized
+            && self.last_ack == Some(ack)
+            && self.last_window == Some(window)
+            && tcp.payload().is_empty()
+            && flags & (TcpFlags::SYN | TcpFlags::FIN | TcpFlags::RST) == 0
+            // 未ACKのデータが残っている場合のみ
+            && matches!(sender.next_seq, Some(next_seq) if seq_gt(next_seq, ack))
+            // SACK利用時は新しい範囲をSACKしている場合のみ（RFC 6675）
+            && match (sack_edge, self.highest_sacked) {
+                (Some(edge), Some(highest)) => seq_gt(edge, highest),
+                _ => true,
+            };
+
+        let advanced = match self.last_ack {
+            Some(last_ack) => seq_gt(ack, last_ack),
+            None => true,
+        };
+        if advanced {
+            // ACKが進んだら連続回数とSACK情報をリセット
+            self.synchronized |= self.last_ack.is_some();
+            self.last_ack = Some(ack);
+            self.duplicate_count = 0;
+            self.highest_sacked = None;
+        }
+        self.last_window = Some(window);
+        if let Some(edge) = sack_edge {
+            match self.highest_sacked {
+                Some(highest) if seq_ge(highest, edge) => {}
+                _ => self.highest_sacked = Some(edge),
+            }
+        }
+
+        if !is_duplicate {
+            return None;
+        }
+        self.duplicate_count += 1;
+        if self.duplicate_count == 3 {
+            Some(AckKind::TripleDuplicate)
+        } else {
+            Some(AckKind::Duplicate)
+        }
+    }
+}
+
+// SACKブロックの右端の最大値
+fn sack_right_edge(tcp: &TcpPacket) -> Option<u32> {
+    let mut highest: Option<u32> = None;
+    for option in tcp.get_options_iter() {
+        if option.get_number() != TcpOptionNumbers::SACK {
+            continue;
+        }
+        for block in option.payload().chunks_exact(8) {
+            let right = u32::from_be_bytes([block[4], block[5], block[6], block[7]]);
+            match highest {
+                Some(current) if seq_ge(current, right) => {}
+                _ => highest = Some(right),
+            }
+        }
+    }
+    highest
+}
+
+// TCPタイムスタンプオプション (TSval, TSecr)
+fn timestamps(tcp: &TcpPacket) -> Option<(u32, u32)> {
+    tcp.get_options_iter()
+        .find(|option| option.get_number() == TcpOptionNumbers::TIMESTAMPS)
+        .and_then(|option| {
+            let data = option.payload();
+            if data.len() < 8 {
+                return None;
+            }
+            let tsval = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+            let tsecr = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+            Some((tsval, tsecr))
+        })
+}
+
+// パッシブRTT計測の状態
+// キャプチャ地点から相手ホストまでの往復（WAN側の区間）を測る
+#[derive(Debug, Default)]
+struct RttTracker {
+    syn_sent: Option<(u32, Option<Duration>)>, // 監視対象IPが送ったSYNまたはSYN-ACK（seq, 時刻）
+    pending_data: Option<(u32, Duration)>,     // ACK待ちのデータ（終端seq, 時刻）
+    pending_tsval: Option<(u32, Duration)>,    // エコー待ちのTSval
+    uses_timestamps: bool,                     // タイムスタンプオプションが使われているか
+}
+
+impl RttTracker {
+    fn on_outbound(&mut self, tcp: &TcpPacket, segment: Option<SegmentKind>, now: Duration) {
+        let flags = tcp.get_flags();
+        let seq = tcp.get_sequence();
+
+        if flags & TcpFlags::SYN != 0 {
+            self.syn_sent = match self.syn_sent {
+                // SYNの再送はどの応答に対応するか分からないので計測しない（Karnのアルゴリズム）
+                Some((sent_seq, _)) if sent_seq == seq => Some((seq, None)),
+                _ => Some((seq, Some(now))),
+            };
+        }
+
+        let tsval = timestamps(tcp).map(|(tsval, _)| tsval);
+        if tsval.is_some() {
+            self.uses_timestamps = true;
+        }
+
+        match segment {
+            Some(SegmentKind::New) => {
+                if let (None, Some(tsval)) = (self.pending_tsval, tsval) {
+                    self.pending_tsval = Some((tsval, now));
+                }
+                if self.pending_data.is_none() && !self.uses_timestamps {
+                    let end = seq.wrapping_add(segment_len(tcp));
+                    self.pending_data = Some((end, now));
+                }
+            }
+            Some(_) => {
+                // 再送を含む区間はサンプルにしない（Karnのアルゴリズム）
+                self.pending_tsval = None;
+                self.pending_data = None;
+            }
+            None => {}
+        }
+    }
+
+    fn on_inbound(&mut self, tcp: &TcpPacket, now: Duration) -> Option<Duration> {
+        let flags = tcp.get_flags();
+        if flags & TcpFlags::ACK == 0 {
+            return None;
+        }
+        let ack = tcp.get_acknowledgement();
+        let mut sample = None;
+
+        // ハンドシェイク: SYN→SYN-ACK、またはSYN-ACK→ACK
+        if let Some((syn_seq, sent_at)) = self.syn_sent {
+            if ack == syn_seq.wrapping_add(1) {
+                sample = sent_at.map(|sent_at| now.saturating_sub(sent_at));
+                self.syn_sent = None;
+            }
+        }
+
+        // タイムスタンプのエコー
+        if let (Some((tsval, sent_at)), Some((_, tsecr))) = (self.pending_tsval, timestamps(tcp)) {
+            if tsecr == tsval {
+                sample = sample.or(Some(now.saturating_sub(sent_at)));
+                self.pending_tsval = None;
+            } else if seq_gt(tsecr, tsval) {
+                // より新しい値がエコーされた場合は対応が取れないので捨てる
+                self.pending_tsval = None;
+            }
+        }
+
+        // データ→ACK
+        if let Some((end, sent_at)) = self.pending_data {
+            if seq_ge(ack, end) {
+                sample = sample.or(Some(now.saturating_sub(sent_at)));
+                self.pending_data = None;
+            }
+        }
+
+        sample
+    }
+}
+
+// 1つのTCPフローの状態
+#[derive(Debug, Default)]
+pub struct FlowState {
+    local: SeqTracker,          // 監視対象IPが送信するデータ
+    remote: SeqTracker,         // 相手が送信するデータ
+    local_acks: AckTracker,     // 監視対象IPが送信するACK
+    remote_acks: AckTracker,    // 相手が送信するACK
+    local_window: Option<u16>,  // 監視対象IPが最後に広告したウィンドウ
+    remote_window: Option<u16>, // 相手が最後に広告したウィンドウ
+    rtt: RttTracker,
+    last_seen: Duration, // 最後にパケットを見た時刻
+}
+
+// 1パケットの解析結果
+pub struct TcpObservation {
+    pub segment: Option<SegmentKind>,
+    pub ack: Option<AckKind>,
+    pub window_changed: bool,
+    pub rtt_sample: Option<Duration>,
+}
+
+// シーケンス空間を消費する長さ（SYN/FINは1として数える）
+fn segment_len(tcp: &TcpPacket) -> u32 {
+    let flags = tcp.get_flags();
+    let mut len = tcp.payload().len() as u32;
+    if flags & TcpFlags::SYN != 0 {
+        len += 1;
+    }
+    if flags & TcpFlags::FIN != 0 {
+        len += 1;
+    }
+    len
+}
+
+impl FlowState {
+    pub fn last_seen(&self) -> Duration {
+        self.last_seen
+    }
+
+    // 監視対象IPが送信したパケット
+    pub fn on_outbound(&mut self, tcp: &TcpPacket, now: Duration) -> TcpObservation {
+        self.on_syn(tcp, true);
+        self.last_seen = now;
+        let flags = tcp.get_flags();
+        let segment = observe_segment(&mut self.local, tcp);
+        self.rtt.on_outbound(tcp, segment, now);
+        let mut ack = None;
+        if flags & TcpFlags::ACK != 0 {
+            ack = self.local_acks.on_ack(tcp, &self.remote);
+            self.remote.on_ack(tcp.get_acknowledgement());
+        }
+        let window_changed = update_window(&mut self.local_window, tcp.get_window());
+        TcpObservation {
+            segment,
+            ack,
+            window_changed,
+            rtt_sample: None,
+        }
+    }
+
+    // 監視対象IPが受信したパケット
+    pub fn on_inbound(&mut self, tcp: &TcpPacket, now: Duration) -> TcpObservation {
+        self.on_syn(tcp, false);
+        self.last_seen = now;
+        let flags = tcp.get_flags();
+        let segment = observe_segment(&mut self.remote, tcp);
+        let rtt_sample = self.rtt.on_inbound(tcp, now);
+        let mut ack = None;
+        if flags & TcpFlags::ACK != 0 {
+            ack = self.remote_acks.on_ack(tcp, &self.local);
+            self.local.on_ack(tcp.get_acknowledgement());
+        }
+        let window_changed = update_window(&mut self.remote_window, tcp.get_window());
+        TcpObservation {
+            segment,
+            ack,
+            window_changed,
+            rtt_sample,
+        }
+    }
+
+    // 同じ4タプルで新しい接続が始まった場合は、前の接続の状態をすべて捨てる（SYNの再送は除く）
+    fn on_syn(&mut self, tcp: &TcpPacket, outbound: bool) {
+        let flags = tcp.get_flags();
+        let sender = if outbound { &self.local } else { &self.remote };
+        if flags & TcpFlags::SYN == 0 || !sender.is_new_syn(tcp.get_sequence(), segment_len(tcp)) {
+            return;
+        }
+        if flags & TcpFlags::ACK == 0 {
+            *self = Self::default();
+        } else {
+            // SYNを見逃してSYN-ACKだけが見えた場合も、前の接続のACK番号とは比べない
+            self.local_acks = AckTracker::default();
+            self.remote_acks = AckTracker::default();
+        }
+        self.local_acks.synchronized = true;
+        self.remote_acks.synchronized = true;
+    }
+}
+
+fn observe_segment(tracker: &mut SeqTracker, tcp: &TcpPacket) -> Option<SegmentKind> {
+    let flags = tcp.get_flags();
+    let len = segment_len(tcp);
+    // データを含まないACKやRSTは分類しない
+    if len == 0 || flags & TcpFlags::RST != 0 {
+        return None;
+    }
+    Some(tracker.on_segment(tcp.get_sequence(), len, flags & TcpFlags::SYN != 0))
+}
+
+fn update_window(last: &mut Option<u16>, window: u16) -> bool {
+    let changed = matches!(*last, Some(previous) if previous != window);
+    *last = Some(window);
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYN: u8 = TcpFlags::SYN;
+    const ACK: u8 = TcpFlags::ACK;
+
+    // TCPヘッダー（オプションは4バイト単位に詰める）とペイロードからセグメントを組み立てる
+    fn segment(
+        seq: u32,
+        ack: u32,
+        flags: u8,
+        window: u16,
+        options: &[u8],
+        payload: usize,
+    ) -> Vec<u8> {
+        let mut options = options.to_vec();
+        while !options.len().is_multiple_of(4) {
+            options.push(1); // NOP
+        }
+        let header_len = 20 + options.len();
+        let mut data = vec![0u8; header_len + payload];
+        data[0..2].copy_from_slice(&40000u16.to_be_bytes());
+        data[2..4].copy_from_slice(&443u16.to_be_bytes());
+        data[4..8].copy_from_slice(&seq.to_be_bytes());
+        data[8..12].copy_from_slice(&ack.to_be_bytes());
+        data[12] = ((header_len / 4) as u8) << 4;
+        data[13] = flags;
+        data[14..16].copy_from_slice(&window.to_be_bytes());
+        data[20..header_len].copy_from_slice(&options);
+        data
+    }
+
+    fn sack(left: u32, right: u32) -> Vec<u8> {
+        let mut option = vec![5, 10];
+        option.extend_from_slice(&left.to_be_bytes());
+        option.extend_from_slice(&right.to_be_bytes());
+        option
+    }
+
+    fn outbound(flow: &mut FlowState, data: &[u8]) -> TcpObservation {
+        flow.on_outbound(&TcpPacket::new(data).unwrap(), Duration::ZERO)
+    }
+
+    fn inbound(flow: &mut FlowState, data: &[u8]) -> TcpObservation {
+        flow.on_inbound(&TcpPacket::new(data).unwrap(), Duration::ZERO)
+    }
+
+    // 監視対象が100から、相手が5000から始める接続のハンドシェイク
+    fn handshake(flow: &mut FlowState) {
+        outbound(flow, &segment(100, 0, SYN, 65535, &[], 0));
+        inbound(flow, &segment(5000, 101, SYN | ACK, 65535, &[], 0));
+        outbound(flow, &segment(101, 5001, ACK, 65535, &[], 0));
+    }
+
+    #[test]
+    fn sequence_comparison_wraps_around() {
+        assert!(seq_lt(0xffff_fff0, 0x10));
+        assert!(seq_gt(0x10, 0xffff_fff0));
+        assert!(!seq_lt(0x10, 0xffff_fff0));
+        assert!(seq_le(5, 5) && seq_ge(5, 5));
+        assert!(!seq_lt(5, 5) && !seq_gt(5, 5));
+        assert!(seq_le(u32::MAX, 0));
+        assert!(seq_ge(0, u32::MAX));
+    }
+
+    #[test]
+    fn classifies_segments_across_the_wrap() {
+        let mut tracker = SeqTracker::default();
+        assert_eq!(
+            tracker.on_segment(0xffff_ff00, 0x100, false),
+            SegmentKind::New
+        );
+        // 2^32を越えて0から続く
+        assert_eq!(tracker.on_segment(0, 1000, false), SegmentKind::New);
+        assert_eq!(tracker.next_seq, Some(1000));
+        assert_eq!(
+            tracker.on_segment(0xffff_ff00, 0x100, false),
+            SegmentKind::Retransmission
+        );
+        tracker.on_ack(1000);
+        assert_eq!(
+            tracker.on_segment(0, 1000, false),
+            SegmentKind::SpuriousRetransmission
+        );
+    }
+
+    #[test]
+    fn fills_holes_out_of_order() {
+        let mut tracker = SeqTracker::default();
+        assert_eq!(tracker.on_segment(1000, 100, false), SegmentKind::New);
+        // 1100から1200が欠けている
+        assert_eq!(tracker.on_segment(1200, 100, false), SegmentKind::New);
+        assert_eq!(tracker.holes, vec![(1100, 1200)]);
+        assert_eq!(tracker.on_segment(1100, 50, false), SegmentKind::OutOfOrder);
+        assert_eq!(tracker.holes, vec![(1150, 1200)]);
+        assert_eq!(tracker.on_segment(1150, 50, false), SegmentKind::OutOfOrder);
+        assert!(tracker.holes.is_empty());
+        // 埋まった区間をもう一度送るのは再送
+        assert_eq!(
+            tracker.on_segment(1100, 100, false),
+            SegmentKind::Retransmission
+        );
+    }
+
+    #[test]
+    fn forgets_the_oldest_hole_beyond_max_holes() {
+        let mut tracker = SeqTracker::default();
+        tracker.on_segment(0, 100, false);
+        // 100ずつ空けて送り、MAX_HOLES+1個の欠落区間を作る
+        for i in 0..=MAX_HOLES as u32 {
+            tracker.on_segment(200 + i * 200, 100, false);
+        }
+        assert_eq!(tracker.holes.len(), MAX_HOLES);
+        assert_eq!(
+            tracker.on_segment(100, 100, false),
+            SegmentKind::Retransmission
+        );
+        let newest = 200 + MAX_HOLES as u32 * 200 - 100;
+        assert_eq!(
+            tracker.on_segment(newest, 100, false),
+            SegmentKind::OutOfOrder
+        );
+    }
+
+    #[test]
+    fn classifies_each_direction_separately() {
+        let mut flow = FlowState::default();
+        let sent = outbound(&mut flow, &segment(1000, 0, ACK, 65535, &[], 100));
+        assert_eq!(sent.segment, Some(SegmentKind::New));
+        // 相手のシーケンス空間は別に追跡する
+        let received = inbound(&mut flow, &segment(1000, 900, ACK, 65535, &[], 100));
+        assert_eq!(received.segment, Some(SegmentKind::New));
+        let resent = outbound(&mut flow, &segment(1000, 1100, ACK, 65535, &[], 100));
+        assert_eq!(resent.segment, Some(SegmentKind::Retransmission));
+        // データのないACKは分類しない
+        let ack = inbound(&mut flow, &segment(1100, 1100, ACK, 65535, &[], 0));
+        assert_eq!(ack.segment, None);
+        // ACK済みの範囲の再送
+        let spurious = outbound(&mut flow, &segment(1000, 1100, ACK, 65535, &[], 100));
+        assert_eq!(spurious.segment, Some(SegmentKind::SpuriousRetransmission));
+    }
+
+    #[test]
+    fn syn_on_a_reused_tuple_resets_the_flow() {
+        let mut flow = FlowState::default();
+        handshake(&mut flow);
+        outbound(&mut flow, &segment(101, 5001, ACK, 65535, &[], 1000));
+        inbound(&mut flow, &segment(5001, 1101, ACK, 65535, &[], 0));
+        assert!(flow.local_acks.last_ack.is_some());
+
+        // 同じ4タプルで別のシーケンス番号のSYN
+        let observation = outbound(&mut flow, &segment(900_000, 0, SYN, 65535, &[], 0));
+        assert_eq!(observation.segment, Some(SegmentKind::New));
+        assert_eq!(flow.local.next_seq, Some(900_001));
+        assert_eq!(flow.remote.next_seq, None);
+        assert_eq!(flow.local_acks.last_ack, None);
+        assert_eq!(flow.remote_acks.last_ack, None);
+        // RTTの計測待ちは新しいSYNのものだけ
+        assert_eq!(flow.rtt.pending_data.map(|(end, _)| end), Some(900_001));
+
+        // SYNの再送では作り直さない
+        let observation = outbound(&mut flow, &segment(900_000, 0, SYN, 65535, &[], 0));
+        assert_eq!(observation.segment, Some(SegmentKind::Retransmission));
+        assert_eq!(flow.local.next_seq, Some(900_001));
+    }
+
+    #[test]
+    fn counts_a_pure_repeated_ack_as_duplicate() {
+        let mut flow = FlowState::default();
+        handshake(&mut flow);
+        outbound(&mut flow, &segment(101, 5001, ACK, 65535, &[], 1000));
+        outbound(&mut flow, &segment(1101, 5001, ACK, 65535, &[], 1000));
+        assert_eq!(
+            inbound(&mut flow, &segment(5001, 1101, ACK, 65535, &[], 0)).ack,
+            None
+        );
+        for expected in [
+            AckKind::Duplicate,
+            AckKind::Duplicate,
+            AckKind::TripleDuplicate,
+        ] {
+            let observation = inbound(&mut flow, &segment(5001, 1101, ACK, 65535, &[], 0));
+            assert_eq!(observation.ack, Some(expected));
+        }
+    }
+
+    #[test]
+    fn counts_duplicates_on_a_flow_seen_mid_stream() {
+        let mut flow = FlowState::default();
+        outbound(&mut flow, &segment(101, 5001, ACK, 65535, &[], 1000));
+        outbound(&mut flow, &segment(1101, 5001, ACK, 65535, &[], 1000));
+        // 最初に見たACKは起点にするだけで、その繰り返しは数えない
+        inbound(&mut flow, &segment(5001, 101, ACK, 65535, &[], 0));
+        assert_eq!(
+            inbound(&mut flow, &segment(5001, 101, ACK, 65535, &[], 0)).ack,
+            None
+        );
+        // ACKが進んだ後は数える
+        inbound(&mut flow, &segment(5001, 1101, ACK, 65535, &[], 0));
+        assert_eq!(
+            inbound(&mut flow, &segment(5001, 1101, ACK, 65535, &[], 0)).ack,
+            Some(AckKind::Duplicate)
+        );
+    }
+
+    #[test]
+    fn window_update_or_data_is_not_a_duplicate() {
+        let mut flow = FlowState::default();
+        handshake(&mut flow);
+        outbound(&mut flow, &segment(101, 5001, ACK, 65535, &[], 1000));
+        outbound(&mut flow, &segment(1101, 5001, ACK, 65535, &[], 1000));
+        inbound(&mut flow, &segment(5001, 1101, ACK, 65535, &[], 0));
+        // ウィンドウが変わった
+        assert_eq!(
+            inbound(&mut flow, &segment(5001, 1101, ACK, 32768, &[], 0)).ack,
+            None
+        );
+        // データを運んでいる
+        assert_eq!(
+            inbound(&mut flow, &segment(5001, 1101, ACK, 32768, &[], 100)).ack,
+            None
+        );
+        // 未ACKのデータがない
+        inbound(&mut flow, &segment(5101, 2101, ACK, 32768, &[], 0));
+        assert_eq!(
+            inbound(&mut flow, &segment(5101, 2101, ACK, 32768, &[], 0)).ack,
+            None
+        );
+    }
+
+    #[test]
+    fn duplicate_with_sack_must_cover_new_data() {
+        let mut flow = FlowState::default();
+        handshake(&mut flow);
+        for seq in [101, 1101, 2101] {
+            outbound(&mut flow, &segment(seq, 5001, ACK, 65535, &[], 1000));
+        }
+        let first = inbound(
+            &mut flow,
+            &segment(5001, 101, ACK, 65535, &sack(1101, 2101), 0),
+        );
+        assert_eq!(first.ack, Some(AckKind::Duplicate));
+        // 同じ範囲のSACKを繰り返しても新しい情報はない
+        let repeated = inbound(
+            &mut flow,
+            &segment(5001, 101, ACK, 65535, &sack(1101, 2101), 0),
+        );
+        assert_eq!(repeated.ack, None);
+        let extended = inbound(
+            &mut flow,
+            &segment(5001, 101, ACK, 65535, &sack(1101, 3101), 0),
+        );
+        assert_eq!(extended.ack, Some(AckKind::Duplicate));
+    }
+}
@@ -0,0 +1,53 @@
+// プロトコル、リモートポート、サービスなど、ホストの通信の一部分の送受信の集計
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TrafficStats {
+    pub tx_packets: u64,
+    pub rx_packets: u64,
+    pub tx_bytes: u64,
+    pub rx_bytes: u64,
+    tx_last_bytes: u64,
+    rx_last_bytes: u64,
+    pub tx_bps: f64, // 送信ビット/秒
+    pub rx_bps: f64, // 受信ビット/秒
+}
+
+impl TrafficStats {
+    // 一度もパケットを見ていないものは出力しない
+    pub fn is_empty(&self) -> bool {
+        self.tx_packets == 0 && self.rx_packets == 0
+    }
+
+    pub fn count_tx(&mut self, bytes: u64) {
+        self.tx_packets += 1;
+        self.tx_bytes += bytes;
+    }
+
+    pub fn count_rx(&mut self, bytes: u64) {
+        self.rx_packets += 1;
+        self.rx_bytes += bytes;
+    }
+
+    pub fn merge(&mut self, other: &TrafficStats) {
+        self.tx_packets += other.tx_packets;
+        self.rx_packets += other.rx_packets;
+        self.tx_bytes += other.tx_bytes;
+        self.rx_bytes += other.rx_bytes;
+        self.tx_last_bytes += other.tx_last_bytes;
+        self.rx_last_bytes += other.rx_last_bytes;
+        self.tx_bps += other.tx_bps;
+        self.rx_bps += other.rx_bps;
+    }
+
+    // 上位N件を選ぶときの順位付けに使う
+    pub fn total_bytes(&self) -> u64 {
+        self.tx_bytes + self.rx_bytes
+    }
+
+    // 前回の計算からtime_diff秒の間のビット/秒
+    pub fn calculate_bps(&mut self, time_diff: f64) {
+        self.tx_bps = self.tx_bytes.saturating_sub(self.tx_last_bytes) as f64 * 8.0 / time_diff;
+        self.rx_bps = self.rx_bytes.saturating_sub(self.rx_last_bytes) as f64 * 8.0 / time_diff;
+        self.tx_last_bytes = self.tx_bytes;
+        self.rx_last_bytes = self.rx_bytes;
+    }
+}
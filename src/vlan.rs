@@ -0,0 +1,152 @@
+use std::fmt;
+
+// 802.1Q、802.1ad（QinQの外側）、古いQinQ実装のTPID
+const TPIDS: [u16; 3] = [0x8100, 0x88a8, 0x9100];
+pub const TAG_LEN: usize = 4;
+// 記録するタグの数（外側から）。それより内側のタグは外すだけ
+const MAX_TAGS: usize = 2;
+
+// フレームに付いていたVLAN ID（外側から順に）
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct VlanTags {
+    ids: [u16; MAX_TAGS],
+    len: u8,
+}
+
+impl VlanTags {
+    // VLAN ID 0はプライオリティタグだけのフレームなので記録しない
+    pub fn push(&mut self, id: u16) {
+        if id != 0 && (self.len as usize) < MAX_TAGS {
+            self.ids[self.len as usize] = id;
+            self.len += 1;
+        }
+    }
+
+    pub fn ids(&self) -> &[u16] {
+        &self.ids[..self.len as usize]
+    }
+
+    pub fn is_tagged(&self) -> bool {
+        self.len > 0
+    }
+}
+
+// メトリクスのvlanラベルと表示用。タグなしは空文字列、QinQは「外側.内側」
+impl fmt::Display for VlanTags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, id) in self.ids().iter().enumerate() {
+            if i > 0 {
+                write!(f, ".")?;
+            }
+            write!(f, "{}", id)?;
+        }
+        Ok(())
+    }
+}
+
+// EtherTypeがVLANタグならタグを外して記録し、内側のEtherTypeとペイロードを返す
+// タグが途中で切れていたらNone
+pub fn strip_tags<'a>(
+    mut ethertype: u16,
+    mut payload: &'a [u8],
+    tags: &mut VlanTags,
+) -> Option<(u16, &'a [u8])> {
+    while TPIDS.contains(&ethertype) {
+        if payload.len() < TAG_LEN {
+            return None;
+        }
+        let tci = u16::from_be_bytes([payload[0], payload[1]]);
+        tags.push(tci & 0x0fff);
+        ethertype = u16::from_be_bytes([payload[2], payload[3]]);
+        payload = &payload[TAG_LEN..];
+    }
+    Some((ethertype, payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ETHERTYPE_IPV4: u16 = 0x0800;
+
+    fn tag(tpid: u16, tci: u16) -> Vec<u8> {
+        let mut tag = tci.to_be_bytes().to_vec();
+        tag.extend_from_slice(&tpid.to_be_bytes());
+        tag
+    }
+
+    // 外側のTPIDはEthernetヘッダーにあるので、タグの列は内側のTPID（またはEtherType）で終わる
+    fn tagged(tags: &[(u16, u16)], ip: &[u8]) -> (u16, Vec<u8>) {
+        let mut payload = Vec::new();
+        for (i, (_, tci)) in tags.iter().enumerate() {
+            let next = tags.get(i + 1).map_or(ETHERTYPE_IPV4, |(tpid, _)| *tpid);
+            payload.extend(tag(next, *tci));
+        }
+        payload.extend_from_slice(ip);
+        (tags[0].0, payload)
+    }
+
+    #[test]
+    fn untagged_frames_pass_through() {
+        let mut tags = VlanTags::default();
+        let ip = [0x45, 0, 0, 20];
+        assert_eq!(
+            strip_tags(ETHERTYPE_IPV4, &ip, &mut tags),
+            Some((ETHERTYPE_IPV4, &ip[..]))
+        );
+        assert!(!tags.is_tagged());
+        assert_eq!(tags.to_string(), "");
+    }
+
+    #[test]
+    fn strips_a_single_tag_and_keeps_the_priority_bits_out_of_the_id() {
+        let (ethertype, payload) = tagged(&[(0x8100, 0xe064)], &[0x45, 0]);
+        let mut tags = VlanTags::default();
+        assert_eq!(
+            strip_tags(ethertype, &payload, &mut tags),
+            Some((ETHERTYPE_IPV4, &[0x45, 0][..]))
+        );
+        assert_eq!(tags.ids(), &[100]);
+        assert_eq!(tags.to_string(), "100");
+    }
+
+    #[test]
+    fn records_qinq_tags_outer_first() {
+        for outer_tpid in [0x88a8, 0x9100, 0x8100] {
+            let (ethertype, payload) = tagged(&[(outer_tpid, 200), (0x8100, 30)], &[0x45]);
+            let mut tags = VlanTags::default();
+            let (inner, rest) = strip_tags(ethertype, &payload, &mut tags).unwrap();
+            assert_eq!((inner, rest), (ETHERTYPE_IPV4, &[0x45][..]));
+            assert_eq!(tags.ids(), &[200, 30]);
+            assert_eq!(tags.to_string(), "200.30");
+        }
+    }
+
+    #[test]
+    fn strips_but_does_not_record_priority_tags_or_a_third_tag() {
+        let (ethertype, payload) = tagged(&[(0x8100, 0)], &[0x45]);
+        let mut tags = VlanTags::default();
+        assert!(strip_tags(ethertype, &payload, &mut tags).is_some());
+        assert!(!tags.is_tagged());
+
+        let (ethertype, payload) = tagged(&[(0x88a8, 1), (0x8100, 2), (0x8100, 3)], &[0x45]);
+        let mut tags = VlanTags::default();
+        assert_eq!(
+            strip_tags(ethertype, &payload, &mut tags),
+            Some((ETHERTYPE_IPV4, &[0x45][..]))
+        );
+        assert_eq!(tags.ids(), &[1, 2]);
+    }
+
+    #[test]
+    fn truncated_tags_are_rejected() {
+        let (ethertype, payload) = tagged(&[(0x88a8, 200), (0x8100, 30)], &[]);
+        for len in [0, 3, 7] {
+            assert_eq!(
+                strip_tags(ethertype, &payload[..len], &mut VlanTags::default()),
+                None
+            );
+        }
+        assert!(strip_tags(ethertype, &payload, &mut VlanTags::default()).is_some());
+    }
+}